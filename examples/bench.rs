@@ -0,0 +1,118 @@
+//! Compares the encoders across a range of qualities, for tuning the settings:
+//!
+//! ```sh
+//! cargo run --release --example bench -- photo.jpg [40,60,80,...]
+//! ```
+//!
+//! For each format (and quality, except for PNG) it processes just the main image, then prints its size,
+//! how similar it is to the original resized the same way (SSIM from dssim, and PSNR), and the encoding time.
+
+use image::GenericImageView;
+use imgroll::{OutputFormat, Parallelism, ProcessOptions, Stages};
+use rgb::FromSlice;
+use std::{env, error::Error, fs, path::Path, time::Duration};
+
+const DEFAULT_QUALITIES: &[f32] = &[40.0, 50.0, 60.0, 70.0, 75.0, 80.0, 85.0, 90.0, 95.0];
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args().skip(1);
+    let path = args
+        .next()
+        .ok_or("use with an image, and optionally comma separated qualities")?;
+    let qualities = match args.next() {
+        Some(list) => list.split(',').map(str::parse).collect::<Result<Vec<f32>, _>>()?,
+        None => DEFAULT_QUALITIES.to_vec(),
+    };
+    let contents = fs::read(&path)?;
+    let name = Path::new(&path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("image");
+    let (original, _) = imgroll::decode_and_orient(&contents, &ProcessOptions::default())?;
+    let dssim = dssim_core::Dssim::new();
+
+    println!(
+        "{:<6} {:>7} {:>10} {:>8} {:>8} {:>10}",
+        "format", "quality", "bytes", "ssim", "psnr", "encode ms"
+    );
+    for format in [OutputFormat::Jpeg, OutputFormat::Webp, OutputFormat::Png] {
+        let runs = match format {
+            OutputFormat::Png => vec![None],
+            _ => qualities.iter().copied().map(Some).collect(),
+        };
+        for quality in runs {
+            let opts = ProcessOptions {
+                formats: Some(vec![format]),
+                jpeg_quality: quality,
+                webp_quality: quality,
+                widths: Some(vec![]),
+                include_original: false,
+                collect_timings: true,
+                parallelism: Parallelism::Sequential,
+                stages: Stages {
+                    palette: false,
+                    tiny_preview: false,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let (photo, files) = match imgroll::process_photo(&contents, name, &opts) {
+                Ok(result) => result,
+                // Not an output format for this input, or not compiled in
+                Err(imgroll::Error::NoEncoder { .. }) | Err(imgroll::Error::NoAllowedFormat { .. }) => break,
+                Err(e) => return Err(e.into()),
+            };
+            let file = files.first().ok_or("no output file")?;
+            let (_, decoded) = imgroll::decode_output_file(&file.bytes)?;
+            let (width, height) = decoded.dimensions();
+            // Downscaled like the main image, when it's over max_dimension
+            let reference = if original.dimensions() == (width, height) {
+                original.to_rgb8()
+            } else {
+                original
+                    .resize_exact(width, height, image::imageops::FilterType::Lanczos3)
+                    .to_rgb8()
+            };
+            let decoded = decoded.to_rgb8();
+            let ssim = similarity(&dssim, &reference, &decoded)?;
+            let encode_time = photo
+                .timings
+                .unwrap_or_default()
+                .iter()
+                .map(|timing| timing.duration)
+                .sum::<Duration>();
+            println!(
+                "{:<6} {:>7} {:>10} {:>8.5} {:>8.2} {:>10.1}",
+                format!("{:?}", format).to_lowercase(),
+                quality.map_or_else(|| "-".to_owned(), |q| q.to_string()),
+                file.bytes.len(),
+                ssim,
+                psnr(&reference, &decoded),
+                encode_time.as_secs_f64() * 1000.0
+            );
+        }
+    }
+    Ok(())
+}
+
+/// SSIM, from dssim's (multi-scale, in a perceptual color space) dissimilarity, which is 1/SSIM - 1.
+fn similarity(dssim: &dssim_core::Dssim, a: &image::RgbImage, b: &image::RgbImage) -> Result<f64, Box<dyn Error>> {
+    let (width, height) = (a.width() as usize, a.height() as usize);
+    let a = dssim.create_image_rgb(a.as_raw().as_rgb(), width, height);
+    let b = dssim.create_image_rgb(b.as_raw().as_rgb(), width, height);
+    let (a, b) = a.zip(b).ok_or("image too small to compare")?;
+    let (value, _) = dssim.compare(&a, b);
+    Ok(1.0 / (1.0 + f64::from(value)))
+}
+
+/// Peak signal-to-noise ratio over all the channels in dB, infinite for identical images.
+fn psnr(a: &image::RgbImage, b: &image::RgbImage) -> f64 {
+    let squared_error = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(&x, &y)| (f64::from(x) - f64::from(y)).powi(2))
+        .sum::<f64>();
+    let mse = squared_error / a.as_raw().len() as f64;
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
@@ -0,0 +1,94 @@
+#ifndef IMGROLL_H
+#define IMGROLL_H
+
+/* Generated with cbindgen from src/ffi.rs, don't edit by hand. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define IMGROLL_OK 0
+
+// Processing failed, see the error message.
+#define IMGROLL_ERROR 1
+
+// A bug in imgroll, the panic was caught at the boundary.
+#define IMGROLL_PANIC 2
+
+// Receives every output file as soon as it's encoded, on the thread that called `imgroll_process`.
+// The pointers are only valid during the call. Returning anything but 0 stops processing with an error.
+typedef int32_t (*ImgrollWriteFn)(void *ctx,
+                                  const char *name,
+                                  const char *mimetype,
+                                  const uint8_t *data,
+                                  size_t len);
+
+// One output file of `imgroll_process_all`.
+typedef struct ImgrollFile {
+  char *name;
+  char *mimetype;
+  uint8_t *data;
+  size_t len;
+} ImgrollFile;
+
+// Everything `imgroll_process_all` returns, in one allocation-owning struct that's easy to use from
+// Python's ctypes and other FFIs without callbacks.
+typedef struct ImgrollResult {
+  // `IMGROLL_OK`, `IMGROLL_ERROR` or `IMGROLL_PANIC`.
+  int32_t code;
+  // The `Photo` JSON, null unless the code is `IMGROLL_OK`.
+  char *json;
+  // The error message, null when the code is `IMGROLL_OK`.
+  char *error;
+  // `files_len` output files.
+  ImgrollFile *files;
+  size_t files_len;
+} ImgrollResult;
+
+// Processes an image, like `process_photo_streaming`.
+//
+// `options_json` is a `ProcessOptions` config, or null for the defaults.
+// `write` (which may be null to skip the files) is called with `ctx` for every output file.
+// Returns `IMGROLL_OK` and sets `*out_json` to the `Photo` JSON, or sets `*out_error` to a message.
+// Both strings are owned by the caller, who has to release them with `imgroll_free`.
+// `out_json` and `out_error` may be null when the caller isn't interested.
+//
+// # Safety
+//
+// `data` must point to `len` readable bytes, and `name` and `options_json` to NUL terminated strings.
+int32_t imgroll_process(const uint8_t *data,
+                        size_t len,
+                        const char *name,
+                        const char *options_json,
+                        ImgrollWriteFn write,
+                        void *ctx,
+                        char **out_json,
+                        char **out_error);
+
+// Processes an image, like `process_photo`, keeping all the output files in memory.
+//
+// `options_json` is a `ProcessOptions` config, or null for the defaults.
+// Never returns null. The result is owned by the caller, who has to release it with `imgroll_result_free`.
+//
+// # Safety
+//
+// `data` must point to `len` readable bytes, and `name` and `options_json` to NUL terminated strings.
+ImgrollResult *imgroll_process_all(const uint8_t *data,
+                                   size_t len,
+                                   const char *name,
+                                   const char *options_json);
+
+// Frees a result of `imgroll_process_all`, including all the strings and files in it. Does nothing for null.
+//
+// # Safety
+//
+// `result` must come from `imgroll_process_all`, unmodified, and not have been freed yet.
+void imgroll_result_free(ImgrollResult *result);
+
+// Frees a string returned by `imgroll_process`. Does nothing for null.
+//
+// # Safety
+//
+// `ptr` must come from imgroll and not have been freed yet.
+void imgroll_free(char *ptr);
+
+#endif /* IMGROLL_H */
@@ -0,0 +1,69 @@
+//! Frame counting for the formats that can be animated, since the decoders just return the first frame.
+
+/// Number of frames in an animated PNG or GIF, or `None` for other formats and still images.
+pub fn frame_count(bytes: &[u8], format: image::ImageFormat) -> Option<u32> {
+    let frames = match format {
+        image::ImageFormat::Png => apng_frames(bytes)?,
+        image::ImageFormat::Gif => gif_frames(bytes)?,
+        _ => return None,
+    };
+    if frames > 1 {
+        Some(frames)
+    } else {
+        None
+    }
+}
+
+/// Reads `num_frames` from the `acTL` chunk, which has to come before the first `IDAT`.
+fn apng_frames(bytes: &[u8]) -> Option<u32> {
+    let mut i = 8;
+    while i + 8 <= bytes.len() {
+        let len = u32::from_be_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]) as usize;
+        let chunk = &bytes[i + 4..i + 8];
+        if chunk == b"IDAT" {
+            return None;
+        }
+        if chunk == b"acTL" {
+            let data = bytes.get(i + 8..i + 12)?;
+            return Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]));
+        }
+        i += 12 + len;
+    }
+    None
+}
+
+/// Counts the image descriptors, skipping over color tables, extensions and image data.
+fn gif_frames(bytes: &[u8]) -> Option<u32> {
+    fn color_table_len(packed: u8) -> usize {
+        if packed & 0x80 != 0 {
+            3 << ((packed & 7) + 1)
+        } else {
+            0
+        }
+    }
+    fn skip_sub_blocks(bytes: &[u8], mut i: usize) -> Option<usize> {
+        loop {
+            let len = usize::from(*bytes.get(i)?);
+            i += 1 + len;
+            if len == 0 {
+                return Some(i);
+            }
+        }
+    }
+
+    let mut i = 13 + color_table_len(*bytes.get(10)?);
+    let mut frames = 0;
+    loop {
+        match bytes.get(i)? {
+            0x21 => i = skip_sub_blocks(bytes, i + 2)?,
+            0x2C => {
+                frames += 1;
+                i += 10 + color_table_len(*bytes.get(i + 9)?);
+                // LZW minimum code size, then the data
+                i = skip_sub_blocks(bytes, i + 1)?;
+            },
+            // the trailer (0x3B), or garbage after a truncated file
+            _ => return Some(frames),
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use crate::{process_photo_owned, CancelToken, Error, OutFile, Photo, ProcessOptions, Result};
+
+/// Cancels the token when the future is dropped before the blocking work is done.
+struct CancelOnDrop(Option<CancelToken>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(token) = self.0.take() {
+            token.cancel();
+        }
+    }
+}
+
+/// Runs `process_photo` on tokio's blocking thread pool, so that it doesn't stall the async runtime.
+///
+/// Dropping the future cancels the processing via `ProcessOptions::cancel`, creating a token if there isn't one.
+pub async fn process_photo_async(
+    file_contents: bytes::Bytes,
+    file_name: String,
+    mut opts: ProcessOptions,
+) -> Result<(Photo, Vec<OutFile>)> {
+    let mut guard = CancelOnDrop(Some(opts.cancel.get_or_insert_with(CancelToken::new).clone()));
+    let result =
+        tokio::task::spawn_blocking(move || process_photo_owned(file_contents.into(), &file_name, &opts)).await;
+    guard.0 = None;
+    match result {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(_) => Err(Error::Cancelled),
+    }
+}
@@ -0,0 +1,318 @@
+use rayon::prelude::*;
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectError, GetObjectRequest, S3Client, S3};
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use tokio::io::AsyncReadExt;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("I/O error: {}", source))]
+    InputOutput { source: std::io::Error },
+
+    #[snafu(display("Unable to write {}: {}", path.display(), source))]
+    WriteOutput { path: PathBuf, source: std::io::Error },
+
+    #[snafu(display("Invalid manifest: {}", source))]
+    Manifest { source: serde_json::Error },
+
+    #[snafu(display("Invalid manifest entry on line {}: {}", line, source))]
+    ManifestLine { line: usize, source: serde_json::Error },
+
+    #[snafu(display("Unable to JSON encode: {}", source))]
+    JsonEnc { source: serde_json::Error },
+
+    #[snafu(display("Number parse error: {}", source))]
+    ParseNum { source: std::num::ParseIntError },
+
+    #[snafu(display("{} needs a value", flag))]
+    MissingValue { flag: String },
+
+    #[snafu(display("Could not start worker threads: {}", source))]
+    ThreadPool { source: rayon::ThreadPoolBuildError },
+
+    #[snafu(display("Invalid S3 URL '{}', expected s3://bucket/key", url))]
+    S3Url { url: String },
+
+    #[snafu(display("S3 get error: {}", source))]
+    S3Get { source: RusotoError<GetObjectError> },
+
+    #[snafu(display("No file name in '{}', set a name for the job", input))]
+    NoName { input: String },
+
+    #[snafu(display(
+        "'{}' and '{}' would both be processed as '{}', set a name for one of the jobs",
+        first,
+        second,
+        name
+    ))]
+    DuplicateName {
+        name: String,
+        first: String,
+        second: String,
+    },
+
+    #[snafu(display("Unable to process: {}", source))]
+    Image { source: imgroll::Error },
+
+    #[snafu(display("{} of {} jobs failed", failed, total))]
+    JobsFailed { failed: usize, total: usize },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// One line of an NDJSON manifest, or one element of a JSON array manifest.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Job {
+    /// A local path or `s3://bucket/key`.
+    input: String,
+    /// The file name to process the photo as, which the output names are based on.
+    /// The last component of `input` by default.
+    name: Option<String>,
+    /// `ProcessOptions` config fields, over the ones from `IMGROLL_CONFIG`.
+    #[serde(default)]
+    options: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+struct Summary {
+    succeeded: usize,
+    failed: usize,
+    skipped: usize,
+    bytes_in: u64,
+    bytes_out: u64,
+    wall_time_secs: f64,
+    failures: Vec<Failure>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Failure {
+    input: String,
+    error: String,
+}
+
+struct Batch {
+    out_dir: PathBuf,
+    resume: bool,
+    base_options: serde_json::Map<String, serde_json::Value>,
+    s3: Option<S3Source>,
+}
+
+/// S3 downloads for the (synchronous) workers.
+struct S3Source {
+    runtime: tokio::runtime::Runtime,
+    client: S3Client,
+}
+
+fn main() -> Result<()> {
+    let mut resume = false;
+    let mut threads = None;
+    let mut positional = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--resume" => resume = true,
+            "--jobs" => {
+                let n = args.next().context(MissingValue { flag: "--jobs" })?;
+                threads = Some(n.parse().context(ParseNum {})?);
+            },
+            _ => positional.push(arg),
+        }
+    }
+    let (manifest, out_dir) = match &positional[..] {
+        [manifest, out_dir] => (manifest, out_dir),
+        _ => {
+            println!(
+                "use with a manifest (JSON or NDJSON) and an output directory, and optionally --resume and --jobs N"
+            );
+            return Ok(());
+        },
+    };
+
+    let jobs = read_manifest(&fs::read_to_string(manifest).context(InputOutput {})?)?;
+    fs::create_dir_all(out_dir).context(InputOutput {})?;
+    let base_options = match serde_json::to_value(imgroll::ProcessOptions::from_env().context(Image {})?) {
+        Ok(serde_json::Value::Object(map)) => map,
+        Ok(_) => unreachable!("options serialize to an object"),
+        Err(source) => return Err(Error::JsonEnc { source }),
+    };
+    let batch = Batch {
+        out_dir: out_dir.into(),
+        resume,
+        base_options,
+        s3: if jobs.iter().any(|job| job.input.starts_with("s3://")) {
+            Some(S3Source::new().context(InputOutput {})?)
+        } else {
+            None
+        },
+    };
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.unwrap_or(0))
+        .build()
+        .context(ThreadPool {})?;
+
+    let start = Instant::now();
+    let results = pool.install(|| {
+        jobs.par_iter()
+            .map(|job| {
+                let result = batch.run(job);
+                if let Err(e) = &result {
+                    eprintln!("{}: {}", job.input, e);
+                }
+                result
+            })
+            .collect::<Vec<_>>()
+    });
+    let mut summary = Summary::default();
+    for (job, result) in jobs.iter().zip(results) {
+        match result {
+            Ok(Some((bytes_in, bytes_out))) => {
+                summary.succeeded += 1;
+                summary.bytes_in += bytes_in;
+                summary.bytes_out += bytes_out;
+            },
+            Ok(None) => summary.skipped += 1,
+            Err(e) => {
+                summary.failed += 1;
+                summary.failures.push(Failure {
+                    input: job.input.clone(),
+                    error: e.to_string(),
+                });
+            },
+        }
+    }
+    summary.wall_time_secs = start.elapsed().as_secs_f64();
+    println!("{}", serde_json::to_string_pretty(&summary).context(JsonEnc {})?);
+    if summary.failed > 0 {
+        return JobsFailed {
+            failed: summary.failed,
+            total: jobs.len(),
+        }
+        .fail();
+    }
+    Ok(())
+}
+
+/// A JSON array of jobs, or one job per line. The names have to be unique, since the JSON of a job
+/// is named after it (and `--resume` goes by it).
+fn read_manifest(manifest: &str) -> Result<Vec<Job>> {
+    let jobs = if manifest.trim_start().starts_with('[') {
+        serde_json::from_str(manifest).context(Manifest {})?
+    } else {
+        manifest
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| serde_json::from_str(line).context(ManifestLine { line: i + 1 }))
+            .collect::<Result<Vec<Job>>>()?
+    };
+    let mut inputs = HashMap::new();
+    for job in &jobs {
+        if let Some(first) = inputs.insert(job.name()?, &job.input) {
+            return DuplicateName {
+                name: job.name()?,
+                first: first.as_str(),
+                second: &job.input,
+            }
+            .fail();
+        }
+    }
+    Ok(jobs)
+}
+
+impl Job {
+    /// The file name the photo is processed as.
+    fn name(&self) -> Result<&str> {
+        let name = match &self.name {
+            Some(name) => name.as_str(),
+            None => self.input.rsplit('/').next().unwrap_or_default(),
+        };
+        Path::new(name)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context(NoName { input: &self.input })
+    }
+}
+
+impl Batch {
+    /// Processes a job, giving the input and output sizes, or `None` when it's skipped by `--resume`.
+    fn run(&self, job: &Job) -> Result<Option<(u64, u64)>> {
+        let name = job.name()?;
+        let json_path = self.out_dir.join(format!("{}.json", name));
+        if self.resume && json_path.exists() {
+            return Ok(None);
+        }
+
+        let mut options = self.base_options.clone();
+        options.extend(job.options.clone());
+        let mut opts = imgroll::ProcessOptions::from_json_str(&serde_json::Value::Object(options).to_string())
+            .context(Image {})?;
+        let contents = match &self.s3 {
+            Some(s3) if job.input.starts_with("s3://") => s3.get(&job.input)?,
+            _ => {
+                let (contents, with_sidecar) = imgroll::read_photo_file(&job.input, &opts).context(Image {})?;
+                opts = with_sidecar;
+                contents
+            },
+        };
+
+        let (photo, files) = imgroll::process_photo(&contents, name, &opts).context(Image {})?;
+        let mut bytes_out = 0;
+        for file in files {
+            let path = self.out_dir.join(&file.name);
+            fs::write(&path, &file.bytes).context(WriteOutput { path })?;
+            bytes_out += file.bytes.len() as u64;
+        }
+        // Written last (and atomically), so that --resume only skips complete jobs
+        let tmp_path = json_path.with_extension("json.tmp");
+        let json = serde_json::to_vec(&photo).context(JsonEnc {})?;
+        fs::write(&tmp_path, json).context(WriteOutput { path: &tmp_path })?;
+        fs::rename(&tmp_path, &json_path).context(WriteOutput { path: &json_path })?;
+        Ok(Some((contents.len() as u64, bytes_out)))
+    }
+}
+
+impl S3Source {
+    fn new() -> std::io::Result<Self> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let region = imgroll::s3::region(env::var("IMGROLL_S3_ENDPOINT").ok());
+        // The HTTP client wants to be created inside the runtime
+        let client = {
+            let _guard = runtime.enter();
+            S3Client::new(region)
+        };
+        Ok(S3Source { runtime, client })
+    }
+
+    fn get(&self, url: &str) -> Result<Vec<u8>> {
+        let (bucket, key) = url
+            .strip_prefix("s3://")
+            .and_then(|path| path.split_once('/'))
+            .context(S3Url { url })?;
+        self.runtime.block_on(async {
+            let obj = self
+                .client
+                .get_object(GetObjectRequest {
+                    bucket: bucket.to_owned(),
+                    key: key.to_owned(),
+                    ..Default::default()
+                })
+                .await
+                .context(S3Get {})?;
+            let mut buf = Vec::new();
+            if let Some(body) = obj.body {
+                body.into_async_read()
+                    .read_to_end(&mut buf)
+                    .await
+                    .context(InputOutput {})?;
+            }
+            Ok(buf)
+        })
+    }
+}
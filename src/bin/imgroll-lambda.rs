@@ -1,13 +1,56 @@
 use aws_lambda_events::event::s3::S3Event;
-use log::info;
-use rusoto_core::{Region, RusotoError};
-use rusoto_s3::{GetObjectError, GetObjectRequest, PutObjectError, PutObjectRequest, S3Client, StreamingBody, S3};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use log::{error, info, warn};
+use rusoto_core::{
+    credential::{AwsCredentials, CredentialsError, DefaultCredentialsProvider, ProvideAwsCredentials},
+    Region, RusotoError,
+};
+use rusoto_s3::{
+    util::{PreSignedRequest, PreSignedRequestOption},
+    GetObjectError, GetObjectRequest, HeadObjectError, HeadObjectRequest, PutObjectError, PutObjectRequest, S3Client,
+    StreamingBody, S3,
+};
+use serde::Deserialize;
 use serde_json::Value;
 use snafu::{ResultExt, Snafu};
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::{self, io::AsyncReadExt};
 
+/// The longest `imgroll-options` metadata that is parsed.
+const MAX_OPTIONS_BYTES: usize = 2048;
+
+/// The config fields that `imgroll-options` metadata can set. Anyone who can upload can set them,
+/// so not the limits (`max_pixels`, `max_dimension`), `parallelism` or `include_original_file`.
+const OVERRIDABLE_OPTIONS: &[&str] = &[
+    "include_original",
+    "descriptor_mode",
+    "crops",
+    "focal_point",
+    "slug_style",
+    "slug_placeholder",
+    "social_preview",
+    "apply_orientation",
+    "palette_hex",
+    "min_rating",
+    "webp_metadata",
+    "webp_image_hint",
+    "tiny_preview_svg",
+    "thumbnail_filter",
+    "reencode_lossless",
+    "jpeg_subsampling",
+    "jpeg_quality",
+    "webp_quality",
+    "quality_by_width",
+    "widths",
+    "formats",
+];
+
+/// The most entries in the lists of `imgroll-options` metadata, each of them can mean more encodes.
+const MAX_OPTIONS_ITEMS: usize = 8;
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("I/O error: {}", source))]
@@ -24,12 +67,21 @@ pub enum Error {
         source: rusoto_signature::region::ParseRegionError,
     },
 
+    #[snafu(display("AWS credentials error: {}", source))]
+    AwsCredentials { source: CredentialsError },
+
+    #[snafu(display("Number parse error: {}", source))]
+    ParseNum { source: std::num::ParseIntError },
+
     #[snafu(display("S3 get error: {}", source))]
     S3Get { source: RusotoError<GetObjectError> },
 
     #[snafu(display("S3 put error: {}", source))]
     S3Put { source: RusotoError<PutObjectError> },
 
+    #[snafu(display("S3 head error: {}", source))]
+    S3Head { source: RusotoError<HeadObjectError> },
+
     #[snafu(display("Unable to JSON encode: {}", source))]
     JsonEnc { source: serde_json::Error },
 
@@ -39,6 +91,22 @@ pub enum Error {
     #[snafu(display("Unable to process: {}", source))]
     Image { source: imgroll::Error },
 
+    #[snafu(display("The object is larger than {} bytes", limit))]
+    TooLarge { limit: usize },
+
+    #[snafu(display("Invalid imgroll-options metadata: {}", message))]
+    BadOptions { message: String },
+
+    #[snafu(display("Unable to make the warmup image: {}", source))]
+    WarmupImage { source: image::ImageError },
+
+    #[snafu(display("{} of {} records failed: {}", failed, total, errors))]
+    RecordsFailed {
+        failed: usize,
+        total: usize,
+        errors: String,
+    },
+
     #[snafu(display("Some error: {}", info))]
     WTF { info: String },
 }
@@ -49,93 +117,782 @@ impl From<&str> for Error {
     }
 }
 
+struct Presign {
+    credentials: AwsCredentials,
+    expires_in: Duration,
+}
+
+/// How the output files are stored: `OUTPUT_ACL`, and `CACHE_CONTROL` and `STORAGE_CLASS`,
+/// which the `imgroll-cache-control` and `imgroll-storage-class` metadata of the original override.
+struct UploadSettings {
+    acl: Option<String>,
+    /// Replaces the default of `imgroll::s3::put_request`.
+    cache_control: Option<String>,
+    storage_class: Option<String>,
+}
+
+/// How the uploads and the callback are retried after transient errors: `RETRY_ATTEMPTS` tries in total
+/// (4 by default), waiting up to `RETRY_BASE_DELAY_MS` (100 by default) before the second, twice that before
+/// the third and so on, so that the work done for a photo isn't thrown away over a single 503.
+#[derive(Debug, Clone, Copy)]
+struct Retry {
+    attempts: u32,
+    base_delay: Duration,
+}
+
+impl Retry {
+    fn from_env() -> Result<Retry, Error> {
+        Ok(Retry {
+            attempts: match std::env::var("RETRY_ATTEMPTS") {
+                Ok(n) => n.parse().context(ParseNum {})?,
+                Err(_) => 4,
+            },
+            base_delay: match std::env::var("RETRY_BASE_DELAY_MS") {
+                Ok(n) => Duration::from_millis(n.parse().context(ParseNum {})?),
+                Err(_) => Duration::from_millis(100),
+            },
+        })
+    }
+
+    /// Calls `f` until it succeeds, fails with an error that's not transient, or runs out of attempts.
+    async fn run<T, E, Fut>(
+        self,
+        what: &str,
+        is_transient: impl Fn(&E) -> bool,
+        mut f: impl FnMut() -> Fut,
+    ) -> Result<T, E>
+    where
+        E: std::fmt::Display,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Err(e) if attempt < self.attempts && is_transient(&e) => {
+                    // Full jitter, so that the records failing together don't all retry together
+                    let delay = (self.base_delay * 2u32.pow((attempt - 1).min(16))).mul_f64(jitter());
+                    warn!(
+                        "{} failed (attempt {} of {}), retrying in {:?}: {}",
+                        what, attempt, self.attempts, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A random number in [0, 1), from the randomly seeded hasher that the standard library already has.
+fn jitter() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let random = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Log lines for CloudWatch: `LEVEL [target] message`, or with `LOG_FORMAT=json`, JSON objects
+/// that include the request ID and the object being processed, for CloudWatch Logs Insights queries.
+struct Logger {
+    level: log::LevelFilter,
+    json: bool,
+}
+
+/// An uploaded object to process, from either kind of S3 event.
+#[derive(Debug, Clone)]
+struct Record {
+    region: String,
+    bucket: String,
+    key: String,
+}
+
+/// The parts of an EventBridge event that are used, which S3 sends for buckets with EventBridge notifications
+/// turned on. Unlike in classic S3 events, the key isn't URL-encoded.
+#[derive(Debug, Deserialize)]
+struct EventBridgeEvent {
+    #[serde(rename = "detail-type")]
+    detail_type: String,
+    region: String,
+    detail: EventBridgeDetail,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventBridgeDetail {
+    bucket: EventBridgeBucket,
+    object: EventBridgeObject,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventBridgeBucket {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventBridgeObject {
+    key: String,
+}
+
+/// What the log lines are about.
+#[derive(Debug, Clone, Default)]
+struct LogContext {
+    request_id: Option<String>,
+    bucket: Option<String>,
+    key: Option<String>,
+}
+
+tokio::task_local! {
+    /// Set around each invocation, and each record in it, which are processed concurrently.
+    static LOG_CONTEXT: LogContext;
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if self.json {
+            let context = LOG_CONTEXT.try_with(LogContext::clone).unwrap_or_default();
+            let line = serde_json::json!({
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+                "request_id": context.request_id,
+                "bucket": context.bucket,
+                "key": context.key,
+            });
+            println!("{}", line);
+        } else {
+            println!("{:<5} [{}] {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-    simple_logger::init_with_level(log::Level::Info).context(SetLogger {})?;
+    // Once per container, invocations on a warm one reuse it
+    let level = std::env::var("LOG_LEVEL")
+        .or_else(|_| std::env::var("RUST_LOG"))
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(log::LevelFilter::Info);
+    let json = std::env::var("LOG_FORMAT").map_or(false, |v| v == "json");
+    log::set_logger(Box::leak(Box::new(Logger { level, json }))).context(SetLogger {})?;
+    log::set_max_level(level);
     let func = lambda_runtime::handler_fn(func);
     lambda_runtime::run(func).await?;
     Ok(())
 }
 
-async fn func(event: Value, _: lambda_runtime::Context) -> Result<Value, Error> {
-    let s3_event: S3Event = serde_json::from_value(event.clone()).context(JsonEnc {})?;
-
-    for record in s3_event.records {
-        let region: Region = record.aws_region.ok_or("region")?.parse().context(AwsRegion {})?;
-        let clnt = S3Client::new(region.clone());
-        let bucket = record.s3.bucket.name.ok_or("name")?;
-        let key = record.s3.object.key.ok_or("key")?;
-        info!(
-            "Processing object key '{}' in bucket '{}' region '{}'",
-            &key,
-            &bucket,
-            region.name()
-        );
-        let obj = clnt
-            .get_object(GetObjectRequest {
-                bucket: bucket.clone(),
-                key: key.clone(),
-                ..Default::default()
+async fn func(event: Value, ctx: lambda_runtime::Context) -> Result<Value, Error> {
+    let context = LogContext {
+        request_id: Some(ctx.request_id),
+        ..Default::default()
+    };
+    LOG_CONTEXT.scope(context.clone(), handle_event(event, context)).await
+}
+
+async fn handle_event(event: Value, context: LogContext) -> Result<Value, Error> {
+    // From a scheduled ping or serverless-plugin-warmup, no S3 involved
+    if event["warmup"] == true || event["source"] == "serverless-plugin-warmup" {
+        let elapsed = warm_up().await?;
+        info!("Warmed up in {:?}", elapsed);
+        return Ok(serde_json::json!({ "warmup": true, "millis": elapsed.as_millis() as u64 }));
+    }
+    if event["Records"][0]["eventSource"] == "aws:sqs" {
+        return handle_sqs(event, context).await;
+    }
+    let records = s3_records(event.clone())?;
+    let total = records.len();
+    let failures = process_records(records.into_iter().map(|record| ((), record)).collect(), &context).await?;
+    if !failures.is_empty() {
+        return RecordsFailed {
+            failed: failures.len(),
+            total,
+            errors: failures.into_iter().map(|(_, e)| e).collect::<Vec<_>>().join("; "),
+        }
+        .fail();
+    }
+    Ok(event)
+}
+
+/// S3 notifications routed through SQS (for throttling), where each message body is an S3 event (or an SNS one
+/// with S3 events inside). Only the failed messages are reported back, which needs `ReportBatchItemFailures`
+/// on the event source mapping, so that the others aren't retried along with them.
+async fn handle_sqs(event: Value, context: LogContext) -> Result<Value, Error> {
+    let mut records = vec![];
+    let mut failed = vec![];
+    for message in event["Records"].as_array().into_iter().flatten() {
+        let id = message["messageId"].as_str().unwrap_or_default().to_owned();
+        let body = serde_json::from_str(message["body"].as_str().unwrap_or_default()).context(JsonEnc {});
+        match body.and_then(s3_records) {
+            Ok(message_records) => records.extend(message_records.into_iter().map(|record| (id.clone(), record))),
+            Err(e) => {
+                error!("Unable to read the S3 event in message '{}': {}", &id, e);
+                failed.push(id);
+            },
+        }
+    }
+    for (id, _) in process_records(records, &context).await? {
+        if !failed.contains(&id) {
+            failed.push(id);
+        }
+    }
+    let failures = failed
+        .into_iter()
+        .map(|id| serde_json::json!({ "itemIdentifier": id }))
+        .collect::<Vec<_>>();
+    Ok(serde_json::json!({ "batchItemFailures": failures }))
+}
+
+/// The objects in an S3 event (a classic one or an EventBridge one), or in the S3 events in an SNS event's messages
+/// or in an SNS notification (the body of an SQS message from a topic).
+fn s3_records(event: Value) -> Result<Vec<Record>, Error> {
+    // Sent once when the notification is set up
+    if event["Event"] == "s3:TestEvent" {
+        return Ok(vec![]);
+    }
+    if event["Records"][0]["EventSource"] == "aws:sns" {
+        let mut records = vec![];
+        for record in event["Records"].as_array().into_iter().flatten() {
+            let message = record["Sns"]["Message"].as_str().ok_or("SNS message")?;
+            records.extend(s3_records(serde_json::from_str(message).context(JsonEnc {})?)?);
+        }
+        return Ok(records);
+    }
+    if event["Type"] == "Notification" {
+        let message = event["Message"].as_str().ok_or("SNS message")?;
+        return s3_records(serde_json::from_str(message).context(JsonEnc {})?);
+    }
+    let s3_event = match serde_json::from_value::<S3Event>(event.clone()) {
+        Ok(s3_event) => s3_event,
+        // When it's not an EventBridge event either, the classic S3 event's error is the useful one
+        Err(e) => return eventbridge_records(event).map_err(|_| e).context(JsonEnc {}),
+    };
+    s3_event
+        .records
+        .into_iter()
+        .map(|record| -> Result<Record, Error> {
+            Ok(Record {
+                region: record.aws_region.ok_or("region")?,
+                bucket: record.s3.bucket.name.ok_or("name")?,
+                key: decode_key(&record.s3.object.key.ok_or("key")?),
             })
-            .await
-            .context(S3Get {})?;
-        let meta = obj.metadata.ok_or("metadata")?;
-        let cb_url = meta.get("imgroll-cb").ok_or("callback")?;
-        info!("Found callback URL '{}' in metadata", &cb_url);
-        let mut buf = Vec::new();
-        obj.body
-            .ok_or("body")?
-            .into_async_read()
-            .read_to_end(&mut buf)
-            .await
-            .context(InputOutput {})?;
-        let (mut photo, files) = imgroll::process_photo(&buf, &key).context(Image {})?;
-        for src in &mut photo.source {
-            for mut srcset in &mut src.srcset {
-                srcset.src = if let Ok(host) = std::env::var("BUCKET_PUBLIC_HOST") {
-                    format!("{}/{}", host, srcset.src)
-                } else {
-                    format!(
-                        "https://{}.s3.dualstack.{}.amazonaws.com/{}",
-                        &bucket,
-                        region.name(),
-                        srcset.src
-                    )
-                };
-            }
+        })
+        .collect()
+}
+
+/// Keys in classic S3 events are URL-encoded like form values, with `+` for spaces.
+fn decode_key(key: &str) -> String {
+    let mut bytes = Vec::with_capacity(key.len());
+    let mut i = 0;
+    while i < key.len() {
+        let byte = key.as_bytes()[i];
+        let escaped = key
+            .get(i + 1..i + 3)
+            .filter(|hex| byte == b'%' && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (byte, escaped) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                i += 3;
+            },
+            (b'+', _) => {
+                bytes.push(b' ');
+                i += 1;
+            },
+            _ => {
+                bytes.push(byte);
+                i += 1;
+            },
         }
-        info!("Processed photo, metadata: {:?}", &photo);
-        let json = serde_json::to_string(&photo).context(JsonEnc {})?;
-        for imgroll::OutFile { name, bytes, mimetype } in files {
-            info!("Uploading file '{}'", &name);
-            let mut file_meta = HashMap::new();
-            file_meta.insert("imgroll-original".to_owned(), key.clone());
-            clnt.put_object(PutObjectRequest {
-                bucket: bucket.clone(),
-                key: name,
-                acl: Some("public-read".to_owned()),
-                metadata: Some(file_meta),
-                content_length: Some(bytes.len().try_into().context(FromInt {})?),
-                content_type: Some(mimetype),
-                content_disposition: Some("inline".to_owned()),
-                cache_control: Some("public, max-age=31536000, immutable".to_owned()),
-                body: Some(StreamingBody::from(bytes)),
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// The object in an EventBridge S3 event.
+fn eventbridge_records(event: Value) -> Result<Vec<Record>, serde_json::Error> {
+    let event: EventBridgeEvent = serde_json::from_value(event)?;
+    // Other S3 events can be routed to the function by a broad rule
+    if event.detail_type != "Object Created" {
+        info!("Ignoring an EventBridge '{}' event", event.detail_type);
+        return Ok(vec![]);
+    }
+    Ok(vec![Record {
+        region: event.region,
+        bucket: event.detail.bucket.name,
+        key: event.detail.object.key,
+    }])
+}
+
+/// Processes the records concurrently, giving the errors of the failed ones along with their tags
+/// (the SQS message they came in).
+async fn process_records<T>(records: Vec<(T, Record)>, context: &LogContext) -> Result<Vec<(T, String)>, Error> {
+    info!("Received {} records", records.len());
+    // Each one holds a whole photo in memory (or a few, while encoding)
+    let max_concurrent = match std::env::var("MAX_CONCURRENT_RECORDS") {
+        Ok(n) => n.parse().context(ParseNum {})?,
+        Err(_) => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    // Every record gets its chance, the failures are reported together at the end
+    let failures = stream::iter(records)
+        .map(|(tag, record)| {
+            let context = LogContext {
+                bucket: Some(record.bucket.clone()),
+                key: Some(record.key.clone()),
+                ..context.clone()
+            };
+            let key = record.key.clone();
+            LOG_CONTEXT.scope(context, async move {
+                process_record(record).await.map_err(|e| {
+                    error!("Unable to process '{}': {}", &key, e);
+                    (tag, format!("{}: {}", key, e))
+                })
+            })
+        })
+        .buffer_unordered(max_concurrent.max(1))
+        .filter_map(|result| async move { result.err() })
+        .collect::<Vec<_>>()
+        .await;
+    Ok(failures)
+}
+
+async fn process_record(record: Record) -> Result<(), Error> {
+    let region: Region = record.region.parse().context(AwsRegion {})?;
+    let clnt = S3Client::new(region.clone());
+    let Record { bucket, key, .. } = record;
+    info!(
+        "Processing object key '{}' in bucket '{}' region '{}'",
+        &key,
+        &bucket,
+        region.name()
+    );
+    // Only the metadata for now, outputs written into the same bucket trigger the event too
+    let head = clnt
+        .head_object(HeadObjectRequest {
+            bucket: bucket.clone(),
+            key: key.clone(),
+            ..Default::default()
+        })
+        .await
+        .context(S3Head {})?;
+    let meta = head.metadata.unwrap_or_default();
+    let out = output_location(&bucket, &meta);
+    // Written after the callback, so S3 retrying the event doesn't process (and call back) twice
+    let done_marker = format!("{}.imgroll-done", key);
+    let skip_processed = std::env::var("SKIP_PROCESSED").map_or(false, |v| v == "true");
+    let marker_exists = skip_processed && exists(&clnt, &bucket, &done_marker).await?;
+    if let Some(reason) = imgroll::s3::skip_reason(&bucket, &key, &meta, &out, marker_exists) {
+        info!("Skipping '{}': {}", &key, reason);
+        return Ok(());
+    }
+    let cb_url = meta.get("imgroll-cb").ok_or("callback")?;
+    info!("Found callback URL '{}' in metadata", &cb_url);
+    let cb_secret = setting(&meta, "imgroll-cb-secret", "CALLBACK_SECRET");
+    let retry = Retry::from_env()?;
+    // Before downloading anything, like the size check
+    let opts = match options_for(&meta) {
+        Err(e @ Error::BadOptions { .. }) => {
+            error!("Not processing '{}': {}", &key, e);
+            let failure = Failure {
+                kind: "bad_options",
+                message: e.to_string(),
+                permanent: true,
+            };
+            return report_failure(cb_url, cb_secret.as_deref(), retry, &key, failure).await;
+        },
+        result => result?,
+    };
+    info!("Options: {}", serde_json::to_string(&opts).context(JsonEnc {})?);
+    let max_bytes = match std::env::var("MAX_OBJECT_BYTES") {
+        Ok(n) => n.parse().context(ParseNum {})?,
+        Err(_) => 50 * 1024 * 1024,
+    };
+    let buf = match read_object(&clnt, &bucket, &key, head.content_length, max_bytes).await {
+        Err(e @ Error::TooLarge { .. }) => {
+            error!("Not processing '{}': {}", &key, e);
+            let failure = Failure {
+                kind: "too_large",
+                message: e.to_string(),
+                permanent: true,
+            };
+            return report_failure(cb_url, cb_secret.as_deref(), retry, &key, failure).await;
+        },
+        result => result?,
+    };
+    let started = Instant::now();
+    let (mut photo, files) = match imgroll::process_photo_async(buf.into(), key.clone(), opts.clone()).await {
+        Ok(result) => result,
+        Err(source) => {
+            let failure = Failure {
+                kind: source.kind(),
+                message: source.to_string(),
+                permanent: source.is_permanent(),
+            };
+            report_failure(cb_url, cb_secret.as_deref(), retry, &key, failure).await?;
+            if source.is_permanent() {
+                // Lambda would retry the event, only to fail the same way
+                error!("Unable to process '{}': {}", &key, source);
+                return Ok(());
+            }
+            return Err(Error::Image { source });
+        },
+    };
+    let presign = match std::env::var("PRESIGN_EXPIRY_SECS") {
+        Ok(secs) => Some(Presign {
+            credentials: DefaultCredentialsProvider::new()
+                .context(AwsCredentials {})?
+                .credentials()
+                .await
+                .context(AwsCredentials {})?,
+            expires_in: Duration::from_secs(secs.parse().context(ParseNum {})?),
+        }),
+        Err(_) => None,
+    };
+    let url_for = |name: &str| {
+        // The original stays where it was uploaded
+        let (url_bucket, url_key) = if name == key {
+            (&bucket, name.to_owned())
+        } else {
+            (&out.bucket, out.key(name))
+        };
+        match &presign {
+            Some(Presign {
+                credentials,
+                expires_in,
+            }) => GetObjectRequest {
+                bucket: url_bucket.clone(),
+                key: url_key,
                 ..Default::default()
+            }
+            .get_presigned_url(
+                &region,
+                credentials,
+                &PreSignedRequestOption {
+                    expires_in: *expires_in,
+                },
+            ),
+            None => match std::env::var("BUCKET_PUBLIC_HOST") {
+                Ok(host) if url_bucket == &out.bucket => format!("{}/{}", host, url_key),
+                _ => imgroll::s3::object_url(&region, url_bucket, &url_key, false),
+            },
+        }
+    };
+    photo.map_urls(url_for);
+    info!("Processed photo, metadata: {:?}", &photo);
+    let json = serde_json::to_string(&photo).context(JsonEnc {})?;
+    let uploads = UploadSettings {
+        acl: match std::env::var("OUTPUT_ACL").ok() {
+            Some(acl) if acl == "none" => None,
+            Some(acl) => Some(acl),
+            // Presigned URLs work on private objects
+            None if presign.is_some() => None,
+            None => Some("public-read".to_owned()),
+        },
+        cache_control: setting(&meta, "imgroll-cache-control", "CACHE_CONTROL"),
+        storage_class: setting(&meta, "imgroll-storage-class", "STORAGE_CLASS"),
+    };
+    let max_uploads: usize = match std::env::var("MAX_CONCURRENT_UPLOADS") {
+        Ok(n) => n.parse().context(ParseNum {})?,
+        Err(_) => 4,
+    };
+    let output_files = files
+        .iter()
+        .map(|file| {
+            let size = file.bytes.len();
+            serde_json::json!({ "key": out.key(&file.name), "size": size, "mimetype": file.mimetype })
+        })
+        .collect::<Vec<_>>();
+    stream::iter(files.into_iter().map(Ok))
+        .try_for_each_concurrent(max_uploads.max(1), |file| {
+            upload_file(&clnt, &out, &key, &uploads, retry, file)
+        })
+        .await?;
+    // Kept next to the outputs, so that the photo isn't lost when the callback fails
+    let json_key = if std::env::var("UPLOAD_PHOTO_JSON").map_or(true, |v| v != "false") {
+        let name = format!("{}.json", photo.file_prefix);
+        info!("Uploading the photo JSON as '{}'", &name);
+        let file = imgroll::OutFile {
+            name: name.clone(),
+            bytes: json.clone().into_bytes(),
+            mimetype: "application/json".to_owned(),
+        };
+        upload_file(&clnt, &out, &key, &uploads, retry, file).await?;
+        Some(out.key(&name))
+    } else {
+        None
+    };
+    let mut cb_json = serde_json::to_value(&photo).context(JsonEnc {})?;
+    if std::env::var("CALLBACK_FORMAT").map_or(false, |v| v == "legacy") {
+        // The bare photo, for receivers from before the envelope
+        if let Some(json_key) = json_key {
+            cb_json["json_key"] = json_key.into();
+        }
+    } else {
+        cb_json = serde_json::json!({
+            "photo": cb_json,
+            "bucket": bucket,
+            "key": key,
+            "output_bucket": out.bucket,
+            "output_files": output_files,
+            "json_key": json_key,
+            "processing_ms": started.elapsed().as_millis() as u64,
+            "imgroll_version": env!("CARGO_PKG_VERSION"),
+        });
+    }
+    let resp = callback(cb_url, &cb_json.to_string(), cb_secret.as_deref(), retry).await?;
+    info!("Callback response: {:?}", &resp);
+    if skip_processed {
+        info!("Uploading marker '{}'", &done_marker);
+        let content_length = json.len().try_into().context(FromInt {})?;
+        let mut marker_meta = HashMap::new();
+        marker_meta.insert("imgroll-original".to_owned(), key.clone());
+        retry
+            .run("Marker upload", imgroll::s3::is_transient, || {
+                clnt.put_object(PutObjectRequest {
+                    bucket: bucket.clone(),
+                    key: done_marker.clone(),
+                    content_length: Some(content_length),
+                    content_type: Some("application/json".to_owned()),
+                    body: Some(StreamingBody::from(json.clone().into_bytes())),
+                    metadata: Some(marker_meta.clone()),
+                    ..Default::default()
+                })
             })
             .await
             .context(S3Put {})?;
+    }
+    Ok(())
+}
+
+/// Uploads an output file, with the original's key in its `imgroll-original` metadata.
+async fn upload_file(
+    clnt: &S3Client,
+    out: &imgroll::s3::Location,
+    original: &str,
+    settings: &UploadSettings,
+    retry: Retry,
+    imgroll::OutFile { name, bytes, mimetype }: imgroll::OutFile,
+) -> Result<(), Error> {
+    info!("Uploading file '{}' to bucket '{}'", out.key(&name), &out.bucket);
+    let mut file_meta = HashMap::new();
+    file_meta.insert("imgroll-original".to_owned(), original.to_owned());
+    retry
+        .run("Upload", imgroll::s3::is_transient, || {
+            let request = imgroll::s3::put_request(&out.bucket, out.key(&name), bytes.clone(), mimetype.clone());
+            clnt.put_object(PutObjectRequest {
+                acl: settings.acl.clone(),
+                cache_control: settings.cache_control.clone().or(request.cache_control),
+                storage_class: settings.storage_class.clone(),
+                metadata: Some(file_meta.clone()),
+                ..request
+            })
+        })
+        .await
+        .context(S3Put {})?;
+    Ok(())
+}
+
+/// Runs a tiny generated JPEG through the pipeline with the configured options, so that the encoders
+/// are loaded and initialized before the first real photo on a new container.
+async fn warm_up() -> Result<Duration, Error> {
+    let started = Instant::now();
+    let pixels = image::RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, 128]));
+    let mut jpeg = Vec::new();
+    image::DynamicImage::ImageRgb8(pixels)
+        .write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(90))
+        .context(WarmupImage {})?;
+    let mut opts = imgroll::ProcessOptions::from_env().context(Image {})?;
+    // It has no rating to pass with
+    opts.min_rating = None;
+    imgroll::process_photo_async(jpeg.into(), "warmup.jpg".to_owned(), opts)
+        .await
+        .context(Image {})?;
+    Ok(started.elapsed())
+}
+
+/// The processing options: the config from `IMGROLL_CONFIG` (or `IMGROLL_CONFIG_JSON`), then `INCLUDE_ORIGINAL`
+/// and `TINY_PREVIEW_FILE`, then the upload's `imgroll-options` metadata, a JSON object of config fields
+/// (like the `options` of an imgroll-batch manifest) with the `OVERRIDABLE_OPTIONS`. Only mistakes in the latter
+/// are `BadOptions`.
+fn options_for(meta: &HashMap<String, String>) -> Result<imgroll::ProcessOptions, Error> {
+    let mut opts = imgroll::ProcessOptions::from_env().context(Image {})?;
+    if let Ok(v) = std::env::var("INCLUDE_ORIGINAL") {
+        opts.include_original = v != "false";
+    }
+    if let Ok(v) = std::env::var("TINY_PREVIEW_FILE") {
+        opts.tiny_preview_file = v == "true";
+    }
+    let overrides = match meta.get("imgroll-options") {
+        Some(overrides) => overrides,
+        None => return Ok(opts),
+    };
+    // S3 itself allows 2 KiB of metadata in total, but other implementations can be more generous
+    if overrides.len() > MAX_OPTIONS_BYTES {
+        return BadOptions {
+            message: format!("longer than {} bytes", MAX_OPTIONS_BYTES),
         }
-        info!("Sending callback request");
-        let hclnt = reqwest::Client::new();
-        let resp = hclnt
-            .post(cb_url)
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .body(json)
-            .send()
-            .await
-            .context(CbReq {})?;
-        info!("Callback response: {:?}", &resp);
+        .fail();
     }
+    let bad = |message: String| Error::BadOptions { message };
+    let overrides =
+        serde_json::from_str::<serde_json::Map<String, Value>>(overrides).map_err(|e| bad(e.to_string()))?;
+    for (field, value) in &overrides {
+        if !OVERRIDABLE_OPTIONS.contains(&field.as_str()) {
+            return Err(bad(format!("{} can't be set per upload", field)));
+        }
+        if value.as_array().map_or(false, |items| items.len() > MAX_OPTIONS_ITEMS) {
+            return Err(bad(format!("{} has more than {} entries", field, MAX_OPTIONS_ITEMS)));
+        }
+    }
+    let mut options = match serde_json::to_value(&opts).context(JsonEnc {})? {
+        Value::Object(map) => map,
+        _ => unreachable!("options serialize to an object"),
+    };
+    options.extend(overrides);
+    imgroll::ProcessOptions::from_json_str(&Value::Object(options).to_string()).map_err(|e| bad(e.to_string()))
+}
 
-    Ok(event)
+/// Downloads the object without blocking the runtime, failing with `TooLarge` instead of running out of memory.
+async fn read_object(
+    clnt: &S3Client,
+    bucket: &str,
+    key: &str,
+    content_length: Option<i64>,
+    max_bytes: usize,
+) -> Result<Vec<u8>, Error> {
+    // The size from HeadObject saves starting the download at all
+    if content_length.map_or(false, |len| len > max_bytes as i64) {
+        return TooLarge { limit: max_bytes }.fail();
+    }
+    let obj = clnt
+        .get_object(GetObjectRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .context(S3Get {})?;
+    let mut buf = Vec::with_capacity(
+        obj.content_length
+            .and_then(|len| len.try_into().ok())
+            .unwrap_or_default(),
+    );
+    // The object may have been replaced since, one byte over the limit is enough to tell
+    obj.body
+        .ok_or("body")?
+        .into_async_read()
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut buf)
+        .await
+        .context(InputOutput {})?;
+    if buf.len() > max_bytes {
+        return TooLarge { limit: max_bytes }.fail();
+    }
+    Ok(buf)
+}
+
+/// Why an object couldn't be processed, for the callback.
+struct Failure {
+    kind: &'static str,
+    message: String,
+    /// Retrying wouldn't help, e.g. for corrupt files.
+    permanent: bool,
+}
+
+/// Tells the uploader waiting for the callback, which would otherwise never come, that there's no photo.
+async fn report_failure(
+    url: &str,
+    secret: Option<&str>,
+    retry: Retry,
+    key: &str,
+    Failure {
+        kind,
+        message,
+        permanent,
+    }: Failure,
+) -> Result<(), Error> {
+    let json = serde_json::json!({
+        "error": { "kind": kind, "message": message, "key": key, "permanent": permanent }
+    });
+    let resp = callback(url, &json.to_string(), secret, retry).await?;
+    info!("Callback response: {:?}", &resp);
+    Ok(())
+}
+
+/// POSTs JSON to the `imgroll-cb` URL: the photo, or `{"error": {...}}` for objects that can't be processed.
+/// Each attempt times out after `CALLBACK_TIMEOUT_SECS` (30 by default), and 5xx responses are retried.
+/// With a secret, the requests are signed, see `imgroll::signature`.
+async fn callback(url: &str, json: &str, secret: Option<&str>, retry: Retry) -> Result<reqwest::Response, Error> {
+    let timeout = match std::env::var("CALLBACK_TIMEOUT_SECS") {
+        Ok(secs) => Duration::from_secs(secs.parse().context(ParseNum {})?),
+        Err(_) => Duration::from_secs(30),
+    };
+    let hclnt = reqwest::Client::builder().timeout(timeout).build().context(CbReq {})?;
+    info!("Sending callback request");
+    let is_transient = |e: &reqwest::Error| {
+        e.is_timeout() || e.is_connect() || e.status().map_or(false, |status| status.is_server_error())
+    };
+    retry
+        .run("Callback", is_transient, || async {
+            let mut request = hclnt
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json");
+            if let Some(secret) = secret {
+                // Signed again for every attempt, so that retries don't look like replays
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+                request = request.header(imgroll::signature::TIMESTAMP_HEADER, timestamp).header(
+                    imgroll::signature::SIGNATURE_HEADER,
+                    imgroll::signature::sign(secret.as_bytes(), timestamp, json.as_bytes()),
+                );
+            }
+            let resp = request.body(json.to_owned()).send().await?;
+            // Retried, other statuses are only logged like before
+            if resp.status().is_server_error() {
+                resp.error_for_status()
+            } else {
+                Ok(resp)
+            }
+        })
+        .await
+        .context(CbReq {})
+}
+
+/// Where the outputs go: `OUTPUT_BUCKET` and `OUTPUT_PREFIX`, unless the object's `imgroll-out-bucket`
+/// and `imgroll-out-prefix` metadata say otherwise, by default next to the original.
+fn output_location(bucket: &str, meta: &HashMap<String, String>) -> imgroll::s3::Location {
+    imgroll::s3::Location {
+        bucket: setting(meta, "imgroll-out-bucket", "OUTPUT_BUCKET").unwrap_or_else(|| bucket.to_owned()),
+        prefix: setting(meta, "imgroll-out-prefix", "OUTPUT_PREFIX")
+            .unwrap_or_default()
+            .trim_matches('/')
+            .to_owned(),
+    }
+}
+
+/// The original's metadata, or else the environment variable.
+fn setting(meta: &HashMap<String, String>, meta_key: &str, var: &str) -> Option<String> {
+    meta.get(meta_key).cloned().or_else(|| std::env::var(var).ok())
+}
+
+async fn exists(clnt: &S3Client, bucket: &str, key: &str) -> Result<bool, Error> {
+    match clnt
+        .head_object(HeadObjectRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            ..Default::default()
+        })
+        .await
+    {
+        Ok(_) => Ok(true),
+        Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(false),
+        // HEAD responses have no body to parse the error from
+        Err(RusotoError::Unknown(resp)) if resp.status.as_u16() == 404 => Ok(false),
+        Err(source) => Err(Error::S3Head { source }),
+    }
 }
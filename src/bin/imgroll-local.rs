@@ -1,5 +1,17 @@
-use snafu::{ResultExt, Snafu};
-use std::{env, fs, io, io::Read};
+use clap::{CommandFactory, Parser};
+use snafu::{ensure, ResultExt, Snafu};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs, io,
+    io::{BufRead, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -11,41 +23,1224 @@ pub enum Error {
 
     #[snafu(display("Unable to process: {}", source))]
     Image { source: imgroll::Error },
+
+    #[snafu(display("Is a directory, use --recursive to process the images in it"))]
+    IsDirectory,
+
+    #[snafu(display("No such file"))]
+    NotFound,
+
+    #[snafu(display("Invalid glob pattern: {}", source))]
+    Pattern { source: glob::PatternError },
+
+    #[snafu(display("Unable to read a matched path: {}", source))]
+    Glob { source: glob::GlobError },
+
+    #[snafu(display("The pattern matched no files"))]
+    NoMatches,
+
+    #[snafu(display("Unable to walk the directory: {}", source))]
+    Walk { source: walkdir::Error },
+
+    #[snafu(display("All {} inputs failed", total))]
+    AllFailed { total: usize },
+
+    #[snafu(display("Stopped because {} failed", path))]
+    FailFast { path: String },
+
+    #[snafu(display("Interrupted, {} inputs were not processed", skipped))]
+    Interrupted { skipped: usize },
+
+    #[snafu(display("Unable to watch for changes: {}", source))]
+    Watch { source: notify::Error },
+
+    #[snafu(display("Unable to handle Ctrl-C: {}", source))]
+    Signal { source: ctrlc::Error },
+
+    #[snafu(display("--name is for the image read from stdin, add - to the inputs"))]
+    NameWithoutStdin,
+
+    #[snafu(display("Stdin can't be both an input and the --files-from list"))]
+    StdinTwice,
+
+    #[snafu(display("Unable to parse {}: {}", path.display(), source))]
+    JsonDec { path: PathBuf, source: serde_json::Error },
+
+    #[snafu(display("Expected {}, found {}", expected, found))]
+    Mismatch { expected: String, found: String },
+
+    #[snafu(display("Invalid data URI"))]
+    DataUri,
+
+    #[snafu(display("{} of {} files failed verification", failed, total))]
+    VerifyFailed { failed: usize, total: usize },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Optimizes images for the web, printing a JSON description of each one.
+#[derive(Debug, Parser)]
+#[command(name = "imgroll-local", version)]
+struct Args {
+    /// Image files, directories (with --recursive), glob patterns, or - for stdin
+    #[arg(value_name = "PATH")]
+    inputs: Vec<String>,
+
+    /// Write the files into DIR instead of the current directory
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    out_dir: PathBuf,
+
+    /// Put the files into a zip (or .tar) archive with a manifest.json
+    #[arg(long, value_name = "out.zip")]
+    archive: Option<String>,
+
+    /// Write the JSON into a file instead of stdout, by default NAME.json next to the files
+    #[arg(long, value_name = "PATH|DIR")]
+    json_out: Option<Option<PathBuf>>,
+
+    /// Indent the JSON
+    #[arg(long)]
+    pretty: bool,
+
+    /// Process the JPEG and PNG files in directories, mirroring their structure under the output directory
+    #[arg(long, short = 'r')]
+    recursive: bool,
+
+    /// Also process the paths listed in FILE (or - for stdin), one per line, as they're read
+    #[arg(long, value_name = "FILE")]
+    files_from: Option<String>,
+
+    /// The --files-from paths are separated by NUL characters, like from find -print0
+    #[arg(short = '0', long = "null", requires = "files_from")]
+    null: bool,
+
+    /// Stop at the first input that fails, instead of reporting it and going on
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Keep processing the images that are added to DIR (or changed) until Ctrl-C, after the ones
+    /// that are newer than their JSON
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["archive", "dry_run", "html"])]
+    watch: Option<PathBuf>,
+
+    /// Overwrite existing output files (and the archive) instead of stopping at them
+    #[arg(long)]
+    force: bool,
+
+    /// Process the images but write nothing, only list the files that would be written with their sizes,
+    /// and print the JSON
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Also write a preview.html page with all the photos, their file sizes, and a switch between the formats
+    #[arg(long)]
+    html: bool,
+
+    /// The file name of the image read from stdin, which the output names are based on [default: stdin]
+    #[arg(long, value_name = "FILENAME")]
+    name: Option<String>,
+
+    /// Prefix every file name in the JSON with this, e.g. https://cdn.example.com/photos/
+    #[arg(long, value_name = "URL")]
+    base_url: Option<String>,
+
+    /// Upload the files to S3 (or a compatible service) under this prefix instead of writing them,
+    /// with their URLs in the JSON
+    #[cfg(feature = "s3")]
+    #[arg(
+        long,
+        value_name = "s3://BUCKET/PREFIX",
+        value_parser = parse_s3_url,
+        conflicts_with_all = ["archive", "watch", "dry_run", "html"]
+    )]
+    s3: Option<imgroll::s3::Location>,
+
+    /// The S3-compatible service to upload to, e.g. https://minio.local, instead of AWS
+    #[cfg(feature = "s3")]
+    #[arg(long, value_name = "URL", requires = "s3")]
+    endpoint: Option<String>,
+
+    /// Use path-style URLs (ENDPOINT/BUCKET/KEY) instead of the bucket's own host, for services without those
+    #[cfg(feature = "s3")]
+    #[arg(long, requires = "s3")]
+    path_style: bool,
+
+    /// How many files to process at once [default: half the physical cores]
+    #[arg(long, short = 'j', value_name = "N")]
+    jobs: Option<usize>,
+
+    /// Check the files from an earlier run instead, for the JSON in PATH (a file, or a directory of them):
+    /// that they decode, and have the type and dimensions the JSON says
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["inputs", "watch"])]
+    verify: Option<PathBuf>,
+
+    /// Processing options from a TOML (or .json) config file, see imgroll.example.toml; the flags below override it
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// JPEG quality (0-100), instead of the default that depends on the image size
+    #[arg(long, value_name = "Q")]
+    jpeg_quality: Option<f32>,
+
+    /// WebP quality (0-100)
+    #[arg(long, value_name = "Q")]
+    webp_quality: Option<f32>,
+
+    /// Widths of the smaller renditions, e.g. 640,1280,1920
+    #[arg(long, value_name = "W,...", value_delimiter = ',')]
+    widths: Option<Vec<u32>>,
+
+    /// Downscale the main image to fit into a square of this size [default: 3000]
+    #[arg(long, value_name = "PX")]
+    max_dimension: Option<u32>,
+
+    /// Don't list the original file in the sources
+    #[arg(long)]
+    no_original: bool,
+
+    /// Only produce these formats, e.g. jpeg,webp
+    #[arg(long, value_name = "FORMAT,...", value_delimiter = ',', value_parser = parse_format)]
+    formats: Option<Vec<imgroll::OutputFormat>>,
+}
+
+fn parse_format(format: &str) -> Result<imgroll::OutputFormat, String> {
+    serde_json::from_value(serde_json::Value::String(format.to_owned()))
+        .map_err(|_| "expected jpeg, webp or png".to_owned())
+}
+
+#[cfg(feature = "s3")]
+fn parse_s3_url(url: &str) -> Result<imgroll::s3::Location, String> {
+    imgroll::s3::Location::parse(url).ok_or_else(|| "expected s3://bucket/prefix".to_owned())
+}
+
+impl Args {
+    /// The config file (or the defaults) with the flags applied.
+    fn options(&self) -> Result<imgroll::ProcessOptions> {
+        let mut opts = match &self.config {
+            Some(path) => imgroll::ProcessOptions::from_path(path).context(Image {})?,
+            None => imgroll::ProcessOptions::default(),
+        };
+        if self.jpeg_quality.is_some() {
+            opts.jpeg_quality = self.jpeg_quality;
+        }
+        if self.webp_quality.is_some() {
+            opts.webp_quality = self.webp_quality;
+        }
+        if self.widths.is_some() {
+            opts.widths = self.widths.clone();
+        }
+        if let Some(max_dimension) = self.max_dimension {
+            opts.max_dimension = max_dimension;
+        }
+        if self.no_original {
+            opts.include_original = false;
+        }
+        if self.formats.is_some() {
+            opts.formats = self.formats.clone();
+        }
+        Ok(opts)
+    }
+}
+
+/// Where the JSON goes.
+enum JsonOut {
+    Stdout,
+    /// `<input name>.json` files in this directory.
+    Dir(PathBuf),
+    File(PathBuf),
+}
+
+impl JsonOut {
+    /// The file for an input's JSON, `None` for stdout.
+    fn path_for(&self, input: &Input) -> Option<PathBuf> {
+        match self {
+            JsonOut::Stdout => None,
+            JsonOut::Dir(dir) => {
+                let name = input.name();
+                let stem = Path::new(name)
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .unwrap_or(name);
+                Some(dir.join(&input.rel_dir).join(format!("{}.json", stem)))
+            },
+            JsonOut::File(path) => Some(path.clone()),
+        }
+    }
+}
+
+/// One image to process.
+struct Input {
+    /// A file path, or `-` for stdin.
+    path: String,
+    /// The name to process stdin as, from `--name`.
+    stdin_name: Option<String>,
+    /// Where the outputs go under `--out-dir` (and in the archive and JSON directory),
+    /// mirroring the directory tree the file was found in.
+    rel_dir: PathBuf,
+}
+
+impl Input {
+    /// The name the photo is processed as.
+    fn name(&self) -> &str {
+        if self.path == "-" {
+            self.stdin_name.as_deref().unwrap_or("stdin")
+        } else {
+            &self.path
+        }
+    }
+}
+
 fn main() -> Result<()> {
-    match &env::args().skip(1).collect::<Vec<String>>()[..] {
-        [] => println!("use with paths or -"),
-        [x] if x == "-" => {
-            let mut buf = Vec::new();
-            {
-                let stdin_ = io::stdin();
-                let mut stdin = stdin_.lock();
-                stdin.read_to_end(&mut buf).context(InputOutput {})?;
-            }
-            output(imgroll::process_photo(&buf, "stdin").context(Image {})?)?;
+    let mut args = Args::parse();
+    // The path is optional, and `--json-out -` means the default path followed by stdin
+    if let Some(Some(path)) = &args.json_out {
+        if path == Path::new("-") {
+            args.json_out = Some(None);
+            args.inputs.insert(0, "-".to_owned());
+        }
+    }
+    if let Some(path) = &args.verify {
+        return verify(path);
+    }
+    if args.inputs.is_empty() && args.watch.is_none() && args.files_from.is_none() {
+        Args::command().print_help().context(InputOutput {})?;
+        return Ok(());
+    }
+    let base_opts = args.options()?;
+
+    let mut failed = 0;
+    let mut inputs = Vec::new();
+    ensure!(
+        args.name.is_none() || args.inputs.iter().any(|arg| arg == "-"),
+        NameWithoutStdin {}
+    );
+    ensure!(
+        args.files_from.as_deref() != Some("-") || !args.inputs.iter().any(|arg| arg == "-"),
+        StdinTwice {}
+    );
+    #[cfg(feature = "s3")]
+    let s3 = match args.s3.take() {
+        Some(location) => Some(S3Sink::new(location, args.endpoint.take(), args.path_style).context(InputOutput {})?),
+        None => None,
+    };
+    let list = match &args.files_from {
+        Some(path) => Some(FileList::open(path, if args.null { 0 } else { b'\n' }).context(InputOutput {})?),
+        None => None,
+    };
+    for arg in &args.inputs {
+        match expand(arg, args.recursive) {
+            Ok(found) => inputs.extend(found.into_iter().map(|input| Input {
+                stdin_name: args.name.clone(),
+                ..input
+            })),
+            Err(e) if !args.fail_fast => {
+                eprintln!("{}: {}", arg, e);
+                failed += 1;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+
+    let Args {
+        out_dir,
+        archive,
+        json_out,
+        pretty,
+        fail_fast,
+        jobs,
+        watch,
+        force,
+        dry_run,
+        base_url,
+        html,
+        recursive,
+        ..
+    } = args;
+    // Changed files are processed again, that has to replace their outputs
+    let force = force || watch.is_some();
+    if !dry_run {
+        fs::create_dir_all(&out_dir).context(InputOutput {})?;
+    }
+    let json_out = match json_out {
+        _ if dry_run => JsonOut::Stdout,
+        // Watching needs the JSON in files, to know what's up to date on the next start
+        None if watch.is_some() => JsonOut::Dir(out_dir.clone()),
+        None => JsonOut::Stdout,
+        Some(None) => JsonOut::Dir(out_dir.clone()),
+        // Every input gets its own file
+        Some(Some(path)) if inputs.len() + failed > 1 || list.is_some() || path.is_dir() || watch.is_some() => {
+            fs::create_dir_all(&path).context(InputOutput {})?;
+            JsonOut::Dir(path)
         },
-        paths => {
-            for path in paths {
-                let mut file = fs::File::open(path).context(InputOutput {})?;
-                let mut buf = Vec::new();
-                file.read_to_end(&mut buf).context(InputOutput {})?;
-                output(imgroll::process_photo(&buf, path).context(Image {})?)?;
-            }
+        Some(Some(path)) => JsonOut::File(path),
+    };
+    if let Some(dir) = &watch {
+        let found = expand(&dir.to_string_lossy(), true)?;
+        let abs_out_dir = out_dir.canonicalize().context(InputOutput {})?;
+        inputs.extend(found.into_iter().filter(|input| {
+            let in_out_dir = Path::new(&input.path)
+                .canonicalize()
+                .map_or(false, |path| path.starts_with(&abs_out_dir));
+            !in_out_dir && !up_to_date(input, &json_out)
+        }));
+    }
+    let out = match archive {
+        Some(path) if dry_run => Output::DryRun(path.into()),
+        Some(path) => Output::archive(&path, force).context(InputOutput {})?,
+        None if dry_run => Output::DryRun(out_dir.clone()),
+        None => Output::Files {
+            dir: out_dir.clone(),
+            force,
         },
+    };
+    #[cfg(feature = "s3")]
+    let (out, base_url) = match s3 {
+        // Like BUCKET_PUBLIC_HOST for the lambda function, --base-url is for a CDN in front of the bucket
+        Some(s3) => {
+            let base_url = base_url.unwrap_or_else(|| s3.base_url());
+            (Output::S3(s3), Some(base_url))
+        },
+        None => (out, base_url),
+    };
+    let writer = Writer {
+        out: Mutex::new(out),
+        json_out,
+        pretty,
+        force,
+        base_url,
+        html,
+    };
+
+    let jobs = jobs.unwrap_or_else(|| num_cpus::get_physical() / 2).max(1);
+    // The length of a list is only known at the end
+    let jobs = if list.is_some() {
+        jobs
+    } else {
+        jobs.min(inputs.len().max(1))
+    };
+    let mut base_opts = base_opts;
+    if jobs > 1 {
+        // Parallel files instead of parallel encoders, so there are at most `jobs` images in memory
+        base_opts.parallelism = imgroll::Parallelism::Sequential;
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    ctrlc::set_handler({
+        let stop = stop.clone();
+        move || {
+            if stop.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+            eprintln!("Finishing the images in progress, press Ctrl-C again to quit now");
+        }
+    })
+    .context(Signal {})?;
+
+    let queue = Queue {
+        state: Mutex::new(QueueState {
+            pending: inputs.into(),
+            list,
+            recursive,
+            fail_fast,
+            taken: 0,
+            failed: 0,
+            failed_fast: None,
+        }),
+    };
+    let mut results = thread::scope(|scope| {
+        let workers = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    while !stop.load(Ordering::SeqCst) {
+                        let (i, input) = match queue.next() {
+                            Some(next) => next,
+                            None => break,
+                        };
+                        let result = process(&input, &base_opts, &writer);
+                        if let Err(e) = &result {
+                            eprintln!("{}: {}", input.path, e);
+                            if fail_fast {
+                                stop.store(true, Ordering::SeqCst);
+                            }
+                        }
+                        done.push((i, input.path, result));
+                    }
+                    done
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+            .collect::<Vec<_>>()
+    });
+    // The manifest lists the photos in input order, whichever worker finished first
+    results.sort_by_key(|(i, _, _)| *i);
+    if let Some(dir) = &watch {
+        if !stop.load(Ordering::SeqCst) {
+            // Failures were reported already, the watcher keeps going
+            return watch_dir(dir, &out_dir, &base_opts, &writer, &stop);
+        }
+    }
+
+    let skipped = if stop.load(Ordering::SeqCst) {
+        queue.remaining()
+    } else {
+        0
+    };
+    let queue = queue.state.into_inner().unwrap();
+    failed += queue.failed;
+    let total = queue.taken + failed;
+    let mut photos = Vec::new();
+    let mut previews = Vec::new();
+    let mut first_failure = None;
+    for (_, path, result) in results {
+        match result {
+            Ok((photo, preview)) => {
+                photos.push(photo);
+                previews.extend(preview);
+            },
+            Err(_) => {
+                failed += 1;
+                first_failure.get_or_insert(path);
+            },
+        }
+    }
+    let mut out = writer.out.into_inner().unwrap();
+    if html {
+        let page = preview_page(&previews);
+        out.write("preview.html", page.len(), "text/html", &mut page.as_bytes())
+            .context(InputOutput {})?;
+    }
+    out.finish(&photos)?;
+    if failed > 0 {
+        eprintln!("{} of {} inputs failed", failed, total);
+    }
+    if let (true, Some(path)) = (fail_fast, first_failure.or(queue.failed_fast)) {
+        return FailFast { path }.fail();
+    }
+    if skipped > 0 {
+        return Interrupted { skipped }.fail();
+    }
+    if failed > 0 && failed == total {
+        return AllFailed { total }.fail();
+    }
+    Ok(())
+}
+
+/// The inputs, handed out to the workers in order. The `--files-from` list is read as they need more.
+struct Queue {
+    state: Mutex<QueueState>,
+}
+
+struct QueueState {
+    pending: VecDeque<Input>,
+    list: Option<FileList>,
+    recursive: bool,
+    fail_fast: bool,
+    /// How many inputs were handed out.
+    taken: usize,
+    /// How many listed paths were not found (or failed to expand otherwise).
+    failed: usize,
+    /// The listed path that stopped everything with `--fail-fast`.
+    failed_fast: Option<String>,
+}
+
+impl Queue {
+    /// The next input, with its position for putting the results in order.
+    fn next(&self) -> Option<(usize, Input)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.failed_fast.is_some() {
+                return None;
+            }
+            if let Some(input) = state.pending.pop_front() {
+                state.taken += 1;
+                return Some((state.taken - 1, input));
+            }
+            let path = match state.list.as_mut()?.next() {
+                Some(Ok(path)) => path,
+                Some(Err(e)) => {
+                    eprintln!("Unable to read the file list: {}", e);
+                    state.failed += 1;
+                    state.list = None;
+                    continue;
+                },
+                None => return None,
+            };
+            match expand(&path, state.recursive) {
+                Ok(found) => state.pending.extend(found),
+                Err(e) => {
+                    eprintln!("{}: {}", path, e);
+                    state.failed += 1;
+                    if state.fail_fast {
+                        state.failed_fast = Some(path);
+                    }
+                },
+            }
+        }
     }
 
+    /// How many inputs (or listed paths, which can be directories) were not handed out.
+    fn remaining(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let listed = state.list.take().map_or(0, Iterator::count);
+        state.pending.len() + listed
+    }
+}
+
+/// The paths in a `--files-from` list, read as they're needed.
+struct FileList {
+    paths: io::Split<Box<dyn BufRead + Send>>,
+    delimiter: u8,
+}
+
+impl FileList {
+    fn open(path: &str, delimiter: u8) -> io::Result<Self> {
+        let reader: Box<dyn BufRead + Send> = if path == "-" {
+            Box::new(io::BufReader::new(io::stdin()))
+        } else {
+            Box::new(io::BufReader::new(fs::File::open(path)?))
+        };
+        Ok(FileList {
+            paths: reader.split(delimiter),
+            delimiter,
+        })
+    }
+}
+
+impl Iterator for FileList {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut path = match self.paths.next()? {
+                Ok(path) => path,
+                Err(e) => return Some(Err(e)),
+            };
+            if self.delimiter == b'\n' && path.last() == Some(&b'\r') {
+                path.pop();
+            }
+            // Blank lines are skipped
+            if !path.is_empty() {
+                return Some(Ok(String::from_utf8_lossy(&path).into_owned()));
+            }
+        }
+    }
+}
+
+/// Processes the images that are created or modified under `dir` (and its subdirectories), until Ctrl-C.
+fn watch_dir(
+    dir: &Path,
+    out_dir: &Path,
+    base_opts: &imgroll::ProcessOptions,
+    writer: &Writer,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    // Editors and file managers write in chunks, wait for a second of quiet
+    let mut debouncer = notify_debouncer_mini::new_debouncer(Duration::from_secs(1), sender).context(Watch {})?;
+    debouncer
+        .watcher()
+        .watch(dir, notify::RecursiveMode::Recursive)
+        .context(Watch {})?;
+    // The events have absolute paths
+    let abs_dir = dir.canonicalize().context(InputOutput {})?;
+    let abs_out_dir = out_dir.canonicalize().context(InputOutput {})?;
+    eprintln!("Watching {} for changes", dir.display());
+    while !stop.load(Ordering::SeqCst) {
+        let events = match receiver.recv_timeout(Duration::from_millis(200)) {
+            Ok(Ok(events)) => events,
+            Ok(Err(e)) => {
+                eprintln!("Watch error: {}", e);
+                continue;
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        for event in events {
+            // The outputs can be in the watched directory, they are not inputs
+            if !event.path.is_file() || !is_image(&event.path) || event.path.starts_with(&abs_out_dir) {
+                continue;
+            }
+            let rel_path = match event.path.strip_prefix(&abs_dir) {
+                Ok(rel_path) => rel_path,
+                Err(_) => continue,
+            };
+            let input = Input {
+                path: dir.join(rel_path).to_string_lossy().into_owned(),
+                stdin_name: None,
+                rel_dir: rel_path.parent().map_or_else(PathBuf::new, Path::to_path_buf),
+            };
+            if let Err(e) = process(&input, base_opts, writer) {
+                eprintln!("{}: {}", input.path, e);
+            }
+        }
+    }
     Ok(())
 }
 
-fn output((photo, files): (imgroll::Photo, Vec<imgroll::OutFile>)) -> Result<()> {
-    println!("{}", serde_json::to_string(&photo).context(JsonEnc {})?);
-    for imgroll::OutFile { name, bytes, .. } in files {
-        use std::io::Write;
-        let mut file = fs::File::create(name).context(InputOutput {})?;
-        file.write_all(&bytes).context(InputOutput {})?;
+/// Checks every file referenced by the photo JSON in `path`, reporting the ones that are missing or wrong.
+fn verify(path: &Path) -> Result<()> {
+    let (root, json_paths) = if path.is_dir() {
+        let mut json_paths = Vec::new();
+        for entry in walkdir::WalkDir::new(path).sort_by_file_name() {
+            let entry = entry.context(Walk {})?;
+            if entry.file_type().is_file() && entry.path().extension().map_or(false, |ext| ext == "json") {
+                json_paths.push(entry.into_path());
+            }
+        }
+        (Some(path), json_paths)
+    } else {
+        (None, vec![path.to_path_buf()])
+    };
+    let mut total: usize = 0;
+    let mut failed: usize = 0;
+    for json_path in &json_paths {
+        let checks = match verify_photo(json_path, root) {
+            Ok(checks) => checks,
+            Err(e) => vec![(json_path.display().to_string(), Err(e))],
+        };
+        for (file, result) in checks {
+            total += 1;
+            if let Err(e) = result {
+                eprintln!("{}: {}", file, e);
+                failed += 1;
+            }
+        }
     }
+    eprintln!("Checked {} files for {} photos", total, json_paths.len());
+    ensure!(failed == 0, VerifyFailed { failed, total });
     Ok(())
 }
+
+/// The result of checking each output in a photo's JSON, by file name.
+/// The files are looked for next to the JSON and in the directories above it, up to `root`.
+fn verify_photo(json_path: &Path, root: Option<&Path>) -> Result<Vec<(String, Result<()>)>> {
+    let json = fs::read(json_path).context(InputOutput {})?;
+    let photo: imgroll::Photo = serde_json::from_slice(&json).context(JsonDec { path: json_path })?;
+    // The names are relative to the output directory, which is above the JSON when it's mirroring subdirectories
+    let json_dir = json_path.parent().unwrap_or_else(|| Path::new(""));
+    let locate = |src: &str| {
+        json_dir
+            .ancestors()
+            .take_while(|dir| root.map_or(true, |root| dir.starts_with(root)))
+            .map(|dir| dir.join(src))
+            .find(|path| path.is_file())
+            .unwrap_or_else(|| json_dir.join(src))
+    };
+    let mut checks = Vec::new();
+    // The original is the input, not an output
+    for src in photo.source.iter().filter(|src| !src.original) {
+        for entry in &src.srcset {
+            // Crops have their own aspect ratio
+            let height = match src.media {
+                None if photo.served_width > 0 => Some(
+                    (f64::from(entry.width) * f64::from(photo.served_height) / f64::from(photo.served_width)).round()
+                        as u32,
+                ),
+                _ => None,
+            };
+            let path = locate(&entry.src);
+            let result = verify_file(&path, &src.r#type, Some(entry.width), height);
+            checks.push((path.display().to_string(), result));
+        }
+    }
+    if let Some(social) = &photo.social_preview {
+        let path = locate(&social.src);
+        let r#type = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("webp") => "image/webp",
+            _ => "image/jpeg",
+        };
+        let result = verify_file(&path, r#type, Some(social.width), None);
+        checks.push((path.display().to_string(), result));
+    }
+    if let Some(url) = &photo.tiny_preview_url {
+        let path = locate(url);
+        let result = verify_file(&path, "image/webp", None, None);
+        checks.push((path.display().to_string(), result));
+    }
+    if let Some(uri) = &photo.tiny_preview {
+        // Either the WebP data URI itself, or an SVG one with it inside
+        const WEBP_URI: &str = "data:image/webp;base64,";
+        let result = uri
+            .find(WEBP_URI)
+            .map(|start| uri[start + WEBP_URI.len()..].split('\'').next().unwrap_or_default())
+            .and_then(|data| base64::decode(data).ok())
+            .ok_or(Error::DataUri)
+            .and_then(|webp| verify_image(&webp, "image/webp", None, None));
+        checks.push((format!("{} (tiny preview)", json_path.display()), result));
+    }
+    Ok(checks)
+}
+
+fn verify_file(path: &Path, r#type: &str, width: Option<u32>, height: Option<u32>) -> Result<()> {
+    ensure!(path.is_file(), NotFound {});
+    let contents = fs::read(path).context(InputOutput {})?;
+    verify_image(&contents, r#type, width, height)
+}
+
+/// Decodes the image, and checks that it's of the type and (when they're known) the dimensions it should be.
+/// The height is allowed to be off by one, since it's rounded when resizing.
+fn verify_image(contents: &[u8], r#type: &str, width: Option<u32>, height: Option<u32>) -> Result<()> {
+    use image::GenericImageView;
+    let (actual_type, imag) = imgroll::decode_output_file(contents).context(Image {})?;
+    ensure!(
+        actual_type == r#type,
+        Mismatch {
+            expected: r#type,
+            found: actual_type
+        }
+    );
+    if let Some(width) = width {
+        ensure!(
+            imag.width() == width,
+            Mismatch {
+                expected: format!("{}px wide", width),
+                found: format!("{}px", imag.width())
+            }
+        );
+    }
+    if let Some(height) = height {
+        ensure!(
+            imag.height() + 1 >= height && imag.height() <= height + 1,
+            Mismatch {
+                expected: format!("{}px high", height),
+                found: format!("{}px", imag.height())
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Whether the JSON from an earlier run is newer than the file and its `.xmp` sidecar.
+fn up_to_date(input: &Input, json_out: &JsonOut) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    let json_modified = match json_out.path_for(input).and_then(|path| modified(&path)) {
+        Some(time) => time,
+        None => return false,
+    };
+    let source = Path::new(&input.path);
+    [source.to_path_buf(), source.with_extension("xmp")]
+        .iter()
+        .filter_map(|path| modified(path))
+        .all(|time| time <= json_modified)
+}
+
+/// Directories (with `--recursive`) and glob patterns become the images in them.
+fn expand(arg: &str, recursive: bool) -> Result<Vec<Input>> {
+    let path = Path::new(arg);
+    if arg == "-" || path.is_file() {
+        return Ok(vec![Input {
+            path: arg.to_owned(),
+            stdin_name: None,
+            rel_dir: PathBuf::new(),
+        }]);
+    }
+    if path.is_dir() {
+        ensure!(recursive, IsDirectory {});
+        let mut inputs = Vec::new();
+        for entry in walkdir::WalkDir::new(path).sort_by_file_name() {
+            let entry = entry.context(Walk {})?;
+            if entry.file_type().is_file() && is_image(entry.path()) {
+                inputs.push(Input {
+                    path: entry.path().to_string_lossy().into_owned(),
+                    stdin_name: None,
+                    rel_dir: relative_dir(entry.path(), path),
+                });
+            }
+        }
+        return Ok(inputs);
+    }
+    // Not a file, so it must be a pattern (shells on Windows don't expand them)
+    ensure!(arg.contains(&['*', '?', '['][..]), NotFound {});
+    let base = glob_base(arg);
+    let mut inputs = Vec::new();
+    for found in glob::glob(arg).context(Pattern {})? {
+        let found = found.context(Glob {})?;
+        if found.is_file() {
+            inputs.push(Input {
+                rel_dir: relative_dir(&found, &base),
+                path: found.to_string_lossy().into_owned(),
+                stdin_name: None,
+            });
+        }
+    }
+    ensure!(!inputs.is_empty(), NoMatches {});
+    Ok(inputs)
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()).map_or(false, |ext| {
+        ["jpg", "jpeg", "png"]
+            .iter()
+            .any(|known| ext.eq_ignore_ascii_case(known))
+    })
+}
+
+/// The leading components of a pattern that have no wildcards.
+fn glob_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|part| !part.as_os_str().to_string_lossy().contains(&['*', '?', '['][..]))
+        .collect()
+}
+
+fn relative_dir(file: &Path, base: &Path) -> PathBuf {
+    file.strip_prefix(base)
+        .ok()
+        .and_then(Path::parent)
+        .map_or_else(PathBuf::new, Path::to_path_buf)
+}
+
+/// Where the results go, shared by the workers.
+struct Writer {
+    out: Mutex<Output>,
+    json_out: JsonOut,
+    pretty: bool,
+    force: bool,
+    /// Prepended to the file names in the JSON.
+    base_url: Option<String>,
+    /// Whether to make the `preview_section`s.
+    html: bool,
+}
+
+/// Processes an input, giving the photo as in the JSON and its `preview_section` with `--html`.
+fn process(
+    input: &Input,
+    base_opts: &imgroll::ProcessOptions,
+    writer: &Writer,
+) -> Result<(imgroll::Photo, Option<Preview>)> {
+    let (buf, opts) = if input.path == "-" {
+        let mut buf = Vec::new();
+        {
+            let stdin_ = io::stdin();
+            let mut stdin = stdin_.lock();
+            stdin.read_to_end(&mut buf).context(InputOutput {})?;
+        }
+        (buf, base_opts.clone())
+    } else {
+        imgroll::read_photo_file(&input.path, base_opts).context(Image {})?
+    };
+    let name = input.name();
+    // Archive entries always use forward slashes
+    let prefix = input
+        .rel_dir
+        .components()
+        .map(|part| format!("{}/", part.as_os_str().to_string_lossy()))
+        .collect::<String>();
+    let mut sizes = HashMap::new();
+    let mut photo = output(&buf, name, &prefix, &opts, &writer.out, &mut sizes)?;
+    if !prefix.is_empty() {
+        // The original keeps pointing at the input
+        photo.map_urls(|src| {
+            if src == name {
+                src.to_owned()
+            } else {
+                format!("{}{}", prefix, src)
+            }
+        });
+    }
+    // The page is next to the files, so it uses the names from before --base-url
+    let preview = if writer.html {
+        Some(preview_section(&photo, name, &sizes))
+    } else {
+        None
+    };
+    if let Some(base_url) = &writer.base_url {
+        photo.map_urls(|src| format!("{}/{}", base_url.trim_end_matches('/'), src));
+    }
+    let json = if writer.pretty {
+        serde_json::to_string_pretty(&photo)
+    } else {
+        serde_json::to_string(&photo)
+    }
+    .context(JsonEnc {})?;
+    match writer.json_out.path_for(input) {
+        Some(path) => {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).context(InputOutput {})?;
+            }
+            create(&path, writer.force)
+                .and_then(|mut file| file.write_all(json.as_bytes()))
+                .context(InputOutput {})?;
+            println!("{}", path.display());
+        },
+        None => println!("{}", json),
+    }
+    Ok((photo, preview))
+}
+
+fn output(
+    buf: &[u8],
+    name: &str,
+    prefix: &str,
+    opts: &imgroll::ProcessOptions,
+    out: &Mutex<Output>,
+    sizes: &mut HashMap<String, usize>,
+) -> Result<imgroll::Photo> {
+    // One file at a time goes into the archive
+    imgroll::process_photo_streaming(buf, name, opts, &mut |meta, contents| {
+        let name = format!("{}{}", prefix, meta.name);
+        out.lock().unwrap().write(&name, meta.size, &meta.mimetype, contents)?;
+        sizes.insert(name, meta.size);
+        Ok(())
+    })
+    .context(Image {})
+}
+
+/// Creates a file, or with `force`, replaces it.
+fn create(path: &Path, force: bool) -> io::Result<fs::File> {
+    if force {
+        return fs::File::create(path);
+    }
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => io::Error::new(
+                e.kind(),
+                format!("{} exists, use --force to overwrite it", path.display()),
+            ),
+            _ => e,
+        })
+}
+
+/// Where the output files go: loose files in a directory, a single archive, or S3.
+enum Output {
+    Files {
+        dir: PathBuf,
+        force: bool,
+    },
+    Zip(zip::ZipWriter<fs::File>),
+    Tar(tar::Builder<fs::File>),
+    /// Only lists the files that would be written to this directory (or archive).
+    DryRun(PathBuf),
+    #[cfg(feature = "s3")]
+    S3(S3Sink),
+}
+
+impl Output {
+    fn archive(path: &str, force: bool) -> io::Result<Self> {
+        let file = create(Path::new(path), force)?;
+        Ok(if path.ends_with(".tar") {
+            Output::Tar(tar::Builder::new(file))
+        } else {
+            Output::Zip(zip::ZipWriter::new(file))
+        })
+    }
+
+    fn write(&mut self, name: &str, size: usize, mimetype: &str, contents: &mut dyn Read) -> io::Result<()> {
+        // Only S3 has content types
+        #[cfg(not(feature = "s3"))]
+        let _ = mimetype;
+        match self {
+            Output::Files { dir, force } => {
+                let path = dir.join(name);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                io::copy(contents, &mut create(&path, *force)?).map(|_| ())
+            },
+            Output::Zip(zip) => {
+                // The images are compressed already
+                let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+                zip.start_file(name, options)?;
+                io::copy(contents, zip).map(|_| ())
+            },
+            Output::Tar(tar) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(size as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                tar.append_data(&mut header, name, contents)
+            },
+            Output::DryRun(dir) => {
+                let path = dir.join(name);
+                let exists = if path.exists() { " (exists)" } else { "" };
+                eprintln!("{:>10}  {}{}", size, path.display(), exists);
+                Ok(())
+            },
+            #[cfg(feature = "s3")]
+            Output::S3(s3) => s3.put(name, mimetype, contents),
+        }
+    }
+
+    /// Adds `manifest.json` with all the photos and completes the archive.
+    fn finish(mut self, photos: &[imgroll::Photo]) -> Result<()> {
+        if !matches!(self, Output::Zip(_) | Output::Tar(_)) {
+            return Ok(());
+        }
+        let manifest = serde_json::to_vec(photos).context(JsonEnc {})?;
+        self.write("manifest.json", manifest.len(), "application/json", &mut &manifest[..])
+            .context(InputOutput {})?;
+        match self {
+            Output::Zip(mut zip) => zip.finish().map(|_| ()).map_err(io::Error::from),
+            Output::Tar(tar) => tar.into_inner().map(|_| ()),
+            _ => Ok(()),
+        }
+        .context(InputOutput {})
+    }
+}
+
+/// Uploads for `--s3`, one at a time like the other outputs.
+#[cfg(feature = "s3")]
+struct S3Sink {
+    runtime: tokio::runtime::Runtime,
+    client: rusoto_s3::S3Client,
+    region: rusoto_core::Region,
+    location: imgroll::s3::Location,
+    path_style: bool,
+}
+
+#[cfg(feature = "s3")]
+impl S3Sink {
+    fn new(location: imgroll::s3::Location, endpoint: Option<String>, path_style: bool) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let region = imgroll::s3::region(endpoint);
+        // The HTTP client wants to be created inside the runtime
+        let client = {
+            let _guard = runtime.enter();
+            rusoto_s3::S3Client::new(region.clone())
+        };
+        Ok(S3Sink {
+            runtime,
+            client,
+            region,
+            location,
+            path_style,
+        })
+    }
+
+    /// Where the uploaded files are served from.
+    fn base_url(&self) -> String {
+        imgroll::s3::object_url(
+            &self.region,
+            &self.location.bucket,
+            &self.location.prefix,
+            self.path_style,
+        )
+    }
+
+    fn put(&self, name: &str, mimetype: &str, contents: &mut dyn Read) -> io::Result<()> {
+        use rusoto_s3::S3;
+        let mut bytes = Vec::new();
+        contents.read_to_end(&mut bytes)?;
+        let request = imgroll::s3::put_request(
+            &self.location.bucket,
+            self.location.key(name),
+            bytes,
+            mimetype.to_owned(),
+        );
+        self.runtime
+            .block_on(self.client.put_object(request))
+            .map(|_| ())
+            .map_err(|e| io::Error::other(format!("S3 put error: {}", e)))
+    }
+}
+
+/// A photo on the preview page.
+struct Preview {
+    html: String,
+    /// The MIME types it has an `<img>` for.
+    types: Vec<String>,
+}
+
+/// The `<picture>` as a browser would pick from it, an `<img>` for every format (hidden until it's picked
+/// with the switch), and the file sizes.
+fn preview_section(photo: &imgroll::Photo, name: &str, sizes: &HashMap<String, usize>) -> Preview {
+    let sources = photo
+        .source
+        .iter()
+        .filter(|src| !src.original && src.media.is_none())
+        .collect::<Vec<_>>();
+    let placeholder = photo.tiny_preview.as_ref().or(photo.tiny_preview_url.as_ref());
+    let mut html = format!(
+        r#"<figure><div class="frame" style="aspect-ratio: {} / {};{}">"#,
+        photo.served_width,
+        photo.served_height,
+        placeholder.map_or_else(String::new, |url| format!(" --placeholder: url('{}')", escape(url))),
+    );
+    html.push_str(&format!(r#"<div data-type="auto">{}</div>"#, photo.picture_html(name)));
+    for src in &sources {
+        let main = src.srcset.first().map_or("", |entry| entry.src.as_str());
+        html.push_str(&format!(
+            r#"<img data-type="{}" src="{}" srcset="{}" alt="{}" hidden>"#,
+            escape(&src.r#type),
+            escape(main),
+            escape(&src.srcset_string()),
+            escape(name),
+        ));
+    }
+    html.push_str(&format!("</div><figcaption><b>{}</b>", escape(name)));
+    for src in &sources {
+        let files = src
+            .srcset
+            .iter()
+            .map(|entry| match sizes.get(&entry.src) {
+                Some(size) => format!("{}px {:.1} KiB", entry.width, *size as f64 / 1024.0),
+                None => format!("{}px", entry.width),
+            })
+            .collect::<Vec<_>>();
+        html.push_str(&format!("<br>{}: {}", escape(&src.r#type), files.join(", ")));
+    }
+    html.push_str("</figcaption></figure>\n");
+    Preview {
+        html,
+        types: sources.iter().map(|src| src.r#type.clone()).collect(),
+    }
+}
+
+/// A self-contained page with the `preview_section`s, for checking the results in a browser.
+fn preview_page(sections: &[Preview]) -> String {
+    let mut types = Vec::new();
+    for r#type in sections.iter().flat_map(|section| &section.types) {
+        if !types.contains(r#type) {
+            types.push(r#type.clone());
+        }
+    }
+    let switch = types
+        .iter()
+        .map(|r#type| {
+            format!(
+                r#"<label><input type="radio" name="show" value="{0}"> {0}</label>"#,
+                r#type
+            )
+        })
+        .collect::<String>();
+    format!(
+        r#"<!doctype html>
+<meta charset="utf-8">
+<title>imgroll preview</title>
+<style>
+body {{ font: 14px sans-serif; max-width: 960px; margin: 0 auto; padding: 1em; }}
+figure {{ margin: 0 0 2em; }}
+.frame {{ position: relative; overflow: hidden; background: #eee; }}
+.frame::before {{ content: ""; position: absolute; inset: 0; background: var(--placeholder) center / cover; filter: blur(16px); transform: scale(1.1); }}
+.frame > * {{ position: relative; display: block; }}
+.frame > [hidden] {{ display: none; }}
+.frame img {{ display: block; width: 100%; height: auto; }}
+figcaption {{ margin-top: 0.5em; color: #555; }}
+</style>
+<p><label><input type="radio" name="show" value="auto" checked> Browser's choice</label>{}</p>
+{}<script>
+for (const input of document.querySelectorAll('input[name=show]')) {{
+  input.addEventListener('change', () => {{
+    for (const el of document.querySelectorAll('.frame > *')) el.hidden = el.dataset.type !== input.value;
+  }});
+}}
+</script>
+"#,
+        switch,
+        sections.iter().map(|section| section.html.as_str()).collect::<String>()
+    )
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,298 @@
+use hyper::{
+    body::HttpBody,
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use log::{error, info};
+use rusoto_core::RusotoError;
+use rusoto_s3::{PutObjectError, S3Client, S3};
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::{convert::Infallible, env, net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio::sync::Semaphore;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("I/O error: {}", source))]
+    InputOutput { source: std::io::Error },
+
+    #[snafu(display("Logging init error: {}", source))]
+    SetLogger { source: log::SetLoggerError },
+
+    #[snafu(display("Listen address parse error: {}", source))]
+    AddrParse { source: std::net::AddrParseError },
+
+    #[snafu(display("Number parse error: {}", source))]
+    ParseNum { source: std::num::ParseIntError },
+
+    #[snafu(display("HTTP error: {}", source))]
+    Http { source: hyper::Error },
+
+    #[snafu(display("Invalid form data: {}", source))]
+    Multipart { source: multer::Error },
+
+    #[snafu(display("S3 put error: {}", source))]
+    S3Put { source: RusotoError<PutObjectError> },
+
+    #[snafu(display("Unable to JSON encode: {}", source))]
+    JsonEnc { source: serde_json::Error },
+
+    #[snafu(display("Unable to process: {}", source))]
+    Image { source: imgroll::Error },
+
+    #[snafu(display("Bad request: {}", info))]
+    BadRequest { info: String },
+
+    #[snafu(display("The upload is larger than {} bytes", limit))]
+    TooLarge { limit: usize },
+
+    #[snafu(display("Too many requests are being processed"))]
+    Busy,
+}
+
+impl Error {
+    /// 4xx for problems with the upload, 5xx for everything on our side.
+    fn status(&self) -> StatusCode {
+        match self {
+            Error::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            Error::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Multipart { source } => match source {
+                multer::Error::StreamSizeExceeded { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                // Errors from reading a field come wrapped
+                multer::Error::StreamReadFailed(e)
+                    if matches!(e.downcast_ref(), Some(multer::Error::StreamSizeExceeded { .. })) =>
+                {
+                    StatusCode::PAYLOAD_TOO_LARGE
+                },
+                _ => StatusCode::BAD_REQUEST,
+            },
+            Error::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Image { source } => match source {
+                imgroll::Error::UnsupportedImageFormat { .. }
+                | imgroll::Error::AnimatedUnsupported { .. }
+                | imgroll::Error::NoEncoder { .. }
+                | imgroll::Error::ImageProc {
+                    source: image::ImageError::Unsupported(_),
+                } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                #[cfg(feature = "exiv2")]
+                imgroll::Error::UnsupportedFormat { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                imgroll::Error::ImageProc {
+                    source: image::ImageError::Decoding(_),
+                }
+                | imgroll::Error::RatingTooLow { .. }
+                | imgroll::Error::TooManyPixels { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Where the output files go.
+enum Store {
+    Dir(PathBuf),
+    S3 { client: S3Client, bucket: String },
+}
+
+impl Store {
+    async fn put(&self, name: String, bytes: Vec<u8>, mimetype: String) -> Result<(), Error> {
+        match self {
+            Store::Dir(dir) => tokio::fs::write(dir.join(name), bytes).await.context(InputOutput {}),
+            Store::S3 { client, bucket } => {
+                client
+                    .put_object(imgroll::s3::put_request(bucket, name, bytes, mimetype))
+                    .await
+                    .context(S3Put {})?;
+                Ok(())
+            },
+        }
+    }
+}
+
+struct Config {
+    opts: imgroll::ProcessOptions,
+    store: Store,
+    public_url: Option<String>,
+    max_body_bytes: usize,
+    permits: Semaphore,
+}
+
+impl Config {
+    fn from_env() -> Result<Config, Error> {
+        let store = match env::var("IMGROLL_S3_BUCKET") {
+            Ok(bucket) => Store::S3 {
+                client: S3Client::new(imgroll::s3::region(env::var("IMGROLL_S3_ENDPOINT").ok())),
+                bucket,
+            },
+            Err(_) => Store::Dir(env::var_os("IMGROLL_OUTPUT_DIR").map_or_else(|| ".".into(), PathBuf::from)),
+        };
+        let max_concurrent = match env::var("IMGROLL_MAX_CONCURRENT") {
+            Ok(n) => n.parse().context(ParseNum {})?,
+            Err(_) => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        Ok(Config {
+            opts: imgroll::ProcessOptions::from_env().context(Image {})?,
+            store,
+            public_url: env::var("IMGROLL_PUBLIC_URL").ok(),
+            max_body_bytes: match env::var("IMGROLL_MAX_BODY_BYTES") {
+                Ok(n) => n.parse().context(ParseNum {})?,
+                Err(_) => 50 * 1024 * 1024,
+            },
+            permits: Semaphore::new(max_concurrent),
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    simple_logger::init_with_level(log::Level::Info).context(SetLogger {})?;
+    let config = Arc::new(Config::from_env()?);
+    let addr: SocketAddr = env::var("IMGROLL_LISTEN")
+        .unwrap_or_else(|_| "127.0.0.1:8080".to_owned())
+        .parse()
+        .context(AddrParse {})?;
+    let make_service = make_service_fn(move |_| {
+        let config = config.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(config.clone(), req))) }
+    });
+    let server = hyper::Server::try_bind(&addr).context(Http {})?.serve(make_service);
+    info!("Listening on http://{}", addr);
+    server.await.context(Http {})?;
+    Ok(())
+}
+
+async fn handle(config: Arc<Config>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.uri().path() != "/process" {
+        return Ok(json_response(
+            StatusCode::NOT_FOUND,
+            r#"{"error":"Not found"}"#.to_owned(),
+        ));
+    }
+    if req.method() != Method::POST {
+        return Ok(json_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            r#"{"error":"Use POST"}"#.to_owned(),
+        ));
+    }
+    Ok(match process(&config, req).await {
+        Ok(json) => json_response(StatusCode::OK, json),
+        Err(e) => {
+            let status = e.status();
+            if status.is_server_error() {
+                error!("Processing failed: {}", e);
+            } else {
+                info!("Rejected upload: {}", e);
+            }
+            json_response(status, serde_json::json!({ "error": e.to_string() }).to_string())
+        },
+    })
+}
+
+fn json_response(status: StatusCode, json: String) -> Response<Body> {
+    let mut resp = Response::new(Body::from(json));
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    resp
+}
+
+async fn process(config: &Config, req: Request<Body>) -> Result<String, Error> {
+    let _permit = config.permits.try_acquire().map_err(|_| Error::Busy)?;
+    let (name, contents) = read_upload(req, config.max_body_bytes).await?;
+    let contents = bytes::Bytes::from(contents);
+    info!("Processing upload '{}' ({} bytes)", name, contents.len());
+    let (mut photo, files) = imgroll::process_photo_async(contents.clone(), name.clone(), config.opts.clone())
+        .await
+        .context(Image {})?;
+    if let Some(original) = photo.source.iter_mut().find(|src| src.original) {
+        // Named after the contents like the outputs, so that another upload of a photo.jpg doesn't replace it
+        let stored_name = match std::path::Path::new(&name).extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => format!("{}.{}", photo.file_prefix, ext.to_lowercase()),
+            None => photo.file_prefix.clone(),
+        };
+        for srcset in &mut original.srcset {
+            srcset.src = stored_name.clone();
+        }
+        config
+            .store
+            .put(stored_name, contents.to_vec(), original.r#type.clone())
+            .await?;
+    }
+    for imgroll::OutFile { name, bytes, mimetype } in files {
+        config.store.put(name, bytes, mimetype).await?;
+    }
+    if let Some(public_url) = &config.public_url {
+        photo.map_urls(|name| format!("{}/{}", public_url.trim_end_matches('/'), name));
+    }
+    serde_json::to_string(&photo).context(JsonEnc {})
+}
+
+/// The file name and contents from either a multipart form (the first file field)
+/// or a raw body with an `X-Filename` header.
+async fn read_upload(req: Request<Body>, limit: usize) -> Result<(String, Vec<u8>), Error> {
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok());
+    if content_length.map_or(false, |len| len > limit) {
+        return TooLarge { limit }.fail();
+    }
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    let (name, contents) = if content_type.starts_with("multipart/form-data") {
+        let boundary = multer::parse_boundary(&content_type).context(Multipart {})?;
+        let constraints = multer::Constraints::new().size_limit(multer::SizeLimit::new().whole_stream(limit as u64));
+        let mut form = multer::Multipart::with_constraints(req.into_body(), boundary, constraints);
+        loop {
+            let mut field = form.next_field().await.context(Multipart {})?.context(BadRequest {
+                info: "no file in the form data",
+            })?;
+            if let Some(name) = field.file_name().map(str::to_owned) {
+                let mut contents = Vec::new();
+                while let Some(chunk) = field.chunk().await.context(Multipart {})? {
+                    contents.extend_from_slice(&chunk);
+                }
+                break (name, contents);
+            }
+        }
+    } else {
+        let name = req
+            .headers()
+            .get("x-filename")
+            .and_then(|name| name.to_str().ok())
+            .context(BadRequest {
+                info: "missing X-Filename header",
+            })?
+            .to_owned();
+        let mut body = req.into_body();
+        let mut contents = Vec::with_capacity(content_length.unwrap_or_default());
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.context(Http {})?;
+            if contents.len() + chunk.len() > limit {
+                return TooLarge { limit }.fail();
+            }
+            contents.extend_from_slice(&chunk);
+        }
+        (name, contents)
+    };
+    // Only the last path component, it's used for the output file names
+    let name = std::path::Path::new(&name)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| !name.starts_with('.'))
+        .context(BadRequest {
+            info: "invalid file name",
+        })?
+        .to_owned();
+    if contents.is_empty() {
+        return BadRequest { info: "empty upload" }.fail();
+    }
+    Ok((name, contents))
+}
@@ -0,0 +1,80 @@
+//! Human readable descriptions of enumerated EXIF values, and other shot data without a `Metadata` getter.
+
+use crate::Metadata;
+
+pub fn exposure_program(meta: &dyn Metadata) -> Option<String> {
+    let desc = match meta.tag_numeric("Exif.Photo.ExposureProgram")? {
+        1 => "Manual",
+        2 => "Normal program",
+        3 => "Aperture priority",
+        4 => "Shutter priority",
+        5 => "Creative program",
+        6 => "Action program",
+        7 => "Portrait mode",
+        8 => "Landscape mode",
+        _ => return None,
+    };
+    Some(desc.to_owned())
+}
+
+pub fn metering_mode(meta: &dyn Metadata) -> Option<String> {
+    let desc = match meta.tag_numeric("Exif.Photo.MeteringMode")? {
+        1 => "Average",
+        2 => "Center-weighted average",
+        3 => "Spot",
+        4 => "Multi-spot",
+        5 => "Pattern",
+        6 => "Partial",
+        255 => "Other",
+        _ => return None,
+    };
+    Some(desc.to_owned())
+}
+
+pub fn white_balance(meta: &dyn Metadata) -> Option<String> {
+    let desc = match meta.tag_numeric("Exif.Photo.WhiteBalance")? {
+        0 => "Auto",
+        1 => "Manual",
+        _ => return None,
+    };
+    Some(desc.to_owned())
+}
+
+/// The star rating, 0–5 (and -1 for rejected), as set by Lightroom and friends or by some cameras.
+pub fn rating(meta: &dyn Metadata) -> Option<i32> {
+    meta.tag_numeric("Xmp.xmp.Rating")
+        .or_else(|| meta.tag_numeric("Exif.Image.Rating"))
+}
+
+/// In meters. 0 means unknown and all ones means infinity, neither is reported.
+pub fn subject_distance(meta: &dyn Metadata) -> Option<f64> {
+    let distance = meta.tag_rational("Exif.Photo.SubjectDistance")?;
+    if *distance.numer() <= 0 || *distance.denom() <= 0 {
+        return None;
+    }
+    Some(f64::from(*distance.numer()) / f64::from(*distance.denom()))
+}
+
+/// The flash value is a bit field: fired, strobe return, mode, function present, red-eye reduction.
+pub fn flash(meta: &dyn Metadata) -> Option<String> {
+    let value = meta.tag_numeric("Exif.Photo.Flash")?;
+    if value & 0x20 != 0 {
+        return Some("No flash function".to_owned());
+    }
+    let mut desc = String::from(if value & 0x1 != 0 { "Fired" } else { "Did not fire" });
+    match (value >> 3) & 0x3 {
+        1 => desc.push_str(", compulsory mode"),
+        2 => desc.push_str(", suppressed"),
+        3 => desc.push_str(", auto mode"),
+        _ => (),
+    }
+    if value & 0x40 != 0 {
+        desc.push_str(", red-eye reduction");
+    }
+    match (value >> 1) & 0x3 {
+        2 => desc.push_str(", return not detected"),
+        3 => desc.push_str(", return detected"),
+        _ => (),
+    }
+    Some(desc)
+}
@@ -0,0 +1,238 @@
+//! C API for embedding imgroll in non-Rust services, see `include/imgroll.h`.
+//!
+//! The header is generated with `cbindgen --config cbindgen.toml --output include/imgroll.h src/ffi.rs`.
+
+use crate::ProcessOptions;
+use std::{
+    ffi::{CStr, CString},
+    io,
+    os::raw::{c_char, c_void},
+    panic, ptr, slice,
+};
+
+pub const IMGROLL_OK: i32 = 0;
+/// Processing failed, see the error message.
+pub const IMGROLL_ERROR: i32 = 1;
+/// A bug in imgroll, the panic was caught at the boundary.
+pub const IMGROLL_PANIC: i32 = 2;
+
+/// Receives every output file as soon as it's encoded, on the thread that called `imgroll_process`.
+/// The pointers are only valid during the call. Returning anything but 0 stops processing with an error.
+pub type ImgrollWriteFn = Option<
+    unsafe extern "C" fn(
+        ctx: *mut c_void,
+        name: *const c_char,
+        mimetype: *const c_char,
+        data: *const u8,
+        len: usize,
+    ) -> i32,
+>;
+
+/// Processes an image, like `process_photo_streaming`.
+///
+/// `options_json` is a `ProcessOptions` config, or null for the defaults.
+/// `write` (which may be null to skip the files) is called with `ctx` for every output file.
+/// Returns `IMGROLL_OK` and sets `*out_json` to the `Photo` JSON, or sets `*out_error` to a message.
+/// Both strings are owned by the caller, who has to release them with `imgroll_free`.
+/// `out_json` and `out_error` may be null when the caller isn't interested.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes, and `name` and `options_json` to NUL terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn imgroll_process(
+    data: *const u8,
+    len: usize,
+    name: *const c_char,
+    options_json: *const c_char,
+    write: ImgrollWriteFn,
+    ctx: *mut c_void,
+    out_json: *mut *mut c_char,
+    out_error: *mut *mut c_char,
+) -> i32 {
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        process(data, len, name, options_json, write, ctx)
+    }));
+    let (code, message) = match result {
+        Ok(Ok(json)) => {
+            if !out_json.is_null() {
+                *out_json = into_c_string(json);
+            }
+            return IMGROLL_OK;
+        },
+        Ok(Err(message)) => (IMGROLL_ERROR, message),
+        Err(_) => (IMGROLL_PANIC, "imgroll panicked".to_owned()),
+    };
+    if !out_error.is_null() {
+        *out_error = into_c_string(message);
+    }
+    code
+}
+
+/// One output file of `imgroll_process_all`.
+#[repr(C)]
+pub struct ImgrollFile {
+    pub name: *mut c_char,
+    pub mimetype: *mut c_char,
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+/// Everything `imgroll_process_all` returns, in one allocation-owning struct that's easy to use from
+/// Python's ctypes and other FFIs without callbacks.
+#[repr(C)]
+pub struct ImgrollResult {
+    /// `IMGROLL_OK`, `IMGROLL_ERROR` or `IMGROLL_PANIC`.
+    pub code: i32,
+    /// The `Photo` JSON, null unless the code is `IMGROLL_OK`.
+    pub json: *mut c_char,
+    /// The error message, null when the code is `IMGROLL_OK`.
+    pub error: *mut c_char,
+    /// `files_len` output files.
+    pub files: *mut ImgrollFile,
+    pub files_len: usize,
+}
+
+/// Processes an image, like `process_photo`, keeping all the output files in memory.
+///
+/// `options_json` is a `ProcessOptions` config, or null for the defaults.
+/// Never returns null. The result is owned by the caller, who has to release it with `imgroll_result_free`.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes, and `name` and `options_json` to NUL terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn imgroll_process_all(
+    data: *const u8,
+    len: usize,
+    name: *const c_char,
+    options_json: *const c_char,
+) -> *mut ImgrollResult {
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        let (file_contents, name, opts) = read_args(data, len, name, options_json)?;
+        let (photo, files) = crate::process_photo(file_contents, name, &opts).map_err(|e| e.to_string())?;
+        Ok((serde_json::to_string(&photo).map_err(|e| e.to_string())?, files))
+    }));
+    let mut out = ImgrollResult {
+        code: IMGROLL_OK,
+        json: ptr::null_mut(),
+        error: ptr::null_mut(),
+        files: ptr::null_mut(),
+        files_len: 0,
+    };
+    match result {
+        Ok(Ok((json, files))) => {
+            out.json = into_c_string(json);
+            let files = files
+                .into_iter()
+                .map(|file| ImgrollFile {
+                    name: into_c_string(file.name),
+                    mimetype: into_c_string(file.mimetype),
+                    len: file.bytes.len(),
+                    data: Box::into_raw(file.bytes.into_boxed_slice()) as *mut u8,
+                })
+                .collect::<Box<[_]>>();
+            out.files_len = files.len();
+            out.files = Box::into_raw(files) as *mut ImgrollFile;
+        },
+        Ok(Err(message)) => {
+            out.code = IMGROLL_ERROR;
+            out.error = into_c_string(message);
+        },
+        Err(_) => {
+            out.code = IMGROLL_PANIC;
+            out.error = into_c_string("imgroll panicked".to_owned());
+        },
+    }
+    Box::into_raw(Box::new(out))
+}
+
+/// Frees a result of `imgroll_process_all`, including all the strings and files in it. Does nothing for null.
+///
+/// # Safety
+///
+/// `result` must come from `imgroll_process_all`, unmodified, and not have been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn imgroll_result_free(result: *mut ImgrollResult) {
+    if result.is_null() {
+        return;
+    }
+    let result = Box::from_raw(result);
+    imgroll_free(result.json);
+    imgroll_free(result.error);
+    if !result.files.is_null() {
+        let files = Box::from_raw(ptr::slice_from_raw_parts_mut(result.files, result.files_len));
+        for file in files.iter() {
+            imgroll_free(file.name);
+            imgroll_free(file.mimetype);
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(file.data, file.len)));
+        }
+    }
+}
+
+/// Frees a string returned by `imgroll_process`. Does nothing for null.
+///
+/// # Safety
+///
+/// `ptr` must come from imgroll and not have been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn imgroll_free(ptr: *mut c_char) {
+    if !ptr.is_null() {
+        drop(CString::from_raw(ptr));
+    }
+}
+
+unsafe fn process(
+    data: *const u8,
+    len: usize,
+    name: *const c_char,
+    options_json: *const c_char,
+    write: ImgrollWriteFn,
+    ctx: *mut c_void,
+) -> Result<String, String> {
+    let (file_contents, name, opts) = read_args(data, len, name, options_json)?;
+    let photo = crate::process_photo_streaming(file_contents, name, &opts, &mut |meta, contents| {
+        let write = match write {
+            Some(write) => write,
+            None => return Ok(()),
+        };
+        let mut bytes = Vec::with_capacity(meta.size);
+        contents.read_to_end(&mut bytes)?;
+        let name = CString::new(meta.name)?;
+        let mimetype = CString::new(meta.mimetype)?;
+        match write(ctx, name.as_ptr(), mimetype.as_ptr(), bytes.as_ptr(), bytes.len()) {
+            0 => Ok(()),
+            code => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("write callback returned {}", code),
+            )),
+        }
+    })
+    .map_err(|e| e.to_string())?;
+    serde_json::to_string(&photo).map_err(|e| e.to_string())
+}
+
+unsafe fn read_args<'a>(
+    data: *const u8,
+    len: usize,
+    name: *const c_char,
+    options_json: *const c_char,
+) -> Result<(&'a [u8], &'a str, ProcessOptions), String> {
+    if data.is_null() || name.is_null() {
+        return Err("data and name must not be null".to_owned());
+    }
+    let file_contents = slice::from_raw_parts(data, len);
+    let name = CStr::from_ptr(name).to_str().map_err(|e| e.to_string())?;
+    let opts = if options_json.is_null() {
+        ProcessOptions::default()
+    } else {
+        let config = CStr::from_ptr(options_json).to_str().map_err(|e| e.to_string())?;
+        ProcessOptions::from_json_str(config).map_err(|e| e.to_string())?
+    };
+    Ok((file_contents, name, opts))
+}
+
+fn into_c_string(s: String) -> *mut c_char {
+    // Messages never contain NUL, but an empty string is better than a panic if one ever does
+    CString::new(s).unwrap_or_default().into_raw()
+}
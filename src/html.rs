@@ -0,0 +1,56 @@
+use crate::{Photo, Source};
+
+impl Photo {
+    /// Renders a `<picture>` element for the processed sources.
+    ///
+    /// Art direction sources (the ones with a media query) come first, since the browser picks the first match.
+    /// The fallback `<img>` uses the first JPEG or PNG source.
+    pub fn picture_html(&self, alt: &str) -> String {
+        let derived = self.source.iter().filter(|s| !s.original).collect::<Vec<_>>();
+        let fallback = derived
+            .iter()
+            .position(|s| s.media.is_none() && (s.r#type == "image/jpeg" || s.r#type == "image/png"))
+            .or_else(|| derived.iter().position(|s| s.media.is_none()));
+
+        let mut html = String::from("<picture>");
+        let (with_media, without_media): (Vec<_>, Vec<_>) =
+            derived.iter().enumerate().partition(|(_, s)| s.media.is_some());
+        for (i, src) in with_media.into_iter().chain(without_media) {
+            if Some(i) == fallback {
+                continue;
+            }
+            html.push_str(&source_html(src));
+        }
+        if let Some(src) = fallback.map(|i| derived[i]) {
+            let main = src.srcset.first().map(|e| e.src.as_str()).unwrap_or("");
+            html.push_str(&format!(
+                r#"<img alt="{}" src="{}" srcset="{}">"#,
+                escape_attr(alt),
+                escape_attr(main),
+                escape_attr(&src.srcset_string())
+            ));
+        }
+        html.push_str("</picture>");
+        html
+    }
+}
+
+fn source_html(src: &Source) -> String {
+    let media = match &src.media {
+        Some(media) => format!(r#" media="{}""#, escape_attr(media)),
+        None => String::new(),
+    };
+    format!(
+        r#"<source srcset="{}" type="{}"{}>"#,
+        escape_attr(&src.srcset_string()),
+        escape_attr(&src.r#type),
+        media
+    )
+}
+
+fn escape_attr(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,175 @@
+//! Minimal JPEG marker parsing, for the things the decoders don't tell us.
+
+const SOI: u8 = 0xD8;
+const SOS: u8 = 0xDA;
+const APP1: u8 = 0xE1;
+const APP2: u8 = 0xE2;
+const APP14: u8 = 0xEE;
+
+const MP_ENTRY: u16 = 0xB002;
+const ORIENTATION: u16 = 0x0112;
+
+const XMP_NAMESPACE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// Splits the header of a JPEG into (marker, payload) pairs, stopping at the start of scan.
+pub fn segments(bytes: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut result = Vec::new();
+    if bytes.get(0..2) != Some(&[0xFF, SOI]) {
+        return result;
+    }
+    let mut i = 2;
+    while i + 4 <= bytes.len() {
+        if bytes[i] != 0xFF {
+            break;
+        }
+        let marker = bytes[i + 1];
+        if marker == 0xFF {
+            // fill byte
+            i += 1;
+            continue;
+        }
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            i += 2;
+            continue;
+        }
+        let len = usize::from(u16::from_be_bytes([bytes[i + 2], bytes[i + 3]]));
+        if len < 2 || i + 2 + len > bytes.len() {
+            break;
+        }
+        result.push((marker, &bytes[i + 4..i + 2 + len]));
+        if marker == SOS {
+            break;
+        }
+        i += 2 + len;
+    }
+    result
+}
+
+fn is_sof(marker: u8) -> bool {
+    (0xC0..=0xCF).contains(&marker) && marker != 0xC4 && marker != 0xC8 && marker != 0xCC
+}
+
+/// Whether the frame has four components, i.e. CMYK or YCCK.
+pub fn is_cmyk(bytes: &[u8]) -> bool {
+    segments(bytes)
+        .into_iter()
+        .find(|(marker, _)| is_sof(*marker))
+        .and_then(|(_, payload)| payload.get(5).copied())
+        == Some(4)
+}
+
+pub fn has_adobe_marker(bytes: &[u8]) -> bool {
+    segments(bytes)
+        .into_iter()
+        .any(|(marker, payload)| marker == APP14 && payload.starts_with(b"Adobe"))
+}
+
+/// The EXIF data (a TIFF structure) from APP1.
+pub fn exif(bytes: &[u8]) -> Option<&[u8]> {
+    segments(bytes)
+        .into_iter()
+        .find(|(marker, payload)| *marker == APP1 && payload.starts_with(b"Exif\0\0"))
+        .map(|(_, payload)| &payload[6..])
+}
+
+/// The XMP packet from APP1, without the extended XMP that large packets continue in.
+pub fn xmp(bytes: &[u8]) -> Option<&[u8]> {
+    segments(bytes)
+        .into_iter()
+        .find(|(marker, payload)| *marker == APP1 && payload.starts_with(XMP_NAMESPACE))
+        .map(|(_, payload)| &payload[XMP_NAMESPACE.len()..])
+}
+
+/// Sets the orientation in the first IFD of EXIF data to normal, for pixels that were already rotated.
+pub fn reset_exif_orientation(tiff: &mut [u8]) -> Option<()> {
+    let (u16_at, u32_at, normal): (fn([u8; 2]) -> u16, fn([u8; 4]) -> u32, _) = match tiff.get(0..4)? {
+        b"II*\0" => (u16::from_le_bytes, u32::from_le_bytes, [1, 0]),
+        b"MM\0*" => (u16::from_be_bytes, u32::from_be_bytes, [0, 1]),
+        _ => return None,
+    };
+    let read_u16 = |at: usize| tiff.get(at..at + 2).map(|b| u16_at([b[0], b[1]]));
+    let read_u32 = |at: usize| tiff.get(at..at + 4).map(|b| u32_at([b[0], b[1], b[2], b[3]]));
+    let ifd = read_u32(4)? as usize;
+    let entry = (0..usize::from(read_u16(ifd)?))
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| read_u16(entry) == Some(ORIENTATION))?;
+    // A SHORT, which is stored in the first two bytes of the value field
+    tiff.get_mut(entry + 8..entry + 10)?.copy_from_slice(&normal);
+    Some(())
+}
+
+/// The same for XMP, where it's a `tiff:Orientation` attribute or element.
+pub fn reset_xmp_orientation(xmp: &mut [u8]) {
+    const TAG: &[u8] = b"tiff:Orientation";
+    let mut i = 0;
+    while let Some(found) = xmp[i..].windows(TAG.len()).position(|window| window == TAG) {
+        i += found + TAG.len();
+        // `="6"` or `>6<`, the closing tag is followed by neither
+        let value = match xmp.get(i..i + 2) {
+            Some([b'=', b'"']) | Some([b'=', b'\'']) => i + 2,
+            Some([b'>', _]) => i + 1,
+            _ => continue,
+        };
+        if xmp.get(value).map_or(false, |digit| (b'1'..=b'8').contains(digit)) {
+            xmp[value] = b'1';
+        }
+    }
+}
+
+/// Splits a Multi-Picture Format (MPO) file into its individual JPEGs, using the MP index in APP2.
+///
+/// Returns `None` for anything that isn't an MPO or has a broken index, callers treat it as a single image.
+pub fn mpo_images(bytes: &[u8]) -> Option<Vec<&[u8]>> {
+    let payload = segments(bytes)
+        .into_iter()
+        .find(|(marker, payload)| *marker == APP2 && payload.starts_with(b"MPF\0"))?
+        .1;
+    // Offsets of all but the first image are relative to the TIFF-style header after the identifier
+    let header = &payload[4..];
+    let base = header.as_ptr() as usize - bytes.as_ptr() as usize;
+    let (u16_at, u32_at): (fn([u8; 2]) -> u16, fn([u8; 4]) -> u32) = match header.get(0..4)? {
+        b"II*\0" => (u16::from_le_bytes, u32::from_le_bytes),
+        b"MM\0*" => (u16::from_be_bytes, u32::from_be_bytes),
+        _ => return None,
+    };
+    let read_u16 = |at: usize| header.get(at..at + 2).map(|b| u16_at([b[0], b[1]]));
+    let read_u32 = |at: usize| header.get(at..at + 4).map(|b| u32_at([b[0], b[1], b[2], b[3]]));
+
+    let ifd = read_u32(4)? as usize;
+    let (count, offset) = (0..usize::from(read_u16(ifd)?))
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| read_u16(entry) == Some(MP_ENTRY))
+        .and_then(|entry| Some((read_u32(entry + 4)? as usize, read_u32(entry + 8)? as usize)))?;
+
+    let mut images = Vec::with_capacity(count / 16);
+    for entry in (0..count / 16).map(|i| offset + i * 16) {
+        let size = read_u32(entry + 4)? as usize;
+        let start = match read_u32(entry + 8)? as usize {
+            0 => 0,
+            relative => base.checked_add(relative)?,
+        };
+        let image = bytes.get(start..start.checked_add(size)?)?;
+        if !image.starts_with(&[0xFF, SOI]) {
+            return None;
+        }
+        images.push(image);
+    }
+    if images.len() < 2 {
+        return None;
+    }
+    Some(images)
+}
+
+/// Inserts an Adobe APP14 segment (transform 0, plain CMYK) right after SOI.
+///
+/// The pure Rust decoder refuses four-component frames without it.
+/// Practically every CMYK JPEG comes from Adobe tools that write inverted CMYK, which is what this declares.
+pub fn with_adobe_marker(bytes: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(bytes.len() + 16);
+    result.extend_from_slice(&bytes[0..2]);
+    result.extend_from_slice(&[0xFF, APP14, 0x00, 0x0E]);
+    result.extend_from_slice(b"Adobe");
+    result.extend_from_slice(&[0x00, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    result.extend_from_slice(&bytes[2..]);
+    result
+}
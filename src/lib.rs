@@ -1,11 +1,45 @@
+mod animation;
+#[cfg(feature = "tokio")]
+mod async_api;
+mod exif;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod html;
+mod jpeg;
+mod metadata;
+mod options;
+mod phash;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "signature")]
+pub mod signature;
+mod watermark;
+#[cfg(feature = "webp")]
 mod webp;
 
+#[cfg(feature = "tokio")]
+pub use async_api::process_photo_async;
+pub use metadata::{Metadata, Orientation};
+pub use options::*;
+
 use snafu::{ResultExt, Snafu};
-use std::{convert::TryInto, ptr};
+#[cfg(any(feature = "png", feature = "wasm"))]
+use std::convert::TryInto;
+#[cfg(feature = "png")]
+use std::ptr;
 
+#[cfg(any(feature = "png", feature = "wasm"))]
 const PNG_QUANTIZE_COLORS: usize = 69;
+#[cfg(feature = "webp")]
 const WEBP_QUALITY: f32 = 53.0;
+#[cfg(any(feature = "jpeg", feature = "wasm"))]
 const JPEG_QUALITY: f32 = 65.0;
+#[cfg(feature = "jpeg")]
+const JPEG_SCANLINE_CHUNK: usize = 64;
+/// Everything the decoders read, not all of it necessarily has an encoder compiled in.
+const INPUT_FORMATS: &[image::ImageFormat] = &[image::ImageFormat::Jpeg, image::ImageFormat::Png];
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -18,27 +52,154 @@ pub enum Error {
     #[snafu(display("Unable to extract palette: {}", source))]
     PaletteExtract { source: color_thief::Error },
 
+    #[cfg(feature = "exiv2")]
     #[snafu(display("Unable to parse metadata: {}", source))]
     MetadataParse { source: rexiv2::Rexiv2Error },
 
+    #[cfg(feature = "exiv2")]
+    #[snafu(display("Unable to parse sidecar XMP: {}", source))]
+    SidecarParse { source: rexiv2::Rexiv2Error },
+
+    #[cfg(feature = "exiv2")]
     #[snafu(display("Unsupported file format: {}", format))]
     UnsupportedFormat { format: rexiv2::MediaType },
 
+    #[snafu(display("Unsupported image format: {:?}", format))]
+    UnsupportedImageFormat { format: image::ImageFormat },
+
+    #[snafu(display(
+        "Animated {:?} images are not supported ({} frames), only the first frame would be processed",
+        format,
+        frames
+    ))]
+    AnimatedUnsupported { format: image::ImageFormat, frames: u32 },
+
+    #[snafu(display("No {:?} encoder was compiled in, enable the corresponding cargo feature", format))]
+    NoEncoder { format: image::ImageFormat },
+
+    #[snafu(display("None of the allowed formats can be produced for {:?} images", format))]
+    NoAllowedFormat { format: image::ImageFormat },
+
+    #[snafu(display("Unable to read {}: {}", path.display(), source))]
+    ReadInput {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Unable to write output file: {}", source))]
+    Sink { source: std::io::Error },
+
+    #[snafu(display("Invalid config at `{}`: {}", field, message))]
+    Config { field: String, message: String },
+
+    #[snafu(display("Unable to read config {}: {}", path.display(), source))]
+    ConfigRead {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Processing was cancelled"))]
+    Cancelled,
+
+    #[snafu(display(
+        "Rated {} which is below the minimum of {}",
+        rating.map_or_else(|| "nothing".to_owned(), |r| r.to_string()),
+        min_rating
+    ))]
+    RatingTooLow { rating: Option<i32>, min_rating: i32 },
+
+    #[snafu(display("The image is {}x{}, more than the maximum of {} pixels", width, height, max_pixels))]
+    TooManyPixels { width: u32, height: u32, max_pixels: u64 },
+
+    #[snafu(display("Could not start encoder threads: {}", source))]
+    ThreadPool { source: rayon::ThreadPoolBuildError },
+
+    #[cfg(feature = "webp")]
     #[snafu(display("Could not encode webp: {}", source))]
     WebpEncode { source: webp::Error },
 
+    #[cfg(feature = "webp")]
+    #[snafu(display("Could not decode webp: {}", source))]
+    WebpDecode { source: webp::Error },
+
+    #[cfg(any(feature = "png", feature = "wasm"))]
     #[snafu(display("Could not encode png: {}", source))]
     PngEncode { source: lodepng::Error },
 
+    #[cfg(any(feature = "jpeg", feature = "wasm"))]
     #[snafu(display("Could not encode jpeg"))]
     JpegEncode {},
 
+    #[snafu(display("Invalid perceptual hash: {}", source))]
+    PhashParse { source: std::num::ParseIntError },
+
     #[snafu(display("Could not fit size value into type: {}", source))]
     ConvertInt { source: std::num::TryFromIntError },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+impl Error {
+    /// The variant's name in snake_case, e.g. for error reports to clients, which shouldn't parse the messages.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::ImageProc { .. } => "image_proc",
+            Error::UnsupportedColor { .. } => "unsupported_color",
+            Error::PaletteExtract { .. } => "palette_extract",
+            #[cfg(feature = "exiv2")]
+            Error::MetadataParse { .. } => "metadata_parse",
+            #[cfg(feature = "exiv2")]
+            Error::SidecarParse { .. } => "sidecar_parse",
+            #[cfg(feature = "exiv2")]
+            Error::UnsupportedFormat { .. } => "unsupported_format",
+            Error::UnsupportedImageFormat { .. } => "unsupported_image_format",
+            Error::AnimatedUnsupported { .. } => "animated_unsupported",
+            Error::NoEncoder { .. } => "no_encoder",
+            Error::NoAllowedFormat { .. } => "no_allowed_format",
+            Error::ReadInput { .. } => "read_input",
+            Error::Sink { .. } => "sink",
+            Error::Config { .. } => "config",
+            Error::ConfigRead { .. } => "config_read",
+            Error::Cancelled => "cancelled",
+            Error::RatingTooLow { .. } => "rating_too_low",
+            Error::TooManyPixels { .. } => "too_many_pixels",
+            Error::ThreadPool { .. } => "thread_pool",
+            #[cfg(feature = "webp")]
+            Error::WebpEncode { .. } => "webp_encode",
+            #[cfg(feature = "webp")]
+            Error::WebpDecode { .. } => "webp_decode",
+            #[cfg(any(feature = "png", feature = "wasm"))]
+            Error::PngEncode { .. } => "png_encode",
+            #[cfg(any(feature = "jpeg", feature = "wasm"))]
+            Error::JpegEncode {} => "jpeg_encode",
+            Error::PhashParse { .. } => "phash_parse",
+            Error::ConvertInt { .. } => "convert_int",
+        }
+    }
+
+    /// Whether it's the input itself that can't be processed (unsupported, corrupt, rejected by the options),
+    /// so that trying again is pointless. Not for problems with the setup, like missing encoders or a bad config,
+    /// which can be fixed before retrying, or for I/O errors and cancellation.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            // Everything is decoded from memory, so even its I/O errors mean a truncated file
+            Error::ImageProc { .. }
+            | Error::UnsupportedColor { .. }
+            | Error::PaletteExtract { .. }
+            | Error::UnsupportedImageFormat { .. }
+            | Error::AnimatedUnsupported { .. }
+            | Error::RatingTooLow { .. }
+            | Error::TooManyPixels { .. }
+            | Error::PhashParse { .. } => true,
+            #[cfg(feature = "exiv2")]
+            Error::MetadataParse { .. } | Error::SidecarParse { .. } | Error::UnsupportedFormat { .. } => true,
+            #[cfg(feature = "webp")]
+            Error::WebpDecode { .. } => true,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct GeoLocation {
     pub longitude: f64,
@@ -46,10 +207,19 @@ pub struct GeoLocation {
     pub altitude: f64,
 }
 
+/// A point in normalized (0.0–1.0) coordinates of the oriented image.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FocalPoint {
+    pub x: f32,
+    pub y: f32,
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct SrcSetEntry {
     pub src: String,
     pub width: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub density: Option<f32>,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -57,20 +227,127 @@ pub struct Source {
     pub original: bool,
     pub srcset: Vec<SrcSetEntry>,
     pub r#type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media: Option<String>,
+}
+
+impl Source {
+    /// Formats the srcset for use in an HTML `srcset` attribute.
+    pub fn srcset_string(&self) -> String {
+        self.srcset
+            .iter()
+            .map(|entry| match entry.density {
+                Some(density) => format!("{} {}x", entry.src, density),
+                None => format!("{} {}w", entry.src, entry.width),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
 }
 
+/// Goes up whenever the fields of `Photo` (or the types inside it) change.
+pub const PHOTO_SCHEMA_VERSION: u32 = 3;
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Photo {
-    pub tiny_preview: String,
+    /// `PHOTO_SCHEMA_VERSION` of the imgroll that made it, 0 for JSON from before versioning.
+    #[serde(default)]
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tiny_preview: Option<String>,
+    /// File name (or URL, in the lambda) of the tiny preview, when it's not inlined.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tiny_preview_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tiny_preview_info: Option<TinyPreviewInfo>,
     pub source: Vec<Source>,
+    /// Dimensions of the original, which may be larger than anything in the non-original sources.
     pub height: u32,
     pub width: u32,
-    pub palette: Vec<rgb::RGB8>,
+    /// Dimensions of the largest derived rendition (the main image is capped at `ProcessOptions::max_dimension`).
+    #[serde(default)]
+    pub served_height: u32,
+    #[serde(default)]
+    pub served_width: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub palette: Option<Vec<rgb::RGB8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub palette_hex: Option<Vec<String>>,
     pub geo: Option<GeoLocation>,
     pub aperture: Option<f64>,
     pub shutter_speed: Option<num_rational::Ratio<i32>>,
     pub focal_length: Option<f64>,
     pub iso: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exposure_program: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metering_mode: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flash: Option<String>,
+    /// In meters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject_distance: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub white_balance: Option<String>,
+    /// The raw EXIF orientation (1–8) as read from the metadata, whether or not it was applied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_orientation: Option<u8>,
+    /// Stars (0–5, -1 for rejected) from `Xmp.xmp.Rating`, or the `Exif.Image.Rating` some cameras set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rating: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focal_point: Option<FocalPoint>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub social_preview: Option<SrcSetEntry>,
+    #[serde(default)]
+    pub monochrome: bool,
+    /// Perceptual hash, compare with `phash_distance`.
+    #[serde(default)]
+    pub phash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Vec<EncodeTiming>>,
+    /// What the output file names start with (the hash and the slug), for naming files that go along with them.
+    /// Not part of the JSON, and empty from `inspect_photo`.
+    #[serde(skip)]
+    pub file_prefix: String,
+}
+
+impl Photo {
+    /// Rewrites every output file name (sources, social preview, tiny preview file), e.g. into public URLs.
+    pub fn map_urls(&mut self, mut f: impl FnMut(&str) -> String) {
+        for entry in self.source.iter_mut().flat_map(|src| src.srcset.iter_mut()) {
+            entry.src = f(&entry.src);
+        }
+        if let Some(social) = &mut self.social_preview {
+            social.src = f(&social.src);
+        }
+        if let Some(tiny) = &mut self.tiny_preview_url {
+            *tiny = f(tiny);
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct EncodeTiming {
+    pub encoder: String,
+    pub width: u32,
+    pub duration: std::time::Duration,
+}
+
+/// What the tiny preview WebP is like, whether it's inlined or a file, for checking the placeholders.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TinyPreviewInfo {
+    pub width: u32,
+    pub height: u32,
+    pub bytes: usize,
+}
+
+/// Everything about an output file except the contents, see `process_photo_streaming`.
+#[derive(Debug, Clone)]
+pub struct OutFileMeta {
+    pub name: String,
+    pub mimetype: String,
+    pub size: usize,
 }
 
 pub struct OutFile {
@@ -79,167 +356,958 @@ pub struct OutFile {
     pub mimetype: String,
 }
 
-pub fn process_photo(file_contents: &[u8], file_name: &str) -> Result<(Photo, Vec<OutFile>)> {
+/// Decodes the image and applies its orientation, exactly like `process_photo` does before processing.
+///
+/// Useful for doing custom analysis and feeding the result to your own encoders.
+/// The metadata is `None` when it could not be parsed (or the `exiv2` feature is off) but the image itself decoded fine.
+pub fn decode_and_orient(
+    file_contents: &[u8],
+    opts: &ProcessOptions,
+) -> Result<(image::DynamicImage, Option<Box<dyn Metadata>>)> {
+    let Decoded { imag, meta, .. } = decode_oriented(file_contents, opts)?;
+    Ok((imag, meta))
+}
+
+/// The EXIF thumbnail that cameras embed into their JPEGs, still encoded (usually a small JPEG) and not rotated
+/// by the orientation tag, e.g. for showing something right away. `None` when there isn't one, or without the
+/// `exiv2` feature. It's not checked against the image, see `quick_tiny_preview` for that.
+pub fn embedded_thumbnail(file_contents: &[u8]) -> Option<Vec<u8>> {
+    metadata::read(file_contents)?.thumbnail().map(<[u8]>::to_vec)
+}
+
+/// Decodes an output file (JPEG, PNG, or WebP with the `webp` feature), giving the MIME type its magic bytes
+/// say it is along with the pixels. For checking the outputs, no orientation or color conversion is applied.
+pub fn decode_output_file(file_contents: &[u8]) -> Result<(&'static str, image::DynamicImage)> {
+    let format = image::guess_format(file_contents).context(ImageProc {})?;
+    match format {
+        #[cfg(feature = "webp")]
+        image::ImageFormat::WebP => Ok((
+            "image/webp",
+            image::DynamicImage::ImageRgba8(webp::decode(file_contents).context(WebpDecode {})?),
+        )),
+        _ => Ok((
+            format_mime(format)?,
+            reader(file_contents, format).decode().context(ImageProc {})?,
+        )),
+    }
+}
+
+struct Decoded {
+    imag: image::DynamicImage,
+    meta: Option<Box<dyn Metadata>>,
+    format: image::ImageFormat,
+    orientation: Orientation,
+    focal_point: Option<FocalPoint>,
+    embedded: Option<Embedded>,
+}
+
+/// The metadata to copy into the WebPs, see `ProcessOptions::webp_metadata`.
+#[cfg_attr(not(feature = "webp"), allow(dead_code))]
+struct Embedded {
+    exif: Option<Vec<u8>>,
+    xmp: Option<Vec<u8>>,
+}
+
+fn decode_oriented(file_contents: &[u8], opts: &ProcessOptions) -> Result<Decoded> {
     use image::GenericImageView;
-    let meta = rexiv2::Metadata::new_from_buffer(&file_contents).context(MetadataParse {})?;
-    let exivfmt = meta.get_media_type().context(MetadataParse {})?;
-    let imag = orient_image(
-        image::load_from_memory_with_format(&file_contents, format_exiv2image(&exivfmt)?).context(ImageProc {})?,
-        meta.get_orientation(),
-    );
-    let samp = samples(&imag)?;
-    let palette = color_thief::get_palette(samp.as_slice(), colortype_image2thief(imag.color())?, 10, 10)
-        .context(PaletteExtract {})?;
-    let (width, height) = imag.dimensions();
+    report(opts, Progress::Decoding);
+    let magic_format = image::guess_format(file_contents).ok();
+    if let Some(format) = magic_format {
+        if let Some(frames) = animation::frame_count(file_contents, format) {
+            return Err(Error::AnimatedUnsupported { format, frames });
+        }
+    }
+    let meta = metadata::read(file_contents);
+    // The magic bytes decide, the metadata reader's idea of the format is only a fallback
+    let format = match (
+        magic_format,
+        meta.as_ref().map(|m| m.image_format()).transpose()?.flatten(),
+    ) {
+        (Some(format), Some(meta_format)) if format != meta_format => {
+            log::warn!("The metadata says {:?}, but the contents are {:?}", meta_format, format);
+            format
+        },
+        (Some(format), _) | (None, Some(format)) => format,
+        (None, None) => image::guess_format(&file_contents).context(ImageProc {})?,
+    };
+    if let (Some(meta), Some(xmp)) = (&meta, &opts.xmp_sidecar) {
+        meta.merge_sidecar(xmp)?;
+    }
+    // Before decoding, rejecting is the cheap part
+    if let Some(min_rating) = opts.min_rating {
+        let rating = meta.as_deref().and_then(exif::rating);
+        if rating.map_or(true, |r| r < min_rating) {
+            return Err(Error::RatingTooLow { rating, min_rating });
+        }
+    }
+    let decoded = decode_image(&file_contents, format, opts.max_pixels)?;
+    let orientation = match (opts.apply_orientation, opts.orientation_override, &meta) {
+        (false, _, _) | (true, None, None) => Orientation::Normal,
+        (true, Some(ori), _) => ori,
+        (true, None, Some(meta)) => meta.orientation(),
+    };
+    let focal_point = opts.focal_point.or_else(|| {
+        meta.as_deref()
+            .and_then(|meta| subject_area(meta, decoded.dimensions()))
+            .map(|point| orient_point(point, orientation))
+    });
+    let embedded = if opts.webp_metadata && format == image::ImageFormat::Jpeg {
+        embedded_metadata(file_contents, orientation)
+    } else {
+        None
+    };
+    Ok(Decoded {
+        imag: orient_image(decoded, orientation),
+        meta,
+        format,
+        orientation,
+        focal_point,
+        embedded,
+    })
+}
 
-    let file_prefix = format!(
-        "{}_{}",
-        {
-            use tiny_keccak::Hasher;
-            let mut hasher = tiny_keccak::ParallelHash::v128(&[], 8192);
-            hasher.update(&samp.as_slice());
-            let mut buf = [0u8; 16];
-            hasher.finalize(&mut buf);
-            hex::encode(&buf[0..6])
+/// The EXIF and XMP segments of a JPEG, saying that it's upright when the orientation is applied to the pixels.
+fn embedded_metadata(file_contents: &[u8], orientation: Orientation) -> Option<Embedded> {
+    let mut exif = jpeg::exif(file_contents).map(<[u8]>::to_vec);
+    let mut xmp = jpeg::xmp(file_contents).map(<[u8]>::to_vec);
+    if exif.is_none() && xmp.is_none() {
+        return None;
+    }
+    if orientation != Orientation::Normal {
+        if let Some(exif) = &mut exif {
+            jpeg::reset_exif_orientation(exif);
+        }
+        if let Some(xmp) = &mut xmp {
+            jpeg::reset_xmp_orientation(xmp);
+        }
+    }
+    Some(Embedded { exif, xmp })
+}
+
+/// Extracts the `Photo` metadata without encoding any renditions, e.g. for re-indexing already processed files.
+///
+/// Uses the default options, so `source` only lists the original.
+pub fn inspect_photo(file_contents: &[u8], file_name: &str) -> Result<Photo> {
+    let opts = ProcessOptions::default();
+    let decoded = decode_oriented(file_contents, &opts)?;
+    let (mut photo, _) = describe_photo(&decoded, &decoded.imag, &opts)?;
+    photo
+        .source
+        .push(original_source(file_name, photo.width, decoded.format)?);
+    Ok(photo)
+}
+
+pub fn process_photo(file_contents: &[u8], file_name: &str, opts: &ProcessOptions) -> Result<(Photo, Vec<OutFile>)> {
+    collect_files(|out| {
+        process_decoded(
+            decode_oriented(file_contents, opts)?,
+            Some(file_contents),
+            file_name,
+            opts,
+            out,
+        )
+    })
+}
+
+/// Processes an image that's already decoded and oriented, e.g. a generated one, without encoding it into a file
+/// for `process_photo` to decode again.
+///
+/// There's no metadata, so the EXIF fields are `None`, and no original file, so `include_original`
+/// and `include_original_file` don't apply. Images with an alpha channel are processed like PNGs with
+/// `reencode_lossless` (there's nothing to keep losslessly), the rest like JPEGs.
+pub fn process_image(
+    imag: image::DynamicImage,
+    file_name: &str,
+    opts: &ProcessOptions,
+) -> Result<(Photo, Vec<OutFile>)> {
+    let opts = ProcessOptions {
+        include_original: false,
+        include_original_file: false,
+        reencode_lossless: true,
+        ..opts.clone()
+    };
+    let format = if imag.color().has_alpha() {
+        image::ImageFormat::Png
+    } else {
+        image::ImageFormat::Jpeg
+    };
+    let decoded = Decoded {
+        imag: normalize_color(imag),
+        meta: None,
+        format,
+        orientation: Orientation::Normal,
+        focal_point: opts.focal_point,
+        embedded: None,
+    };
+    collect_files(|out| process_decoded(decoded, None, file_name, &opts, out))
+}
+
+/// Like `process_photo`, but processes every image of a multi-image container (MPO, as written by
+/// stereo cameras and some phones) as a separate photo. Any other file gives a single result.
+pub fn process_multi_photo(
+    file_contents: &[u8],
+    file_name: &str,
+    opts: &ProcessOptions,
+) -> Result<Vec<(Photo, Vec<OutFile>)>> {
+    match jpeg::mpo_images(file_contents) {
+        Some(images) => images
+            .into_iter()
+            .map(|image| process_photo(image, file_name, opts))
+            .collect(),
+        None => Ok(vec![process_photo(file_contents, file_name, opts)?]),
+    }
+}
+
+/// Like `process_photo`, but frees the file contents right after decoding, lowering peak memory use.
+pub fn process_photo_owned(
+    file_contents: Vec<u8>,
+    file_name: &str,
+    opts: &ProcessOptions,
+) -> Result<(Photo, Vec<OutFile>)> {
+    let decoded = decode_oriented(&file_contents, opts)?;
+    // Unless the original is an output too
+    let original = if opts.include_original_file {
+        Some(file_contents)
+    } else {
+        drop(file_contents);
+        None
+    };
+    collect_files(|out| process_decoded(decoded, original.as_deref(), file_name, opts, out))
+}
+
+/// Reads an image file for the `process_photo*` functions. The returned options are `opts` plus the `.xmp` sidecar
+/// next to the file, unless `opts` already has a sidecar.
+pub fn read_photo_file(path: impl AsRef<std::path::Path>, opts: &ProcessOptions) -> Result<(Vec<u8>, ProcessOptions)> {
+    let path = path.as_ref();
+    let file_contents = std::fs::read(path).context(ReadInput { path })?;
+    let sidecar = path.with_extension("xmp");
+    let xmp_sidecar = match &opts.xmp_sidecar {
+        None if sidecar.is_file() => Some(std::fs::read(&sidecar).context(ReadInput { path: sidecar })?),
+        xmp => xmp.clone(),
+    };
+    Ok((
+        file_contents,
+        ProcessOptions {
+            xmp_sidecar,
+            ..opts.clone()
         },
-        slug::slugify(basename(&file_name))
-    );
+    ))
+}
+
+/// Like `process_photo_owned`, reading the file (see `read_photo_file`) and naming the outputs after its file name.
+pub fn process_photo_path(path: impl AsRef<std::path::Path>, opts: &ProcessOptions) -> Result<(Photo, Vec<OutFile>)> {
+    let path = path.as_ref();
+    let (file_contents, opts) = read_photo_file(path, opts)?;
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    process_photo_owned(file_contents, &file_name, &opts)
+}
+
+/// Like `process_photo`, but hands each output file to `sink` as soon as it's encoded,
+/// instead of keeping all of them in memory.
+///
+/// `sink` is called on the calling thread, while the processing runs on another one.
+/// The encoders wait for it, so there's only one finished file in memory at a time.
+pub fn process_photo_streaming(
+    file_contents: &[u8],
+    file_name: &str,
+    opts: &ProcessOptions,
+    sink: &mut dyn FnMut(OutFileMeta, &mut dyn std::io::Read) -> std::io::Result<()>,
+) -> Result<Photo> {
+    let (sender, receiver) = std::sync::mpsc::sync_channel::<OutFile>(0);
+    std::thread::scope(|scope| {
+        let worker = scope.spawn(move || {
+            let sender = std::sync::Mutex::new(sender);
+            let out = |file: OutFile| sender.lock().unwrap().send(file).map_err(|_| Error::Cancelled);
+            process_decoded(
+                decode_oriented(file_contents, opts)?,
+                Some(file_contents),
+                file_name,
+                opts,
+                &out,
+            )
+        });
+        let mut written = Ok(());
+        // Stopping early drops the receiver, which makes the worker stop too
+        for OutFile { name, bytes, mimetype } in receiver {
+            let meta = OutFileMeta {
+                name,
+                mimetype,
+                size: bytes.len(),
+            };
+            written = sink(meta, &mut bytes.as_slice());
+            if written.is_err() {
+                break;
+            }
+        }
+        let photo = worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        written.context(Sink {})?;
+        photo
+    })
+}
+
+type FileSink<'a> = &'a (dyn Fn(OutFile) -> Result<()> + Sync);
+
+fn collect_files(process: impl FnOnce(FileSink) -> Result<Photo>) -> Result<(Photo, Vec<OutFile>)> {
+    let files = std::sync::Mutex::new(vec![]);
+    let photo = process(&|file| {
+        files.lock().unwrap().push(file);
+        Ok(())
+    })?;
+    let mut files = files.into_inner().unwrap();
+    // Parallel encoders finish in whatever order
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok((photo, files))
+}
+
+/// `original` is the file the image was decoded from, `None` when it's only needed for `include_original_file`
+/// and that's off.
+fn process_decoded(
+    mut decoded: Decoded,
+    original: Option<&[u8]>,
+    file_name: &str,
+    opts: &ProcessOptions,
+    sink: FileSink,
+) -> Result<Photo> {
+    use image::GenericImageView;
+    check_cancelled(opts)?;
+    let (width, height) = decoded.imag.dimensions();
+    let format = decoded.format;
+    let focal_point = decoded.focal_point;
+    let embedded = decoded.embedded.take();
+
+    let file_prefix = file_prefix(short_hash(decoded.imag.as_bytes()), file_name, opts);
+    // Only logged, the outputs and the original's type in the sources go by the contents
+    if let Ok(declared) = image::ImageFormat::from_path(file_name) {
+        if declared != format {
+            log::warn!("'{}' is named like {:?}, but it's {:?}", file_name, declared, format);
+        }
+    }
 
-    let lossless = format_is_lossless(&exivfmt);
+    let lossless = format_is_lossless(format) && !opts.reencode_lossless;
+
+    let seen = std::sync::Mutex::new(std::collections::HashSet::new());
+    let out = |file: OutFile| {
+        // Identical renditions have the same name when content addressed, only write them once
+        if opts.content_addressed && !seen.lock().unwrap().insert(file.name.clone()) {
+            return Ok(());
+        }
+        sink(file)
+    };
 
     // Always constrain the size of the main processed image
-    let (imag, main_width) = if !lossless && (width > 3000 || height > 3000) {
-        let i = imag.resize(3000, 3000, image::imageops::FilterType::Lanczos3);
-        let w = i.width();
-        (i, w)
+    let max_dimension = opts.max_dimension;
+    let capped = if !lossless && (width > max_dimension || height > max_dimension) {
+        check_cancelled(opts)?;
+        Some(
+            decoded
+                .imag
+                .resize(max_dimension, max_dimension, image::imageops::FilterType::Lanczos3),
+        )
     } else {
-        (imag, width)
+        None
     };
 
-    use rayon::prelude::*;
-    let (mut source, files): (Vec<_>, Vec<_>) = encoders_for_format(&exivfmt)?
-        .par_iter()
-        .map(|encoder| {
-            let main_result = encoder(&imag)?;
-            let main_filename = format!("{}.{}.{}", file_prefix, main_width, main_result.file_ext);
-            let mut files = vec![];
-            files.push(OutFile {
-                name: main_filename.clone(),
-                bytes: main_result.bytes,
-                mimetype: main_result.mime_type.to_owned(),
-            });
-            let mut srcset = vec![SrcSetEntry {
-                src: main_filename,
-                width: main_width,
-            }];
-
-            let mimetype = main_result.mime_type.to_owned();
-            let mut make_thumbnail = |size| {
-                let thumb = imag.resize(size, size, image::imageops::FilterType::Lanczos3);
-                let result = encoder(&thumb)?;
-                let filename = format!("{}.{}.{}", file_prefix, thumb.width(), result.file_ext);
-                files.push(OutFile {
+    check_cancelled(opts)?;
+    let (photo, tiny_preview_webp) = describe_photo(&decoded, capped.as_ref().unwrap_or(&decoded.imag), opts)?;
+    // Everything else works from the capped image, the full size one can go
+    let imag = &capped.unwrap_or(decoded.imag);
+    let main_width = imag.width();
+    let params = EncodeParams {
+        monochrome: photo.monochrome,
+        webp_hint: opts.webp_image_hint,
+        quality: None,
+        jpeg_subsampling: opts.jpeg_subsampling,
+        jpeg_quality: opts.jpeg_quality,
+        webp_quality: opts.webp_quality,
+        webp_lossless: opts
+            .webp_lossless_max_colors
+            .map_or(false, |max| has_few_colors(imag, max)),
+    };
+    // Qualities by width go in place of the fixed ones
+    let params_for_width = |width: u32| match opts
+        .quality_by_width
+        .as_deref()
+        .and_then(|table| quality_for_width(table, width))
+    {
+        Some(quality) => EncodeParams {
+            jpeg_quality: Some(quality),
+            webp_quality: Some(quality),
+            ..params
+        },
+        None => params,
+    };
+
+    // Art direction crops are shared between encoders
+    let crops = opts
+        .crops
+        .iter()
+        .map(|spec| (spec, crop_to_aspect(imag, spec.aspect, spec.gravity, focal_point)))
+        .collect::<Vec<_>>();
+
+    let encoders = encoders_for_format(format, lossless, opts.formats.as_deref())?;
+    let thumbnail_boxes = if lossless {
+        vec![]
+    } else {
+        thumbnail_boxes(opts.widths.as_deref(), width, imag.dimensions())
+    };
+    // For progress reporting, this mirrors what `render` does below
+    let per_encoder = (opts.stages.main_reencode as usize)
+        + if opts.stages.thumbnails {
+            match opts.descriptor_mode {
+                DescriptorMode::Width => thumbnail_boxes.len(),
+                DescriptorMode::Density {
+                    base_width,
+                    max_density,
+                } => (2..=max_density)
+                    .take_while(|&factor| base_width.saturating_mul(factor) <= main_width)
+                    .count(),
+            }
+        } else {
+            0
+        }
+        + crops
+            .iter()
+            .map(|(spec, cropped)| crop_widths(spec, cropped).len())
+            .sum::<usize>();
+    let total = encoders.len() * per_encoder + (opts.social_preview.is_some() as usize);
+    let completed = std::sync::atomic::AtomicUsize::new(0);
+    let report_encoded = |result: &EncodedImg, width: u32| {
+        report(
+            opts,
+            Progress::Encoded {
+                format: result.file_ext,
+                width,
+                completed: completed.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1,
+                total,
+            },
+        )
+    };
+
+    let thumbnail_filter = opts.thumbnail_filter.into();
+    let render = |encoder: &Encoder| -> Result<(Vec<Source>, Vec<EncodeTiming>)> {
+        let mut timings = vec![];
+        let mut emit =
+            |imag: &image::DynamicImage, tag: &str, density: Option<f32>| -> Result<(SrcSetEntry, &'static str)> {
+                let marked;
+                let imag = match &opts.watermark {
+                    Some(wm) => {
+                        marked = watermark::apply(imag, wm);
+                        &marked
+                    },
+                    None => imag,
+                };
+                check_cancelled(opts)?;
+                let params = params_for_width(imag.width());
+                let mut result = match &opts.size_budget {
+                    Some(budget) => encode_within_budget(encoder, imag, &params, budget, &mut timings)?,
+                    None => encode_timed(encoder, imag, &params, &mut timings)?,
+                };
+                if let Some(embedded) = &embedded {
+                    embed_metadata(&mut result, imag.dimensions(), embedded);
+                }
+                report_encoded(&result, imag.width());
+                let filename = if opts.content_addressed {
+                    format!("{}.{}", short_hash(&result.bytes), result.file_ext)
+                } else {
+                    format!("{}{}.{}.{}", file_prefix, tag, imag.width(), result.file_ext)
+                };
+                out(OutFile {
                     name: filename.clone(),
                     bytes: result.bytes,
-                    mimetype: mimetype.clone(),
-                });
-                srcset.push(SrcSetEntry {
-                    src: filename,
-                    width: thumb.width(),
-                });
-                Ok(())
+                    mimetype: result.mime_type.to_owned(),
+                })?;
+                Ok((
+                    SrcSetEntry {
+                        src: filename,
+                        width: imag.width(),
+                        density,
+                    },
+                    result.mime_type,
+                ))
             };
 
-            if !lossless && width > 2500 {
-                make_thumbnail(2000)?;
+        let mut sources = vec![];
+        let mut rendered = vec![];
+        if opts.stages.main_reencode {
+            check_cancelled(opts)?;
+            rendered.push(match opts.descriptor_mode {
+                DescriptorMode::Width => emit(imag, "", None)?,
+                // Never upscale: 1x falls back to the main image, higher densities are dropped
+                DescriptorMode::Density { base_width, .. } if base_width >= main_width => emit(imag, "", Some(1.0))?,
+                DescriptorMode::Density { base_width, .. } => emit(
+                    &imag.resize(base_width, u32::MAX, image::imageops::FilterType::Lanczos3),
+                    "",
+                    Some(1.0),
+                )?,
+            });
+        }
+        if opts.stages.thumbnails {
+            match opts.descriptor_mode {
+                DescriptorMode::Width => {
+                    for &(nwidth, nheight) in &thumbnail_boxes {
+                        check_cancelled(opts)?;
+                        let thumb = imag.resize(nwidth, nheight, thumbnail_filter);
+                        rendered.push(emit(&thumb, "", None)?);
+                    }
+                },
+                DescriptorMode::Density {
+                    base_width,
+                    max_density,
+                } => {
+                    for factor in 2..=max_density {
+                        let w = base_width.saturating_mul(factor);
+                        if w > main_width {
+                            break;
+                        }
+                        check_cancelled(opts)?;
+                        let variant = imag.resize(w, u32::MAX, thumbnail_filter);
+                        rendered.push(emit(&variant, "", Some(factor as f32))?);
+                    }
+                },
             }
+        }
+        if let Some(&(_, mime_type)) = rendered.first() {
+            sources.push(Source {
+                original: false,
+                srcset: rendered.into_iter().map(|(entry, _)| entry).collect(),
+                r#type: mime_type.to_owned(),
+                media: None,
+            });
+        }
 
-            if !lossless && width > 1500 {
-                make_thumbnail(1000)?;
+        for (spec, cropped) in &crops {
+            let tag = format!(".{}x{}", spec.aspect.0, spec.aspect.1);
+            let widths = crop_widths(spec, cropped);
+            let mut srcset = vec![];
+            let mut mime_type = None;
+            for w in widths {
+                check_cancelled(opts)?;
+                let variant = cropped.resize(w, u32::MAX, thumbnail_filter);
+                let (entry, mt) = emit(&variant, &tag, None)?;
+                srcset.push(entry);
+                mime_type = Some(mt);
             }
-
-            Ok((
-                Source {
+            if let Some(mime_type) = mime_type {
+                sources.push(Source {
                     original: false,
                     srcset,
-                    r#type: main_result.mime_type.to_owned(),
-                },
-                files,
-            ))
-        })
-        .collect::<Result<Vec<_>, _>>()?
-        .into_iter()
-        .unzip();
+                    r#type: mime_type.to_owned(),
+                    media: spec.media.clone(),
+                });
+            }
+        }
+
+        Ok((sources, timings))
+    };
+
+    use rayon::prelude::*;
+    let results = match &opts.parallelism {
+        Parallelism::Sequential => encoders.iter().map(render).collect::<Result<Vec<_>>>()?,
+        Parallelism::Global => encoders.par_iter().map(render).collect::<Result<Vec<_>>>()?,
+        Parallelism::Threads(n) => rayon::ThreadPoolBuilder::new()
+            .num_threads(*n)
+            .build()
+            .context(ThreadPool {})?
+            .install(|| encoders.par_iter().map(render).collect::<Result<Vec<_>>>())?,
+        Parallelism::Pool(pool) => pool.install(|| encoders.par_iter().map(render).collect::<Result<Vec<_>>>())?,
+    };
+
+    let mut source = vec![];
+    let mut timings = vec![];
+    for (srcs, ts) in results {
+        source.extend(srcs);
+        timings.extend(ts);
+    }
+
+    let social_preview = match &opts.social_preview {
+        Some(spec) => {
+            check_cancelled(opts)?;
+            let mut preview = crop_to_aspect(imag, (spec.width, spec.height), Gravity::Subject, focal_point)
+                .resize_exact(spec.width, spec.height, image::imageops::FilterType::Lanczos3);
+            if let Some(wm) = &opts.watermark {
+                preview = watermark::apply(&preview, wm);
+            }
+            // Most crawlers still don't take webp, it's only used when there's no jpeg encoder
+            let encoders: &[(OutputFormat, Encoder)] = &[
+                #[cfg(any(feature = "jpeg", feature = "wasm"))]
+                (OutputFormat::Jpeg, encode_jpeg),
+                #[cfg(feature = "webp")]
+                (OutputFormat::Webp, encode_webp),
+            ];
+            if encoders.is_empty() {
+                return Err(Error::NoEncoder {
+                    format: image::ImageFormat::Jpeg,
+                });
+            }
+            let (_, encoder) = encoders
+                .iter()
+                .find(|(fmt, _)| opts.formats.as_ref().map_or(true, |formats| formats.contains(fmt)))
+                .ok_or(Error::NoAllowedFormat {
+                    format: image::ImageFormat::Jpeg,
+                })?;
+            let result = encode_timed(encoder, &preview, &params_for_width(spec.width), &mut timings)?;
+            report_encoded(&result, spec.width);
+            let name = if opts.content_addressed {
+                format!("{}.{}", short_hash(&result.bytes), result.file_ext)
+            } else {
+                format!("{}.og.{}x{}.{}", file_prefix, spec.width, spec.height, result.file_ext)
+            };
+            out(OutFile {
+                name: name.clone(),
+                bytes: result.bytes,
+                mimetype: result.mime_type.to_owned(),
+            })?;
+            Some(SrcSetEntry {
+                src: name,
+                width: spec.width,
+                density: None,
+            })
+        },
+        None => None,
+    };
+
+    let served_width = source
+        .iter()
+        .filter(|s| s.media.is_none())
+        .flat_map(|s| s.srcset.iter().map(|e| e.width))
+        .max()
+        .unwrap_or(main_width);
+    let served_height = if served_width == imag.width() {
+        imag.height()
+    } else {
+        (u64::from(imag.height()) * u64::from(served_width) / u64::from(imag.width())) as u32
+    };
+
+    let tiny_preview_url = match tiny_preview_webp {
+        Some(bytes) => {
+            let name = if opts.content_addressed {
+                format!("{}.webp", short_hash(&bytes))
+            } else {
+                format!("{}.tiny.webp", file_prefix)
+            };
+            out(OutFile {
+                name: name.clone(),
+                bytes,
+                mimetype: "image/webp".to_owned(),
+            })?;
+            Some(name)
+        },
+        None => None,
+    };
+
+    if opts.include_original && opts.stages.original_source {
+        source.push(original_source(file_name, width, format)?);
+    }
+    if let (true, Some(bytes)) = (opts.include_original_file, original) {
+        out(OutFile {
+            name: file_name.to_owned(),
+            bytes: bytes.to_vec(),
+            mimetype: format_mime(format)?.to_owned(),
+        })?;
+    }
+
+    report(opts, Progress::Done);
+
+    Ok(Photo {
+        tiny_preview_url,
+        source,
+        served_width,
+        served_height,
+        social_preview,
+        timings: if opts.collect_timings { Some(timings) } else { None },
+        file_prefix,
+        ..photo
+    })
+}
+
+/// Everything in `Photo` that doesn't depend on the encoded renditions,
+/// plus the tiny preview when it should be written to its own file.
+///
+/// `imag` is the (possibly downscaled) image used for the tiny preview and the monochrome check.
+fn describe_photo(
+    decoded: &Decoded,
+    imag: &image::DynamicImage,
+    opts: &ProcessOptions,
+) -> Result<(Photo, Option<Vec<u8>>)> {
+    use image::GenericImageView;
+    let Decoded {
+        imag: full,
+        meta,
+        orientation,
+        focal_point,
+        ..
+    } = decoded;
+    let palette = if opts.stages.palette {
+        report(opts, Progress::ExtractingPalette);
+        let size = opts.palette_sample_size;
+        let (small, quality) = if size > 0 && (full.width() > size || full.height() > size) {
+            // Averaging the pixels down already samples them, so color-thief can look at every one
+            (Some(full.thumbnail(size, size)), 1)
+        } else {
+            (None, 10)
+        };
+        let samp_image = small.as_ref().unwrap_or(full);
+        let samp = samples(samp_image)?;
+        Some(
+            color_thief::get_palette(samp, colortype_image2thief(samp_image.color())?, quality, 10)
+                .context(PaletteExtract {})?,
+        )
+    } else {
+        None
+    };
+    let (width, height) = full.dimensions();
+    // Without a webp encoder there's no tiny preview, rather than failing every photo
+    let tiny_preview = if opts.stages.tiny_preview && cfg!(feature = "webp") {
+        let thumb = meta
+            .as_deref()
+            .filter(|_| opts.preview_from_exif_thumbnail)
+            .and_then(|meta| exif_thumbnail(meta, *orientation, (width, height)));
+        Some(encode_tiny_preview(
+            thumb.as_ref().unwrap_or(imag),
+            opts.tiny_preview_size,
+        )?)
+    } else {
+        None
+    };
+    let tiny_preview_info = tiny_preview.as_ref().map(|(_, info)| *info);
+    if let Some(info) = &tiny_preview_info {
+        log::debug!("Tiny preview: {}x{}, {} bytes", info.width, info.height, info.bytes);
+    }
+    let (tiny_preview, tiny_preview_file) = match tiny_preview.map(|(webp, _)| webp) {
+        Some(webp) if opts.tiny_preview_file => (None, Some(webp)),
+        Some(webp) if opts.tiny_preview_svg => (
+            Some(blurred_svg_data_uri(&webp, (width, height), opts.tiny_preview_size)),
+            None,
+        ),
+        webp => (webp.map(|webp| webp_data_uri(&webp)), None),
+    };
 
-    source.push(Source {
+    let photo = Photo {
+        schema_version: PHOTO_SCHEMA_VERSION,
+        tiny_preview,
+        tiny_preview_url: None,
+        tiny_preview_info,
+        source: vec![],
+        width,
+        height,
+        served_width: width,
+        served_height: height,
+        palette_hex: palette.as_ref().filter(|_| opts.palette_hex).map(|palette| {
+            palette
+                .iter()
+                .map(|c| format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b))
+                .collect()
+        }),
+        palette,
+        geo: meta.as_deref().and_then(|m| m.gps()),
+        aperture: meta.as_deref().and_then(|m| m.fnumber()),
+        shutter_speed: meta.as_deref().and_then(|m| m.exposure_time()),
+        focal_length: meta.as_deref().and_then(|m| m.focal_length()),
+        iso: meta.as_deref().and_then(|m| m.iso()),
+        exposure_program: meta.as_deref().and_then(exif::exposure_program),
+        metering_mode: meta.as_deref().and_then(exif::metering_mode),
+        flash: meta.as_deref().and_then(exif::flash),
+        subject_distance: meta.as_deref().and_then(exif::subject_distance),
+        white_balance: meta.as_deref().and_then(exif::white_balance),
+        source_orientation: meta.as_deref().and_then(|m| m.orientation().exif_value()),
+        rating: meta.as_deref().and_then(exif::rating),
+        focal_point: *focal_point,
+        social_preview: None,
+        monochrome: is_monochrome(imag),
+        phash: phash::phash(full),
+        timings: None,
+        file_prefix: String::new(),
+    };
+    Ok((photo, tiny_preview_file))
+}
+
+fn original_source(file_name: &str, width: u32, format: image::ImageFormat) -> Result<Source> {
+    Ok(Source {
         original: true,
         srcset: vec![SrcSetEntry {
             src: file_name.to_owned(),
-            width: width,
+            width,
+            density: None,
         }],
-        r#type: format_exiv2mime(&exivfmt)?.to_owned(),
-    });
+        r#type: format_mime(format)?.to_owned(),
+        media: None,
+    })
+}
 
-    Ok((
-        Photo {
-            tiny_preview: make_tiny_preview(&imag)?,
-            source,
-            width,
-            height,
-            palette,
-            geo: meta.get_gps_info().map(
-                |rexiv2::GpsInfo {
-                     latitude,
-                     longitude,
-                     altitude,
-                 }| GeoLocation {
-                    latitude,
-                    longitude,
-                    altitude,
-                },
-            ),
-            aperture: meta.get_fnumber(),
-            shutter_speed: meta.get_exposure_time(),
-            focal_length: meta.get_focal_length(),
-            iso: meta.get_iso_speed(),
+fn report(opts: &ProcessOptions, progress: Progress) {
+    if let Some(callback) = &opts.progress {
+        (callback.0)(progress);
+    }
+}
+
+fn check_cancelled(opts: &ProcessOptions) -> Result<()> {
+    match &opts.cancel {
+        Some(token) if token.is_cancelled() => Err(Error::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+/// Mime types that `process_photo` accepts with the default options and the compiled-in encoders,
+/// e.g. for the `accept` attribute of an upload form.
+pub fn supported_input_formats() -> &'static [&'static str] {
+    static FORMATS: std::sync::OnceLock<Vec<&'static str>> = std::sync::OnceLock::new();
+    FORMATS.get_or_init(|| {
+        INPUT_FORMATS
+            .iter()
+            .filter(|&&format| encoders_for_format(format, format_is_lossless(format), None).is_ok())
+            .filter_map(|&format| format_mime(format).ok())
+            .collect()
+    })
+}
+
+/// Mime types of the renditions the compiled-in encoders produce (the tiny preview is always WebP).
+pub fn supported_output_formats() -> &'static [&'static str] {
+    &[
+        #[cfg(any(feature = "jpeg", feature = "wasm"))]
+        "image/jpeg",
+        #[cfg(feature = "webp")]
+        "image/webp",
+        #[cfg(any(feature = "png", feature = "wasm"))]
+        "image/png",
+    ]
+}
+
+/// Number of differing bits between two perceptual hashes; up to ~10 usually means the same picture.
+pub fn phash_distance(a: &str, b: &str) -> Result<u32> {
+    let a = u64::from_str_radix(a, 16).context(PhashParse {})?;
+    let b = u64::from_str_radix(b, 16).context(PhashParse {})?;
+    Ok((a ^ b).count_ones())
+}
+
+fn decode_image(
+    file_contents: &[u8],
+    format: image::ImageFormat,
+    max_pixels: Option<u64>,
+) -> Result<image::DynamicImage> {
+    // Only the header is read for the dimensions, so decompression bombs are rejected before allocating anything.
+    // Errors are left to the decoding, which knows how to work around some of them.
+    if let (Some(max_pixels), Ok((width, height))) = (max_pixels, reader(file_contents, format).into_dimensions()) {
+        if u64::from(width) * u64::from(height) > max_pixels {
+            return Err(Error::TooManyPixels {
+                width,
+                height,
+                max_pixels,
+            });
+        }
+    }
+    let imag = match reader(file_contents, format).decode() {
+        Ok(imag) => imag,
+        Err(_)
+            if format == image::ImageFormat::Jpeg
+                && jpeg::is_cmyk(file_contents)
+                && !jpeg::has_adobe_marker(file_contents) =>
+        {
+            log::info!("Decoding CMYK JPEG without an Adobe marker");
+            reader(&jpeg::with_adobe_marker(file_contents), format)
+                .decode()
+                .context(ImageProc {})?
         },
-        files.into_iter().flatten().collect(),
-    ))
+        Err(source) => return Err(Error::ImageProc { source }),
+    };
+    Ok(normalize_color(imag))
 }
 
-fn format_exiv2image(mt: &rexiv2::MediaType) -> Result<image::ImageFormat> {
-    match mt {
-        rexiv2::MediaType::Jpeg => Ok(image::ImageFormat::Jpeg),
-        rexiv2::MediaType::Png => Ok(image::ImageFormat::Png),
-        f => Err(Error::UnsupportedFormat { format: f.clone() }),
+/// Decodes from memory like `image::load_from_memory_with_format`, but can tell the dimensions first.
+fn reader(file_contents: &[u8], format: image::ImageFormat) -> image::io::Reader<std::io::Cursor<&[u8]>> {
+    image::io::Reader::with_format(std::io::Cursor::new(file_contents), format)
+}
+
+/// Converts anything the encoders don't handle (grayscale, 16-bit, BGR) to 8-bit RGB(A).
+fn normalize_color(imag: image::DynamicImage) -> image::DynamicImage {
+    match imag.color() {
+        image::ColorType::Rgb8 | image::ColorType::Rgba8 => imag,
+        c if c.has_alpha() => image::DynamicImage::ImageRgba8(imag.into_rgba8()),
+        _ => image::DynamicImage::ImageRgb8(imag.into_rgb8()),
     }
 }
 
-fn format_exiv2mime(mt: &rexiv2::MediaType) -> Result<&'static str> {
-    match mt {
-        rexiv2::MediaType::Jpeg => Ok("image/jpeg"),
-        rexiv2::MediaType::Png => Ok("image/png"),
-        f => Err(Error::UnsupportedFormat { format: f.clone() }),
+fn format_mime(fmt: image::ImageFormat) -> Result<&'static str> {
+    match fmt {
+        image::ImageFormat::Jpeg => Ok("image/jpeg"),
+        image::ImageFormat::Png => Ok("image/png"),
+        f => Err(Error::UnsupportedImageFormat { format: f }),
     }
 }
 
-fn format_is_lossless(mt: &rexiv2::MediaType) -> bool {
-    match mt {
-        rexiv2::MediaType::Png => true,
+fn format_is_lossless(fmt: image::ImageFormat) -> bool {
+    match fmt {
+        image::ImageFormat::Png => true,
         _f => false,
     }
 }
 
-fn encoders_for_format(mt: &rexiv2::MediaType) -> Result<&'static [Encoder]> {
-    match mt {
-        rexiv2::MediaType::Jpeg => Ok(&[encode_jpeg, encode_webp]),
-        rexiv2::MediaType::Png => Ok(&[encode_png]),
-        f => Err(Error::UnsupportedFormat { format: f.clone() }),
+/// Only the encoders that are compiled in, erroring when that leaves none for the format.
+///
+/// PNGs that are not kept lossless also get WebP, but stay PNG rather than JPEG otherwise to keep transparency.
+/// `formats` (from `ProcessOptions::formats`) narrows them down further.
+fn encoders_for_format(
+    fmt: image::ImageFormat,
+    lossless: bool,
+    formats: Option<&[OutputFormat]>,
+) -> Result<Vec<Encoder>> {
+    let encoders: &'static [(OutputFormat, Encoder)] = match fmt {
+        image::ImageFormat::Jpeg => &[
+            #[cfg(any(feature = "jpeg", feature = "wasm"))]
+            (OutputFormat::Jpeg, encode_jpeg),
+            #[cfg(feature = "webp")]
+            (OutputFormat::Webp, encode_webp),
+        ],
+        image::ImageFormat::Png if lossless => &[
+            #[cfg(any(feature = "png", feature = "wasm"))]
+            (OutputFormat::Png, encode_png),
+        ],
+        image::ImageFormat::Png => &[
+            #[cfg(any(feature = "png", feature = "wasm"))]
+            (OutputFormat::Png, encode_png),
+            #[cfg(feature = "webp")]
+            (OutputFormat::Webp, encode_webp),
+        ],
+        f => return Err(Error::UnsupportedImageFormat { format: f }),
+    };
+    if encoders.is_empty() {
+        return Err(Error::NoEncoder { format: fmt });
+    }
+    let allowed = encoders
+        .iter()
+        .filter(|(output, _)| formats.map_or(true, |formats| formats.contains(output)))
+        .map(|&(_, encoder)| encoder)
+        .collect::<Vec<_>>();
+    if allowed.is_empty() {
+        return Err(Error::NoAllowedFormat { format: fmt });
+    }
+    Ok(allowed)
+}
+
+/// Bounding boxes of the smaller renditions in `Width` mode, largest first, never upscaling the main image.
+fn thumbnail_boxes(widths: Option<&[u32]>, width: u32, (main_width, main_height): (u32, u32)) -> Vec<(u32, u32)> {
+    match widths {
+        None => [(2500, 2000), (1500, 1000)]
+            .iter()
+            .filter(|&&(min, size)| width > min && size < main_width.max(main_height))
+            .map(|&(_, size)| (size, size))
+            .collect(),
+        Some(widths) => {
+            let mut widths = widths.iter().copied().filter(|&w| w < main_width).collect::<Vec<_>>();
+            widths.sort_unstable_by(|a, b| b.cmp(a));
+            widths.dedup();
+            widths.into_iter().map(|w| (w, u32::MAX)).collect()
+        },
     }
 }
 
-fn orient_image(imag: image::DynamicImage, ori: rexiv2::Orientation) -> image::DynamicImage {
-    use rexiv2::Orientation::*;
+/// Resets the orientation tag to normal, for metadata that is written along with pixels
+/// that were already rotated (like the ones from `decode_and_orient`), so viewers don't rotate them twice.
+pub fn normalize_orientation(meta: &dyn Metadata) {
+    meta.set_orientation(Orientation::Normal);
+}
+
+fn orient_image(imag: image::DynamicImage, ori: Orientation) -> image::DynamicImage {
+    use Orientation::*;
     match ori {
         HorizontalFlip => imag.fliph(),
         Rotate180 => imag.rotate180(),
@@ -252,6 +1320,98 @@ fn orient_image(imag: image::DynamicImage, ori: rexiv2::Orientation) -> image::D
     }
 }
 
+/// The widths to produce for a crop, largest first, never upscaled.
+fn crop_widths(spec: &CropSpec, cropped: &image::DynamicImage) -> Vec<u32> {
+    use image::GenericImageView;
+    let mut widths = spec.widths.iter().map(|&w| w.min(cropped.width())).collect::<Vec<_>>();
+    widths.sort_unstable_by(|a, b| b.cmp(a));
+    widths.dedup();
+    widths
+}
+
+/// Cuts the largest rectangle of the given aspect ratio out of the image, positioned around the gravity point.
+fn crop_to_aspect(
+    imag: &image::DynamicImage,
+    aspect: (u32, u32),
+    gravity: Gravity,
+    subject: Option<FocalPoint>,
+) -> image::DynamicImage {
+    use image::GenericImageView;
+    let (width, height) = imag.dimensions();
+    let (aw, ah) = (u64::from(aspect.0.max(1)), u64::from(aspect.1.max(1)));
+    let (cw, ch) = if u64::from(width) * ah > u64::from(height) * aw {
+        ((u64::from(height) * aw / ah) as u32, height)
+    } else {
+        (width, (u64::from(width) * ah / aw) as u32)
+    };
+    let (fx, fy) = match gravity {
+        Gravity::Center => (0.5, 0.5),
+        Gravity::FocalPoint { x, y } => (x, y),
+        Gravity::Subject => subject.map(|p| (p.x, p.y)).unwrap_or((0.5, 0.5)),
+    };
+    let place = |focus: f32, full: u32, size: u32| {
+        let start = focus * full as f32 - size as f32 / 2.0;
+        start.max(0.0).min((full - size) as f32) as u32
+    };
+    imag.crop_imm(place(fx, width, cw), place(fy, height, ch), cw.max(1), ch.max(1))
+}
+
+/// Reads the center of `Exif.Photo.SubjectArea` (a point, circle or rectangle; all start with the center).
+fn subject_area(meta: &dyn Metadata, (width, height): (u32, u32)) -> Option<FocalPoint> {
+    let area = meta.tag_string("Exif.Photo.SubjectArea")?;
+    let mut coords = area.split_whitespace().map(|c| c.parse::<u32>());
+    let x = coords.next()?.ok()?;
+    let y = coords.next()?.ok()?;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    Some(FocalPoint {
+        x: (x as f32 / width as f32).max(0.0).min(1.0),
+        y: (y as f32 / height as f32).max(0.0).min(1.0),
+    })
+}
+
+/// Maps a point from the stored image to the image as transformed by `orient_image`.
+fn orient_point(p: FocalPoint, ori: Orientation) -> FocalPoint {
+    use Orientation::*;
+    let (x, y) = match ori {
+        HorizontalFlip => (1.0 - p.x, p.y),
+        Rotate180 => (1.0 - p.x, 1.0 - p.y),
+        VerticalFlip => (p.x, 1.0 - p.y),
+        Rotate90HorizontalFlip => (p.y, p.x),
+        Rotate90 => (1.0 - p.y, p.x),
+        Rotate90VerticalFlip => (1.0 - p.y, 1.0 - p.x),
+        Rotate270 => (p.y, 1.0 - p.x),
+        _ => (p.x, p.y),
+    };
+    FocalPoint { x, y }
+}
+
+/// Whether there are no more than `max` distinct colors, stopping early once there are.
+fn has_few_colors(imag: &image::DynamicImage, max: usize) -> bool {
+    use image::GenericImageView;
+    let mut seen = std::collections::HashSet::new();
+    imag.pixels().all(|(_, _, p)| {
+        seen.insert(p.0);
+        seen.len() <= max
+    })
+}
+
+/// Whether the image is effectively grayscale, judging by a sample of up to ~10k pixels.
+fn is_monochrome(imag: &image::DynamicImage) -> bool {
+    use image::{GenericImageView, Pixel};
+    const EPSILON: u8 = 6;
+    let (width, height) = imag.dimensions();
+    let step = ((u64::from(width) * u64::from(height) / 10_000) as f64).sqrt().max(1.0) as usize;
+    (0..height).step_by(step).all(|y| {
+        (0..width).step_by(step).all(|x| {
+            let p = imag.get_pixel(x, y).to_rgb();
+            let (r, g, b) = (p[0], p[1], p[2]);
+            r.max(g).max(b) - r.min(g).min(b) <= EPSILON
+        })
+    })
+}
+
 fn colortype_image2thief(t: image::ColorType) -> Result<color_thief::ColorFormat> {
     match t {
         image::ColorType::Rgb8 => Ok(color_thief::ColorFormat::Rgb),
@@ -260,20 +1420,161 @@ fn colortype_image2thief(t: image::ColorType) -> Result<color_thief::ColorFormat
     }
 }
 
+/// Decodes and orients the EXIF thumbnail, unless it doesn't match the main image's aspect ratio
+/// (some editors leave stale thumbnails behind).
+fn exif_thumbnail(
+    meta: &dyn Metadata,
+    orientation: Orientation,
+    (width, height): (u32, u32),
+) -> Option<image::DynamicImage> {
+    use image::GenericImageView;
+    let thumb = orient_image(
+        normalize_color(image::load_from_memory(meta.thumbnail()?).ok()?),
+        orientation,
+    );
+    let ratio = |(w, h): (u32, u32)| w as f32 / h.max(1) as f32;
+    let expected = ratio((width, height));
+    if (ratio(thumb.dimensions()) - expected).abs() / expected > 0.05 {
+        log::debug!("Ignoring EXIF thumbnail with mismatched aspect ratio");
+        return None;
+    }
+    Some(thumb)
+}
+
+/// The tiny preview data URI for an image, at the default size.
 pub fn make_tiny_preview(imag: &image::DynamicImage) -> Result<String> {
-    let thumb = imag.resize(48, 48, image::imageops::FilterType::Gaussian);
-    let webp = webp::encode(thumb, webp::Quality::Lossy(0.2)).context(WebpEncode {})?;
-    Ok(format!("data:image/webp;base64,{}", base64::encode(webp.as_slice())))
+    let (webp, _) = encode_tiny_preview(imag, ProcessOptions::default().tiny_preview_size)?;
+    Ok(webp_data_uri(&webp))
 }
 
-fn samples(imag: &image::DynamicImage) -> Result<image::FlatSamples<Vec<u8>>> {
-    Ok(match imag.color() {
-        image::ColorType::Rgb8 => imag.to_rgb8().into_flat_samples(),
-        image::ColorType::Rgba8 => imag.to_rgba8().into_flat_samples(),
-        f => return Err(Error::UnsupportedColor { format: f }),
+/// The tiny preview data URI for a photo at the default size, made from its EXIF thumbnail when it's usable
+/// (like with `ProcessOptions::preview_from_exif_thumbnail`), so that the photo itself is only decoded without one.
+pub fn quick_tiny_preview(file_contents: &[u8]) -> Result<String> {
+    let thumb = metadata::read(file_contents).and_then(|meta| {
+        let format = image::guess_format(file_contents).ok()?;
+        let dimensions = reader(file_contents, format).into_dimensions().ok()?;
+        // Compared before orienting, both are stored the same way
+        let thumb = exif_thumbnail(&*meta, Orientation::Normal, dimensions)?;
+        Some(orient_image(thumb, meta.orientation()))
+    });
+    match thumb {
+        Some(thumb) => make_tiny_preview(&thumb),
+        None => make_tiny_preview(&decode_and_orient(file_contents, &ProcessOptions::default())?.0),
+    }
+}
+
+#[cfg(feature = "webp")]
+fn encode_tiny_preview(imag: &image::DynamicImage, size: u32) -> Result<(Vec<u8>, TinyPreviewInfo)> {
+    use image::GenericImageView;
+    let size = size.max(1);
+    let thumb = imag.resize(size, size, image::imageops::FilterType::Gaussian);
+    let webp = webp::encode(&thumb, webp::Quality::Lossy(0.2), WebpImageHint::Default).context(WebpEncode {})?;
+    let webp = webp.as_slice().to_vec();
+    let info = TinyPreviewInfo {
+        width: thumb.width(),
+        height: thumb.height(),
+        bytes: webp.len(),
+    };
+    Ok((webp, info))
+}
+
+#[cfg(not(feature = "webp"))]
+fn encode_tiny_preview(_imag: &image::DynamicImage, _size: u32) -> Result<(Vec<u8>, TinyPreviewInfo)> {
+    Err(Error::NoEncoder {
+        format: image::ImageFormat::WebP,
     })
 }
 
+fn webp_data_uri(webp: &[u8]) -> String {
+    format!("data:image/webp;base64,{}", base64::encode(webp))
+}
+
+/// The tiny preview inside an SVG that blurs it, see `ProcessOptions::tiny_preview_svg`.
+fn blurred_svg_data_uri(webp: &[u8], (width, height): (u32, u32), preview_size: u32) -> String {
+    // About one pixel of the tiny preview, and without blurring in transparency from the edges
+    let svg = format!(
+        "<svg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 {w} {h}'>\
+         <filter id='b' color-interpolation-filters='sRGB'><feGaussianBlur stdDeviation='{blur}'/>\
+         <feComponentTransfer><feFuncA type='discrete' tableValues='1 1'/></feComponentTransfer></filter>\
+         <image filter='url(#b)' width='{w}' height='{h}' preserveAspectRatio='none' href='{href}'/></svg>",
+        w = width,
+        h = height,
+        blur = (width.max(height) / preview_size.max(1)).max(1),
+        href = webp_data_uri(webp)
+    );
+    // Only these need escaping with the single quoted attributes
+    format!(
+        "data:image/svg+xml,{}",
+        svg.replace('%', "%25")
+            .replace('#', "%23")
+            .replace('<', "%3C")
+            .replace('>', "%3E")
+    )
+}
+
+/// Adds the source's EXIF and XMP to a WebP, other formats are left alone.
+#[cfg(feature = "webp")]
+fn embed_metadata(result: &mut EncodedImg, dimensions: (u32, u32), embedded: &Embedded) {
+    if result.mime_type != "image/webp" {
+        return;
+    }
+    let exif = embedded.exif.as_deref();
+    let xmp = embedded.xmp.as_deref();
+    match webp::with_metadata(&result.bytes, dimensions, exif, xmp) {
+        Some(bytes) => result.bytes = bytes,
+        None => log::warn!("Unable to add metadata to a WebP"),
+    }
+}
+
+#[cfg(not(feature = "webp"))]
+fn embed_metadata(_result: &mut EncodedImg, _dimensions: (u32, u32), _embedded: &Embedded) {}
+
+/// The packed pixel bytes, borrowed instead of copied.
+fn samples(imag: &image::DynamicImage) -> Result<&[u8]> {
+    match imag.color() {
+        image::ColorType::Rgb8 | image::ColorType::Rgba8 => Ok(imag.as_bytes()),
+        f => Err(Error::UnsupportedColor { format: f }),
+    }
+}
+
+fn short_hash(data: &[u8]) -> String {
+    use tiny_keccak::Hasher;
+    let mut hasher = tiny_keccak::ParallelHash::v128(&[], 8192);
+    hasher.update(data);
+    let mut buf = [0u8; 16];
+    hasher.finalize(&mut buf);
+    hex::encode(&buf[0..6])
+}
+
+/// The start of the output file names: the hash and the slug of the file name, or just the hash
+/// (unless there's a placeholder) when the slug is empty, rather than ending in a stray underscore.
+fn file_prefix(hash: String, file_name: &str, opts: &ProcessOptions) -> String {
+    let slug = match opts.slug_style {
+        SlugStyle::Ascii => slug::slugify(basename(file_name)),
+        SlugStyle::Unicode => slugify_unicode(&basename(file_name)),
+    };
+    match (slug.is_empty(), &opts.slug_placeholder) {
+        (false, _) => format!("{}_{}", hash, slug),
+        (true, Some(placeholder)) => format!("{}_{}", hash, placeholder),
+        (true, None) => hash,
+    }
+}
+
+/// Like `slug::slugify` without the transliteration: letters and digits of any script are kept,
+/// along with the combining accents and kana voiced sound marks of decomposed names (as macOS writes them).
+fn slugify_unicode(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in name.chars().flat_map(char::to_lowercase) {
+        let combining = matches!(c, '\u{300}'..='\u{36f}' | '\u{3099}'..='\u{309a}');
+        if c.is_alphanumeric() || (combining && !slug.is_empty() && !slug.ends_with('-')) {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_owned()
+}
+
 fn basename(path: &str) -> String {
     let mut pieces = path.rsplit('/');
     let mut parts = match pieces.next() {
@@ -287,7 +1588,23 @@ fn basename(path: &str) -> String {
     }
 }
 
-type Encoder = fn(&image::DynamicImage) -> Result<EncodedImg>;
+type Encoder = fn(&image::DynamicImage, &EncodeParams) -> Result<EncodedImg>;
+
+/// Per-photo encoder settings, derived from the options and the image analysis.
+#[derive(Clone, Copy)]
+#[cfg_attr(not(all(feature = "jpeg", feature = "webp", feature = "png")), allow(dead_code))]
+struct EncodeParams {
+    monochrome: bool,
+    webp_hint: WebpImageHint,
+    /// Overrides the default quality of the lossy encoders.
+    quality: Option<f32>,
+    jpeg_subsampling: ChromaSubsampling,
+    /// `ProcessOptions::jpeg_quality`, used when `quality` is not set.
+    jpeg_quality: Option<f32>,
+    webp_quality: Option<f32>,
+    /// Flat graphics, see `ProcessOptions::webp_lossless_max_colors`. A set `quality` still means lossy.
+    webp_lossless: bool,
+}
 
 struct EncodedImg {
     bytes: Vec<u8>,
@@ -295,15 +1612,123 @@ struct EncodedImg {
     file_ext: &'static str,
 }
 
+fn encode_timed(
+    encoder: &Encoder,
+    imag: &image::DynamicImage,
+    params: &EncodeParams,
+    timings: &mut Vec<EncodeTiming>,
+) -> Result<EncodedImg> {
+    use image::GenericImageView;
+    // Instant::now panics on wasm32-unknown-unknown, timings are just zero there
+    let start = (!cfg!(target_arch = "wasm32")).then(std::time::Instant::now);
+    let result = encoder(imag, params)?;
+    let duration = start.map(|start| start.elapsed()).unwrap_or_default();
+    log::debug!(
+        "Encoded {} at width {} in {:?}",
+        result.file_ext,
+        imag.width(),
+        duration
+    );
+    timings.push(EncodeTiming {
+        encoder: result.file_ext.to_owned(),
+        width: imag.width(),
+        duration,
+    });
+    Ok(result)
+}
+
+/// Binary searches for the highest quality that fits the budget, if the default quality doesn't.
+/// Falls back to the smallest attempt when nothing fits.
+fn encode_within_budget(
+    encoder: &Encoder,
+    imag: &image::DynamicImage,
+    params: &EncodeParams,
+    budget: &SizeBudget,
+    timings: &mut Vec<EncodeTiming>,
+) -> Result<EncodedImg> {
+    let first = encode_timed(encoder, imag, params, timings)?;
+    if first.bytes.len() <= budget.max_bytes {
+        return Ok(first);
+    }
+    let default_quality = match first.mime_type {
+        #[cfg(any(feature = "jpeg", feature = "wasm"))]
+        "image/jpeg" => Some(params.jpeg_quality.unwrap_or(JPEG_QUALITY + quality_bonus(imag))),
+        #[cfg(feature = "webp")]
+        "image/webp" => Some(params.webp_quality.unwrap_or(WEBP_QUALITY + quality_bonus(imag))),
+        _ => None,
+    };
+    let mut high = match default_quality {
+        Some(quality) => quality,
+        None => return Ok(first),
+    };
+    let mut low = budget.min_quality;
+    let mut best = None;
+    let mut smallest = first;
+    for _ in 0..budget.max_attempts {
+        let quality = (low + high) / 2.0;
+        let result = encode_timed(
+            encoder,
+            imag,
+            &EncodeParams {
+                quality: Some(quality),
+                ..*params
+            },
+            timings,
+        )?;
+        if result.bytes.len() <= budget.max_bytes {
+            low = quality;
+            let close_enough = result.bytes.len() as f32 >= budget.max_bytes as f32 * (1.0 - budget.tolerance);
+            best = Some(result);
+            if close_enough {
+                break;
+            }
+        } else {
+            high = quality;
+            if result.bytes.len() < smallest.bytes.len() {
+                smallest = result;
+            }
+        }
+    }
+    Ok(best.unwrap_or_else(|| {
+        log::warn!(
+            "Could not fit {} into {} bytes, got {}",
+            smallest.file_ext,
+            budget.max_bytes,
+            smallest.bytes.len()
+        );
+        smallest
+    }))
+}
+
 // Big images can have less "quality": see "Compressive Images"
+#[cfg(any(feature = "jpeg", feature = "webp", feature = "wasm"))]
 fn quality_bonus(imag: &image::DynamicImage) -> f32 {
     use image::GenericImageView;
     (5000.0 - f32::max(imag.width() as f32, 4900.0)) * 0.001
 }
 
-fn encode_webp(imag: &image::DynamicImage) -> Result<EncodedImg> {
-    let webp =
-        webp::encode(imag.clone(), webp::Quality::Lossy(WEBP_QUALITY + quality_bonus(imag))).context(WebpEncode {})?;
+/// The quality from `ProcessOptions::quality_by_width` for a rendition of this width.
+fn quality_for_width(table: &[(u32, f32)], width: u32) -> Option<f32> {
+    table
+        .iter()
+        .filter(|&&(max_width, _)| width <= max_width)
+        .min_by_key(|&&(max_width, _)| max_width)
+        .or_else(|| table.iter().max_by_key(|&&(max_width, _)| max_width))
+        .map(|&(_, quality)| quality)
+}
+
+// libwebp handles flat chroma well by itself, so monochrome doesn't matter here
+#[cfg(feature = "webp")]
+fn encode_webp(imag: &image::DynamicImage, params: &EncodeParams) -> Result<EncodedImg> {
+    let quality = match params.quality {
+        None if params.webp_lossless => webp::Quality::Lossless,
+        quality => webp::Quality::Lossy(
+            quality
+                .or(params.webp_quality)
+                .unwrap_or(WEBP_QUALITY + quality_bonus(imag)),
+        ),
+    };
+    let webp = webp::encode(imag, quality, params.webp_hint).context(WebpEncode {})?;
     let mut bytes = Vec::new();
     bytes.extend_from_slice(webp.as_slice());
     Ok(EncodedImg {
@@ -313,21 +1738,52 @@ fn encode_webp(imag: &image::DynamicImage) -> Result<EncodedImg> {
     })
 }
 
-fn encode_jpeg(imag: &image::DynamicImage) -> Result<EncodedImg> {
+#[cfg(feature = "jpeg")]
+fn encode_jpeg(imag: &image::DynamicImage, params: &EncodeParams) -> Result<EncodedImg> {
     use image::GenericImageView;
     let mut jpeg = mozjpeg::Compress::new(match imag.color() {
+        _ if params.monochrome => mozjpeg::ColorSpace::JCS_GRAYSCALE,
         image::ColorType::Rgb8 => mozjpeg::ColorSpace::JCS_RGB,
         image::ColorType::Rgba8 => mozjpeg::ColorSpace::JCS_EXT_RGBA,
         f => return Err(Error::UnsupportedColor { format: f }),
     });
     jpeg.set_scan_optimization_mode(mozjpeg::ScanMode::AllComponentsTogether);
+    if !params.monochrome {
+        // Luma sampling factors relative to the chroma ones
+        let (h, v) = match params.jpeg_subsampling {
+            ChromaSubsampling::Yuv444 => (1, 1),
+            ChromaSubsampling::Yuv422 => (2, 1),
+            ChromaSubsampling::Yuv420 => (2, 2),
+        };
+        let luma = &mut jpeg.components_mut()[0];
+        luma.h_samp_factor = h;
+        luma.v_samp_factor = v;
+    }
     jpeg.set_size(imag.width() as usize, imag.height() as usize);
-    jpeg.set_quality(JPEG_QUALITY + quality_bonus(imag));
+    jpeg.set_quality(
+        params
+            .quality
+            .or(params.jpeg_quality)
+            .unwrap_or(JPEG_QUALITY + quality_bonus(imag)),
+    );
     jpeg.set_mem_dest();
 
+    // Feed rows straight from the image in chunks, instead of making a full copy first
+    let pixel_len = imag.color().bytes_per_pixel() as usize;
+    let row_len = imag.width() as usize * pixel_len;
     jpeg.start_compress();
-    let samp = samples(imag)?;
-    jpeg.write_scanlines(&samp.as_slice());
+    for rows in samples(imag)?.chunks(row_len * JPEG_SCANLINE_CHUNK) {
+        if params.monochrome {
+            use image::Pixel;
+            let luma = rows
+                .chunks(pixel_len)
+                .map(|p| image::Rgb([p[0], p[1], p[2]]).to_luma()[0])
+                .collect::<Vec<_>>();
+            jpeg.write_scanlines(&luma);
+        } else {
+            jpeg.write_scanlines(rows);
+        }
+    }
     jpeg.finish_compress();
 
     jpeg.data_to_vec()
@@ -339,9 +1795,37 @@ fn encode_jpeg(imag: &image::DynamicImage) -> Result<EncodedImg> {
         .map_err(|_| Error::JpegEncode {})
 }
 
-fn encode_png(imag: &image::DynamicImage) -> Result<EncodedImg> {
+/// The image crate's baseline encoder, for builds without mozjpeg (like wasm).
+#[cfg(all(feature = "wasm", not(feature = "jpeg")))]
+fn encode_jpeg(imag: &image::DynamicImage, params: &EncodeParams) -> Result<EncodedImg> {
+    use image::GenericImageView;
+    let quality = params
+        .quality
+        .or(params.jpeg_quality)
+        .unwrap_or(JPEG_QUALITY + quality_bonus(imag));
+    let mut bytes = Vec::new();
+    let mut encoder = image::jpeg::JpegEncoder::new_with_quality(&mut bytes, quality.max(1.0).min(100.0) as u8);
+    let (width, height) = imag.dimensions();
+    let result = if params.monochrome {
+        encoder.encode(&imag.to_luma8(), width, height, image::ColorType::L8)
+    } else {
+        encoder.encode(imag.as_bytes(), width, height, imag.color())
+    };
+    result.map_err(|_| Error::JpegEncode {})?;
+    Ok(EncodedImg {
+        bytes,
+        mime_type: "image/jpeg",
+        file_ext: "jpg",
+    })
+}
+
+#[cfg(any(feature = "png", feature = "wasm"))]
+fn encode_png(imag: &image::DynamicImage, params: &EncodeParams) -> Result<EncodedImg> {
     use exoquant::{convert_to_indexed, ditherer, optimizer, Color};
     use image::{GenericImageView, Pixel};
+    if params.monochrome {
+        return encode_png_grey(imag);
+    }
     let pixels = imag
         .pixels()
         .map(|(_, _, p)| {
@@ -351,15 +1835,17 @@ fn encode_png(imag: &image::DynamicImage) -> Result<EncodedImg> {
         .collect::<Vec<_>>();
     let width = imag.width().try_into().context(ConvertInt {})?;
     let height = imag.height().try_into().context(ConvertInt {})?;
-    let (palette, indexed_pixels) = convert_to_indexed(
-        &pixels,
-        width,
-        PNG_QUANTIZE_COLORS,
-        &optimizer::KMeans,
-        &ditherer::FloydSteinberg::checkered(),
-    );
-    let mut state = lodepng::State::new();
-    state.set_custom_zlib(Some(compress_zopfli), ptr::null());
+    let (palette, indexed_pixels) = match exact_palette(&pixels) {
+        Some(exact) => exact,
+        None => convert_to_indexed(
+            &pixels,
+            width,
+            PNG_QUANTIZE_COLORS,
+            &optimizer::KMeans,
+            &ditherer::FloydSteinberg::checkered(),
+        ),
+    };
+    let mut state = png_state();
     for color in palette {
         let rgba = rgb::RGBA::new(color.r, color.g, color.b, color.a);
         state.info_png_mut().color.palette_add(rgba).context(PngEncode {})?;
@@ -377,6 +1863,63 @@ fn encode_png(imag: &image::DynamicImage) -> Result<EncodedImg> {
     })
 }
 
+/// Indexes the pixels by their own colors if there are no more than `PNG_QUANTIZE_COLORS` of them,
+/// so that flat graphics don't get dithering noise from quantization.
+#[cfg(any(feature = "png", feature = "wasm"))]
+fn exact_palette(pixels: &[exoquant::Color]) -> Option<(Vec<exoquant::Color>, Vec<u8>)> {
+    let mut index = std::collections::HashMap::new();
+    let mut palette = vec![];
+    let mut indexed = Vec::with_capacity(pixels.len());
+    for &c in pixels {
+        let i = match index.entry((c.r, c.g, c.b, c.a)) {
+            std::collections::hash_map::Entry::Occupied(e) => *e.get(),
+            std::collections::hash_map::Entry::Vacant(e) => {
+                if palette.len() >= PNG_QUANTIZE_COLORS {
+                    return None;
+                }
+                palette.push(c);
+                *e.insert(palette.len() as u8 - 1)
+            },
+        };
+        indexed.push(i);
+    }
+    Some((palette, indexed))
+}
+
+#[cfg(any(feature = "png", feature = "wasm"))]
+fn encode_png_grey(imag: &image::DynamicImage) -> Result<EncodedImg> {
+    use image::GenericImageView;
+    let mut state = png_state();
+    let (colortype, raw) = if imag.color().has_alpha() {
+        (lodepng::ColorType::GREY_ALPHA, imag.to_luma_alpha8().into_raw())
+    } else {
+        (lodepng::ColorType::GREY, imag.to_luma8().into_raw())
+    };
+    state.info_png_mut().color.set_bitdepth(8);
+    state.info_png_mut().color.colortype = colortype;
+    state.info_raw_mut().set_bitdepth(8);
+    state.info_raw_mut().colortype = colortype;
+    let width = imag.width().try_into().context(ConvertInt {})?;
+    let height = imag.height().try_into().context(ConvertInt {})?;
+    let bytes = state.encode(&raw, width, height).context(PngEncode {})?;
+    Ok(EncodedImg {
+        bytes,
+        mime_type: "image/png",
+        file_ext: "png",
+    })
+}
+
+#[cfg(any(feature = "png", feature = "wasm"))]
+fn png_state() -> lodepng::State {
+    #[allow(unused_mut)]
+    let mut state = lodepng::State::new();
+    // The wasm build makes do with lodepng's own deflate
+    #[cfg(feature = "png")]
+    state.set_custom_zlib(Some(compress_zopfli), ptr::null());
+    state
+}
+
+#[cfg(feature = "png")]
 fn compress_zopfli(
     input: &[u8], output: &mut dyn std::io::Write, _context: &lodepng::CompressSettings
 ) -> Result<(), lodepng::Error> {
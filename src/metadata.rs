@@ -0,0 +1,243 @@
+//! Embedded metadata behind a trait, so that exiv2 (C++, doesn't build for wasm) can be left out.
+
+use crate::{GeoLocation, Result};
+use num_rational::Ratio;
+
+/// The EXIF orientation: how the stored pixels have to be transformed for display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Orientation {
+    Unspecified,
+    Normal,
+    HorizontalFlip,
+    Rotate180,
+    VerticalFlip,
+    Rotate90HorizontalFlip,
+    Rotate90,
+    Rotate90VerticalFlip,
+    Rotate270,
+}
+
+impl Orientation {
+    /// The number used in the EXIF tag, `None` when it's unspecified.
+    pub fn exif_value(self) -> Option<u8> {
+        match self {
+            Orientation::Unspecified => None,
+            Orientation::Normal => Some(1),
+            Orientation::HorizontalFlip => Some(2),
+            Orientation::Rotate180 => Some(3),
+            Orientation::VerticalFlip => Some(4),
+            Orientation::Rotate90HorizontalFlip => Some(5),
+            Orientation::Rotate90 => Some(6),
+            Orientation::Rotate90VerticalFlip => Some(7),
+            Orientation::Rotate270 => Some(8),
+        }
+    }
+}
+
+/// What the pipeline reads from the embedded metadata. Tags are named like in exiv2, e.g. `Exif.Photo.Flash`.
+pub trait Metadata {
+    /// `None` when the backend can't tell, the format is then guessed from the magic bytes.
+    fn image_format(&self) -> Result<Option<image::ImageFormat>>;
+    /// Copies XMP from a sidecar file over the embedded metadata.
+    fn merge_sidecar(&self, xmp: &[u8]) -> Result<()>;
+    fn orientation(&self) -> Orientation;
+    fn set_orientation(&self, orientation: Orientation);
+    fn gps(&self) -> Option<GeoLocation>;
+    fn fnumber(&self) -> Option<f64>;
+    fn exposure_time(&self) -> Option<Ratio<i32>>;
+    fn focal_length(&self) -> Option<f64>;
+    fn iso(&self) -> Option<i32>;
+    fn tag_numeric(&self, tag: &str) -> Option<i32>;
+    fn tag_rational(&self, tag: &str) -> Option<Ratio<i32>>;
+    fn tag_string(&self, tag: &str) -> Option<String>;
+    /// The embedded EXIF thumbnail, still encoded.
+    fn thumbnail(&self) -> Option<&[u8]>;
+}
+
+/// Parses the metadata with whichever backend is compiled in.
+///
+/// Some valid images (e.g. stripped files) trip up exiv2, that's no reason to give up on them,
+/// so failures just give `None`.
+pub fn read(file_contents: &[u8]) -> Option<Box<dyn Metadata>> {
+    #[cfg(feature = "exiv2")]
+    match rexiv2::Metadata::new_from_buffer(file_contents) {
+        Ok(meta) => Some(Box::new(meta)),
+        Err(e) => {
+            log::warn!("Unable to parse metadata, continuing without it: {}", e);
+            None
+        },
+    }
+    #[cfg(not(feature = "exiv2"))]
+    {
+        let _ = file_contents;
+        None
+    }
+}
+
+#[cfg(feature = "exiv2")]
+mod exiv2 {
+    use super::{Metadata, Orientation};
+    use crate::{GeoLocation, MetadataParse, Result, SidecarParse};
+    use num_rational::Ratio;
+    use snafu::ResultExt;
+
+    /// Sidecar values that exiv2's convenience getters only read from EXIF.
+    const SIDECAR_EXIF_MIRRORS: &[(&str, &str)] = &[
+        ("Xmp.tiff.Orientation", "Exif.Image.Orientation"),
+        ("Xmp.exif.FNumber", "Exif.Photo.FNumber"),
+        ("Xmp.exif.ExposureTime", "Exif.Photo.ExposureTime"),
+        ("Xmp.exif.FocalLength", "Exif.Photo.FocalLength"),
+    ];
+
+    /// Maker note tags that some older cameras store the rotation in instead of `Exif.Image.Orientation`,
+    /// with the way their values map to orientations. Covers Canon (`AutoRotate` in the shot info)
+    /// and Panasonic (which uses the EXIF numbering). Minolta's `Rotation` is already read by gexiv2 itself.
+    const MAKER_NOTE_ORIENTATIONS: &[(&str, fn(i32) -> Option<Orientation>)] = &[
+        ("Exif.CanonSi.AutoRotate", |value| match value {
+            1 => Some(Orientation::Rotate90),
+            2 => Some(Orientation::Rotate180),
+            3 => Some(Orientation::Rotate270),
+            _ => None,
+        }),
+        ("Exif.Panasonic.Rotation", |value| match value {
+            3 => Some(Orientation::Rotate180),
+            6 => Some(Orientation::Rotate90),
+            8 => Some(Orientation::Rotate270),
+            _ => None,
+        }),
+    ];
+
+    impl Metadata for rexiv2::Metadata {
+        fn image_format(&self) -> Result<Option<image::ImageFormat>> {
+            match self.get_media_type() {
+                Ok(rexiv2::MediaType::Jpeg) => Ok(Some(image::ImageFormat::Jpeg)),
+                Ok(rexiv2::MediaType::Png) => Ok(Some(image::ImageFormat::Png)),
+                Ok(f) => Err(crate::Error::UnsupportedFormat { format: f }),
+                Err(_) => Ok(None),
+            }
+        }
+
+        fn merge_sidecar(&self, xmp: &[u8]) -> Result<()> {
+            let sidecar = rexiv2::Metadata::new_from_buffer(xmp).context(SidecarParse {})?;
+            for tag in sidecar.get_xmp_tags().context(SidecarParse {})? {
+                let values = sidecar.get_tag_multiple_strings(&tag).unwrap_or_default();
+                let result = if values.len() > 1 {
+                    self.set_tag_multiple_strings(&tag, &values.iter().map(|v| v.as_str()).collect::<Vec<_>>())
+                } else {
+                    sidecar.get_tag_string(&tag).and_then(|v| self.set_tag_string(&tag, &v))
+                };
+                if let Err(e) = result {
+                    log::debug!("Skipping sidecar tag {}: {}", tag, e);
+                }
+            }
+            for (xmp_tag, exif_tag) in SIDECAR_EXIF_MIRRORS {
+                if let Ok(value) = sidecar.get_tag_string(xmp_tag) {
+                    self.set_tag_string(exif_tag, &value).context(MetadataParse {})?;
+                }
+            }
+            Ok(())
+        }
+
+        fn orientation(&self) -> Orientation {
+            match self.get_orientation().into() {
+                // Best effort: a rotation in the maker notes wins over a missing or default standard tag
+                orientation @ (Orientation::Normal | Orientation::Unspecified) => MAKER_NOTE_ORIENTATIONS
+                    .iter()
+                    .find_map(|(tag, map)| self.tag_numeric(tag).and_then(map))
+                    .unwrap_or(orientation),
+                orientation => orientation,
+            }
+        }
+
+        fn set_orientation(&self, orientation: Orientation) {
+            // Takes care of Xmp.tiff.Orientation and Minolta maker notes too
+            rexiv2::Metadata::set_orientation(self, orientation.into());
+            for (tag, _) in MAKER_NOTE_ORIENTATIONS {
+                self.clear_tag(tag);
+            }
+        }
+
+        fn gps(&self) -> Option<GeoLocation> {
+            self.get_gps_info().map(
+                |rexiv2::GpsInfo {
+                     latitude,
+                     longitude,
+                     altitude,
+                 }| GeoLocation {
+                    latitude,
+                    longitude,
+                    altitude,
+                },
+            )
+        }
+
+        fn fnumber(&self) -> Option<f64> {
+            self.get_fnumber()
+        }
+
+        fn exposure_time(&self) -> Option<Ratio<i32>> {
+            self.get_exposure_time()
+        }
+
+        fn focal_length(&self) -> Option<f64> {
+            self.get_focal_length()
+        }
+
+        fn iso(&self) -> Option<i32> {
+            self.get_iso_speed()
+        }
+
+        fn tag_numeric(&self, tag: &str) -> Option<i32> {
+            if self.has_tag(tag) {
+                Some(self.get_tag_numeric(tag))
+            } else {
+                None
+            }
+        }
+
+        fn tag_rational(&self, tag: &str) -> Option<Ratio<i32>> {
+            self.get_tag_rational(tag)
+        }
+
+        fn tag_string(&self, tag: &str) -> Option<String> {
+            self.get_tag_string(tag).ok()
+        }
+
+        fn thumbnail(&self) -> Option<&[u8]> {
+            self.get_thumbnail()
+        }
+    }
+
+    impl From<rexiv2::Orientation> for Orientation {
+        fn from(orientation: rexiv2::Orientation) -> Self {
+            match orientation {
+                rexiv2::Orientation::Unspecified => Orientation::Unspecified,
+                rexiv2::Orientation::Normal => Orientation::Normal,
+                rexiv2::Orientation::HorizontalFlip => Orientation::HorizontalFlip,
+                rexiv2::Orientation::Rotate180 => Orientation::Rotate180,
+                rexiv2::Orientation::VerticalFlip => Orientation::VerticalFlip,
+                rexiv2::Orientation::Rotate90HorizontalFlip => Orientation::Rotate90HorizontalFlip,
+                rexiv2::Orientation::Rotate90 => Orientation::Rotate90,
+                rexiv2::Orientation::Rotate90VerticalFlip => Orientation::Rotate90VerticalFlip,
+                rexiv2::Orientation::Rotate270 => Orientation::Rotate270,
+            }
+        }
+    }
+
+    impl From<Orientation> for rexiv2::Orientation {
+        fn from(orientation: Orientation) -> Self {
+            match orientation {
+                Orientation::Unspecified => rexiv2::Orientation::Unspecified,
+                Orientation::Normal => rexiv2::Orientation::Normal,
+                Orientation::HorizontalFlip => rexiv2::Orientation::HorizontalFlip,
+                Orientation::Rotate180 => rexiv2::Orientation::Rotate180,
+                Orientation::VerticalFlip => rexiv2::Orientation::VerticalFlip,
+                Orientation::Rotate90HorizontalFlip => rexiv2::Orientation::Rotate90HorizontalFlip,
+                Orientation::Rotate90 => rexiv2::Orientation::Rotate90,
+                Orientation::Rotate90VerticalFlip => rexiv2::Orientation::Rotate90VerticalFlip,
+                Orientation::Rotate270 => rexiv2::Orientation::Rotate270,
+            }
+        }
+    }
+}
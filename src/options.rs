@@ -0,0 +1,503 @@
+use crate::FocalPoint;
+use snafu::ResultExt;
+
+/// Knobs for `process_photo`. `Default` gives the classic imgroll behavior.
+///
+/// Can be loaded from a config file, where missing fields keep their defaults (see `imgroll.example.toml`).
+/// Things that only make sense in code (callbacks, images, thread pools, per-file data) are not part of it.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProcessOptions {
+    /// Whether to list the original upload as an `original: true` source.
+    pub include_original: bool,
+    /// Whether to return the original upload (unchanged, under its file name) as one of the output files,
+    /// e.g. to upload everything in one pass.
+    pub include_original_file: bool,
+    /// Whether to report per-encoder timings in `Photo::timings`.
+    pub collect_timings: bool,
+    /// Which kind of srcset descriptors to generate renditions for.
+    pub descriptor_mode: DescriptorMode,
+    /// Art direction crops, each producing its own sources.
+    pub crops: Vec<CropSpec>,
+    /// Overrides the focal point read from `Exif.Photo.SubjectArea`.
+    pub focal_point: Option<FocalPoint>,
+    /// Contents of an `.xmp` sidecar file, merged over the embedded metadata.
+    #[serde(skip)]
+    pub xmp_sidecar: Option<Vec<u8>>,
+    /// Overlay composited onto every derived image (but not the tiny preview).
+    #[serde(skip)]
+    pub watermark: Option<Watermark>,
+    /// Name outputs after a hash of their own bytes, so identical renditions share a file.
+    pub content_addressed: bool,
+    /// How the input file name is turned into the part of the output names after the image's hash.
+    pub slug_style: SlugStyle,
+    /// Used (as is) when nothing of the file name survives slugging, e.g. for an all-emoji name,
+    /// instead of naming the outputs after the hash alone.
+    pub slug_placeholder: Option<String>,
+    /// Generate a fixed-size JPEG for OpenGraph/social cards.
+    pub social_preview: Option<SocialPreviewSpec>,
+    /// Used instead of the orientation from the metadata, for files with a wrong tag.
+    #[serde(skip)]
+    pub orientation_override: Option<crate::Orientation>,
+    /// Whether to rotate/flip according to the orientation at all.
+    pub apply_orientation: bool,
+    /// Also emit the palette as `#rrggbb` strings in `Photo::palette_hex`.
+    pub palette_hex: bool,
+    /// The palette is extracted from the image downscaled to fit into a square of this size,
+    /// which is much faster and gives about the same colors. 0 uses the full image.
+    pub palette_sample_size: u32,
+    /// Fail with `Error::RatingTooLow` before decoding anything when the rating (see `Photo::rating`) is below this,
+    /// e.g. 1 to reject unrated and rejected photos.
+    pub min_rating: Option<i32>,
+    /// Copy the EXIF and XMP of JPEG inputs into the WebPs (with the orientation reset when it's applied),
+    /// for consumers that read the metadata from the served files. Everything is stripped by default.
+    pub webp_metadata: bool,
+    /// Fail with `Error::TooManyPixels` when the image has more pixels than this, checked from the header
+    /// before decoding, e.g. against decompression bombs (a few KB of PNG can decode into gigabytes).
+    pub max_pixels: Option<u64>,
+    /// Make the tiny preview from the embedded EXIF thumbnail when it's usable, skipping a full-size resize.
+    pub preview_from_exif_thumbnail: bool,
+    /// Which parts of the pipeline to run.
+    pub stages: Stages,
+    /// Content hint for the WebP encoder, e.g. `Graph` for screenshots.
+    pub webp_image_hint: WebpImageHint,
+    /// Where to run the encoders. The output (the files, their order and the JSON) is the same in every mode,
+    /// `Sequential` also hands the files to `process_photo_streaming`'s sink in a fixed order.
+    pub parallelism: Parallelism,
+    /// Checked between the expensive steps, processing stops with `Error::Cancelled` once it's set.
+    #[serde(skip)]
+    pub cancel: Option<CancelToken>,
+    /// Write the tiny preview as a `.webp` file referenced by `Photo::tiny_preview_url`, instead of a data URI.
+    pub tiny_preview_file: bool,
+    /// Make the inlined tiny preview an SVG data URI with the WebP blurred by a filter, which scales up smoothly
+    /// (like Gatsby's placeholders). Doesn't apply to `tiny_preview_file`.
+    pub tiny_preview_svg: bool,
+    /// The tiny preview is downscaled to fit into a square of this size, e.g. 64 for a more detailed blur-up
+    /// or 32 for a smaller data URI.
+    pub tiny_preview_size: u32,
+    /// Receives `Progress` events, e.g. for a progress bar.
+    #[serde(skip)]
+    pub progress: Option<ProgressCallback>,
+    /// Lower the quality of lossy renditions that would be larger than this, instead of using a fixed quality.
+    pub size_budget: Option<SizeBudget>,
+    /// Filter for downscaling the smaller renditions (and crops), the main image always uses Lanczos3.
+    pub thumbnail_filter: ResizeFilter,
+    /// Downscale lossless sources (PNGs) and add lossy formats like for JPEGs, instead of only recompressing them.
+    pub reencode_lossless: bool,
+    /// Chroma subsampling of the JPEGs (with MozJPEG, the wasm encoder doesn't subsample).
+    pub jpeg_subsampling: ChromaSubsampling,
+    /// Fixed quality (0–100) for the JPEGs, instead of the default that goes down a bit for huge images.
+    pub jpeg_quality: Option<f32>,
+    /// Fixed quality (0–100) for the lossy WebPs.
+    pub webp_quality: Option<f32>,
+    /// Qualities of the lossy renditions by size, as `[width, quality]` pairs: each rendition gets the quality
+    /// of the smallest width it fits into (or of the largest width, when it's wider than all of them).
+    /// Takes precedence over `jpeg_quality` and `webp_quality`, for both formats.
+    pub quality_by_width: Option<Vec<(u32, f32)>>,
+    /// The main image is downscaled to fit into a square of this size (lossless sources are never resized).
+    pub max_dimension: u32,
+    /// Widths of the smaller renditions in `Width` mode, instead of 2000px and 1000px boxes for large images.
+    /// Widths that are not below the main image are dropped.
+    pub widths: Option<Vec<u32>>,
+    /// Only produce these formats (out of the ones that fit the input), e.g. to skip JPEGs.
+    /// The tiny preview is always WebP.
+    pub formats: Option<Vec<OutputFormat>>,
+    /// Make the WebPs lossless when the image has at most this many distinct colors, for flat graphics that were
+    /// saved as JPEG. Compression artifacts add colors around the edges, so leave some room.
+    pub webp_lossless_max_colors: Option<usize>,
+}
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        ProcessOptions {
+            include_original: true,
+            include_original_file: false,
+            collect_timings: false,
+            descriptor_mode: DescriptorMode::Width,
+            crops: vec![],
+            focal_point: None,
+            xmp_sidecar: None,
+            watermark: None,
+            content_addressed: false,
+            slug_style: SlugStyle::Ascii,
+            slug_placeholder: None,
+            social_preview: None,
+            orientation_override: None,
+            apply_orientation: true,
+            palette_hex: false,
+            palette_sample_size: 200,
+            min_rating: None,
+            webp_metadata: false,
+            max_pixels: None,
+            preview_from_exif_thumbnail: false,
+            stages: Stages::default(),
+            webp_image_hint: WebpImageHint::Default,
+            parallelism: Parallelism::Global,
+            cancel: None,
+            tiny_preview_file: false,
+            tiny_preview_svg: false,
+            tiny_preview_size: 48,
+            progress: None,
+            size_budget: None,
+            thumbnail_filter: ResizeFilter::Lanczos3,
+            reencode_lossless: false,
+            jpeg_subsampling: ChromaSubsampling::Yuv420,
+            jpeg_quality: None,
+            webp_quality: None,
+            quality_by_width: None,
+            max_dimension: 3000,
+            widths: None,
+            formats: None,
+            webp_lossless_max_colors: None,
+        }
+    }
+}
+
+impl ProcessOptions {
+    pub fn from_toml_str(config: &str) -> crate::Result<Self> {
+        serde_path_to_error::deserialize(&mut toml::Deserializer::new(config)).map_err(config_error)
+    }
+
+    pub fn from_json_str(config: &str) -> crate::Result<Self> {
+        serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(config)).map_err(config_error)
+    }
+
+    /// Reads a config file, JSON if the name ends in `.json` and TOML otherwise.
+    pub fn from_path(path: impl AsRef<std::path::Path>) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let config = std::fs::read_to_string(path).context(crate::ConfigRead { path })?;
+        if path.extension().map_or(false, |ext| ext == "json") {
+            Self::from_json_str(&config)
+        } else {
+            Self::from_toml_str(&config)
+        }
+    }
+
+    /// The config for the binaries: the file at `IMGROLL_CONFIG`, inline JSON in `IMGROLL_CONFIG_JSON`,
+    /// or the defaults.
+    pub fn from_env() -> crate::Result<Self> {
+        if let Some(path) = std::env::var_os("IMGROLL_CONFIG") {
+            return Self::from_path(path);
+        }
+        match std::env::var("IMGROLL_CONFIG_JSON") {
+            Ok(config) => Self::from_json_str(&config),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+fn config_error<E: std::fmt::Display>(err: serde_path_to_error::Error<E>) -> crate::Error {
+    crate::Error::Config {
+        field: err.path().to_string(),
+        message: err.inner().to_string(),
+    }
+}
+
+/// Content type hint for the lossy WebP encoder, see `WebPConfig.image_hint`.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebpImageHint {
+    Default,
+    /// Digital picture, like a portrait or an inner shot
+    Picture,
+    /// Outdoor photograph, with natural lighting
+    Photo,
+    /// Discrete tone image (graph, map-tile, screenshot etc.)
+    Graph,
+}
+
+impl Default for WebpImageHint {
+    fn default() -> Self {
+        WebpImageHint::Default
+    }
+}
+
+/// How file names are slugged for the output names, both lowercased with dashes between the words.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlugStyle {
+    /// Transliterated to ASCII with `slug::slugify`, safe for any URL or file system.
+    Ascii,
+    /// Keeping the letters and digits of every script, e.g. for Japanese names that don't transliterate well.
+    /// The URLs need percent-encoding then.
+    Unicode,
+}
+
+impl Default for SlugStyle {
+    fn default() -> Self {
+        SlugStyle::Ascii
+    }
+}
+
+/// Resampling filters, from fastest to sharpest.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for image::imageops::FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => image::imageops::FilterType::Nearest,
+            ResizeFilter::Triangle => image::imageops::FilterType::Triangle,
+            ResizeFilter::CatmullRom => image::imageops::FilterType::CatmullRom,
+            ResizeFilter::Gaussian => image::imageops::FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => image::imageops::FilterType::Lanczos3,
+        }
+    }
+}
+
+/// How much color resolution JPEGs keep. 4:2:0 is the smallest, 4:4:4 keeps saturated edges (e.g. red on black) sharp.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ChromaSubsampling {
+    #[serde(rename = "4:4:4")]
+    Yuv444,
+    /// Half the horizontal resolution.
+    #[serde(rename = "4:2:2")]
+    Yuv422,
+    /// Half the horizontal and vertical resolution.
+    #[serde(rename = "4:2:0")]
+    Yuv420,
+}
+
+/// Formats of the renditions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    Jpeg,
+    Webp,
+    Png,
+}
+
+/// A target file size, reached by re-encoding at a binary searched quality.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SizeBudget {
+    pub max_bytes: usize,
+    /// Stop searching once a result is within this fraction below `max_bytes`.
+    #[serde(default = "SizeBudget::default_tolerance")]
+    pub tolerance: f32,
+    /// Lowest quality to try, 0–100.
+    #[serde(default = "SizeBudget::default_min_quality")]
+    pub min_quality: f32,
+    /// Re-encodes after the first attempt at the default quality.
+    #[serde(default = "SizeBudget::default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+impl SizeBudget {
+    pub fn new(max_bytes: usize) -> Self {
+        SizeBudget {
+            max_bytes,
+            tolerance: Self::default_tolerance(),
+            min_quality: Self::default_min_quality(),
+            max_attempts: Self::default_max_attempts(),
+        }
+    }
+
+    fn default_tolerance() -> f32 {
+        0.1
+    }
+
+    fn default_min_quality() -> f32 {
+        10.0
+    }
+
+    fn default_max_attempts() -> u32 {
+        6
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Progress {
+    Decoding,
+    ExtractingPalette,
+    /// One rendition is done, out of `total` (across all formats, including the social preview).
+    Encoded {
+        format: &'static str,
+        width: u32,
+        completed: usize,
+        total: usize,
+    },
+    Done,
+}
+
+/// Called from whichever (rayon worker) thread is making progress.
+#[derive(Clone)]
+pub struct ProgressCallback(pub std::sync::Arc<dyn Fn(Progress) + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new(callback: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        ProgressCallback(std::sync::Arc::new(callback))
+    }
+}
+
+impl std::fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// A flag shared between the caller and `process_photo`, clone it to keep a handle.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(std::sync::Arc<std::sync::atomic::AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Parallelism {
+    /// One encoder after another on the calling thread, for the lowest peak memory.
+    Sequential,
+    /// rayon's global thread pool.
+    Global,
+    /// A new pool with this many threads, just for this call.
+    Threads(usize),
+    /// An existing pool, e.g. to share it with the rest of the application.
+    #[serde(skip)]
+    Pool(std::sync::Arc<rayon::ThreadPool>),
+}
+
+/// Parts of the pipeline that can be skipped. Skipped outputs are left out of `Photo` rather than faked.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Stages {
+    pub palette: bool,
+    pub tiny_preview: bool,
+    /// The full-size rendition (the 1x one in density mode), e.g. off for uploads that are already optimized.
+    pub main_reencode: bool,
+    /// The smaller renditions (the higher densities in density mode).
+    pub thumbnails: bool,
+    /// Listing the original in `Photo::source`, in addition to `ProcessOptions::include_original`.
+    pub original_source: bool,
+}
+
+impl Default for Stages {
+    fn default() -> Self {
+        Stages {
+            palette: true,
+            tiny_preview: true,
+            main_reencode: true,
+            thumbnails: true,
+            original_source: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DescriptorMode {
+    /// `w` descriptors: the main image plus smaller thumbnails.
+    Width,
+    /// `x` descriptors: 1x, 2x… up to `max_density` renditions of a fixed display width, never upscaled.
+    Density { base_width: u32, max_density: u32 },
+}
+
+impl DescriptorMode {
+    /// Just 1x and 2x renditions for the given display width.
+    pub fn retina(display_width: u32) -> Self {
+        DescriptorMode::Density {
+            base_width: display_width,
+            max_density: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CropSpec {
+    /// Aspect ratio as (width, height), e.g. (1, 1) for squares.
+    pub aspect: (u32, u32),
+    pub gravity: Gravity,
+    /// Widths to produce for this crop, never upscaled.
+    pub widths: Vec<u32>,
+    /// Media query for the `<source>` element, e.g. `(max-width: 600px)`.
+    #[serde(default)]
+    pub media: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Gravity {
+    Center,
+    /// Normalized coordinates (0.0–1.0) of the point to keep in frame.
+    FocalPoint {
+        x: f32,
+        y: f32,
+    },
+    /// The photo's focal point: `ProcessOptions::focal_point`, the EXIF subject area, or the center.
+    Subject,
+}
+
+#[derive(Debug, Clone)]
+pub struct Watermark {
+    pub overlay: image::DynamicImage,
+    pub corner: Corner,
+    pub margin: Margin,
+    /// Multiplied with the overlay's own alpha, 0.0–1.0.
+    pub opacity: f32,
+    /// Width of the overlay as a fraction of the target image width.
+    pub relative_width: f32,
+}
+
+impl Watermark {
+    /// Decodes the overlay image, with a bottom-right placement and half opacity.
+    pub fn from_bytes(overlay: &[u8]) -> crate::Result<Watermark> {
+        Ok(Watermark {
+            overlay: image::load_from_memory(overlay).map_err(|source| crate::Error::ImageProc { source })?,
+            corner: Corner::BottomRight,
+            margin: Margin::Percent(2.0),
+            opacity: 0.5,
+            relative_width: 0.2,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Margin {
+    Pixels(u32),
+    /// Percentage of the target image width.
+    Percent(f32),
+}
+
+/// Exact output dimensions of the social preview; the image is cropped to fit, never letterboxed.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocialPreviewSpec {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Default for SocialPreviewSpec {
+    fn default() -> Self {
+        SocialPreviewSpec {
+            width: 1200,
+            height: 630,
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! DCT-based perceptual hash, for spotting re-exports and resized copies of the same photo.
+
+use image::{imageops::FilterType, DynamicImage};
+use std::f64::consts::PI;
+
+const SIZE: usize = 32;
+const LOW: usize = 8;
+
+/// Hashes the low frequencies of a 32x32 grayscale downscale into 64 bits, hex encoded.
+pub fn phash(imag: &DynamicImage) -> String {
+    let small = imag
+        .resize_exact(SIZE as u32, SIZE as u32, FilterType::Triangle)
+        .to_luma8();
+    let pixels = small.as_raw();
+
+    let mut cos = [[0f64; SIZE]; LOW];
+    for (u, row) in cos.iter_mut().enumerate() {
+        for (x, c) in row.iter_mut().enumerate() {
+            *c = ((2 * x + 1) as f64 * u as f64 * PI / (2 * SIZE) as f64).cos();
+        }
+    }
+
+    let mut coeffs = Vec::with_capacity(LOW * LOW);
+    for cos_v in cos.iter() {
+        for cos_u in cos.iter() {
+            let mut sum = 0.0;
+            for (y, row) in pixels.chunks_exact(SIZE).enumerate() {
+                for (x, &p) in row.iter().enumerate() {
+                    sum += f64::from(p) * cos_u[x] * cos_v[y];
+                }
+            }
+            coeffs.push(sum);
+        }
+    }
+
+    // The DC term is just the average brightness, it would skew the median
+    let mut sorted = coeffs[1..].to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let median = sorted[sorted.len() / 2];
+
+    let hash = coeffs
+        .iter()
+        .enumerate()
+        .fold(0u64, |hash, (i, &c)| if c > median { hash | (1 << i) } else { hash });
+    format!("{:016x}", hash)
+}
@@ -0,0 +1,67 @@
+//! Python module, built with maturin (see `pyproject.toml`):
+//!
+//! ```python
+//! import imgroll
+//! photo, files = imgroll.process(data, "name.jpg", include_original=False)
+//! ```
+
+use crate::ProcessOptions;
+use pyo3::{
+    create_exception,
+    exceptions::PyException,
+    prelude::*,
+    types::{PyBytes, PyDict},
+};
+
+create_exception!(
+    imgroll,
+    Error,
+    PyException,
+    "Processing failed, the message is the imgroll error."
+);
+
+type Files = Vec<(String, Py<PyBytes>, String)>;
+
+/// process(data, name, **options)
+/// --
+///
+/// Processes an image. The keyword arguments are `ProcessOptions` config fields, like in
+/// `imgroll.example.toml`. Returns the photo as a dict (its JSON representation) and the files as
+/// a list of `(name, bytes, mimetype)` tuples. The GIL is released while processing.
+#[pyfunction]
+#[pyo3(signature = (data, name, **options))]
+fn process(
+    py: Python<'_>,
+    data: &[u8],
+    name: &str,
+    options: Option<&Bound<'_, PyDict>>,
+) -> PyResult<(PyObject, Files)> {
+    let json = py.import("json")?;
+    let opts = match options {
+        Some(options) => {
+            let config: String = json.call_method1("dumps", (options,))?.extract()?;
+            ProcessOptions::from_json_str(&config).map_err(to_py_err)?
+        },
+        None => ProcessOptions::default(),
+    };
+    let (photo, files) = py
+        .allow_threads(|| crate::process_photo(data, name, &opts))
+        .map_err(to_py_err)?;
+    let photo = json.call_method1("loads", (serde_json::to_string(&photo).map_err(to_py_err)?,))?;
+    let files = files
+        .into_iter()
+        .map(|file| (file.name, PyBytes::new(py, &file.bytes).unbind(), file.mimetype))
+        .collect();
+    Ok((photo.unbind(), files))
+}
+
+fn to_py_err(err: impl std::fmt::Display) -> PyErr {
+    Error::new_err(err.to_string())
+}
+
+#[pymodule]
+fn imgroll(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(process, m)?)?;
+    m.add("Error", m.py().get_type::<Error>())?;
+    Ok(())
+}
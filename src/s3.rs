@@ -0,0 +1,151 @@
+//! Uploading the output files to S3 (or a compatible service), shared by `imgroll-lambda`, `imgroll-serve`,
+//! `imgroll-batch` and `imgroll-local --s3`.
+
+use rusoto_core::{Region, RusotoError};
+use rusoto_s3::{PutObjectRequest, StreamingBody};
+use std::{collections::HashMap, fmt};
+
+/// A bucket and a key prefix, from an `s3://bucket/prefix` URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub bucket: String,
+    /// Without the slashes around it, empty for the root of the bucket.
+    pub prefix: String,
+}
+
+impl Location {
+    /// `None` when it's not an `s3://` URL with a bucket.
+    pub fn parse(url: &str) -> Option<Location> {
+        let path = url.strip_prefix("s3://")?;
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            return None;
+        }
+        Some(Location {
+            bucket: bucket.to_owned(),
+            prefix: prefix.trim_matches('/').to_owned(),
+        })
+    }
+
+    /// The key for an output file.
+    pub fn key(&self, name: &str) -> String {
+        if self.prefix.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}/{}", self.prefix, name)
+        }
+    }
+}
+
+/// Why the lambda function leaves an uploaded object alone, see `skip_reason`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Skip {
+    /// It's an output file, its `imgroll-original` metadata is the original's key.
+    Output { original: String },
+    /// It's a `<key>.imgroll-done` marker, even one written without the `imgroll-original` metadata.
+    IsMarker,
+    /// It's under the output prefix of its own bucket.
+    OutputPrefix,
+    /// Its `imgroll-processed` metadata is set, e.g. on an original restored from a backup.
+    Flagged,
+    /// Its `<key>.imgroll-done` marker exists.
+    Marker,
+}
+
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Skip::Output { original } => write!(f, "it's an output for '{}'", original),
+            Skip::IsMarker => write!(f, "it's a .imgroll-done marker"),
+            Skip::OutputPrefix => write!(f, "it's under the output prefix"),
+            Skip::Flagged => write!(f, "its imgroll-processed metadata is set"),
+            Skip::Marker => write!(f, "its .imgroll-done marker exists"),
+        }
+    }
+}
+
+/// Decides whether an uploaded object should be skipped instead of processed, from its user metadata,
+/// where the outputs go, and whether its done marker exists. Buckets notifying for all created objects
+/// would otherwise have the function process its own outputs, over and over.
+pub fn skip_reason(
+    bucket: &str,
+    key: &str,
+    meta: &HashMap<String, String>,
+    out: &Location,
+    marker_exists: bool,
+) -> Option<Skip> {
+    if let Some(original) = meta.get("imgroll-original") {
+        return Some(Skip::Output {
+            original: original.clone(),
+        });
+    }
+    if key.ends_with(".imgroll-done") {
+        return Some(Skip::IsMarker);
+    }
+    if out.bucket == bucket && !out.prefix.is_empty() && key.starts_with(&out.key("")) {
+        return Some(Skip::OutputPrefix);
+    }
+    if meta.get("imgroll-processed").map_or(false, |v| v != "false") {
+        return Some(Skip::Flagged);
+    }
+    if marker_exists {
+        return Some(Skip::Marker);
+    }
+    None
+}
+
+/// A custom endpoint (like `https://minio.local`) in the region from `AWS_REGION`, which is `us-east-1`
+/// by default since most S3-compatible services don't care, or AWS in the default region.
+pub fn region(endpoint: Option<String>) -> Region {
+    match endpoint {
+        Some(endpoint) => Region::Custom {
+            name: std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_owned()),
+            endpoint,
+        },
+        None => Region::default(),
+    }
+}
+
+/// The public URL of an object. Requests always use path-style addressing, but the URLs default to
+/// the bucket's own host (dualstack on AWS), `path_style` puts the bucket into the path instead.
+pub fn object_url(region: &Region, bucket: &str, key: &str, path_style: bool) -> String {
+    let (scheme, host) = match region {
+        Region::Custom { endpoint, .. } => {
+            let endpoint = endpoint.trim_end_matches('/');
+            // rusoto defaults to https for endpoints without a scheme too
+            let (scheme, host) = endpoint.split_once("://").unwrap_or(("https", endpoint));
+            (scheme, host.to_owned())
+        },
+        _ => ("https", format!("s3.dualstack.{}.amazonaws.com", region.name())),
+    };
+    if path_style {
+        format!("{}://{}/{}/{}", scheme, host, bucket, key)
+    } else {
+        format!("{}://{}.{}/{}", scheme, bucket, host, key)
+    }
+}
+
+/// Whether a failed request is worth retrying: connection errors, timeouts and 5xx responses (like S3's
+/// 503 Slow Down), but not 4xx ones or local errors, which would only fail again.
+pub fn is_transient<E>(err: &RusotoError<E>) -> bool {
+    match err {
+        RusotoError::HttpDispatch(_) => true,
+        RusotoError::Unknown(resp) => resp.status.is_server_error(),
+        _ => false,
+    }
+}
+
+/// The upload of an output file: displayed inline with its content type, and cached forever,
+/// since the file names change with the contents. Access control is up to the caller (or the bucket policy).
+pub fn put_request(bucket: &str, key: String, bytes: Vec<u8>, mimetype: String) -> PutObjectRequest {
+    PutObjectRequest {
+        bucket: bucket.to_owned(),
+        key,
+        content_length: Some(bytes.len() as i64),
+        content_type: Some(mimetype),
+        content_disposition: Some("inline".to_owned()),
+        cache_control: Some("public, max-age=31536000, immutable".to_owned()),
+        body: Some(StreamingBody::from(bytes)),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,61 @@
+use crate::{Corner, Margin, Watermark};
+use image::{DynamicImage, GenericImageView, Pixel};
+
+/// Composites the watermark onto a copy of the image, scaled relative to the image's width.
+pub fn apply(imag: &DynamicImage, wm: &Watermark) -> DynamicImage {
+    let (width, height) = imag.dimensions();
+    let target_width = ((width as f32 * wm.relative_width).round() as u32).max(1).min(width);
+    let overlay = wm
+        .overlay
+        .resize(target_width, height, image::imageops::FilterType::Lanczos3)
+        .to_rgba8();
+    let (ow, oh) = overlay.dimensions();
+    let margin = match wm.margin {
+        Margin::Pixels(px) => px,
+        Margin::Percent(pct) => (width as f32 * pct / 100.0).round() as u32,
+    };
+    let x = match wm.corner {
+        Corner::TopLeft | Corner::BottomLeft => margin,
+        Corner::TopRight | Corner::BottomRight => width.saturating_sub(ow).saturating_sub(margin),
+    }
+    .min(width - ow);
+    let y = match wm.corner {
+        Corner::TopLeft | Corner::TopRight => margin,
+        Corner::BottomLeft | Corner::BottomRight => height.saturating_sub(oh).saturating_sub(margin),
+    }
+    .min(height - oh);
+    let opacity = wm.opacity.max(0.0).min(1.0);
+
+    let mut result = imag.clone();
+    match &mut result {
+        DynamicImage::ImageRgb8(buf) => {
+            for (ox, oy, src) in overlay.enumerate_pixels() {
+                let alpha = src[3] as f32 / 255.0 * opacity;
+                let dst = buf.get_pixel_mut(x + ox, y + oy).channels_mut();
+                for (d, &s) in dst.iter_mut().zip(src.channels()) {
+                    *d = (s as f32 * alpha + *d as f32 * (1.0 - alpha)).round() as u8;
+                }
+            }
+        },
+        DynamicImage::ImageRgba8(buf) => {
+            // Porter-Duff "over" with straight (non-premultiplied) alpha
+            for (ox, oy, src) in overlay.enumerate_pixels() {
+                let src_alpha = src[3] as f32 / 255.0 * opacity;
+                let dst = buf.get_pixel_mut(x + ox, y + oy).channels_mut();
+                let dst_alpha = dst[3] as f32 / 255.0;
+                let out_alpha = src_alpha + dst_alpha * (1.0 - src_alpha);
+                if out_alpha <= 0.0 {
+                    continue;
+                }
+                for (d, &s) in dst.iter_mut().zip(src.channels()).take(3) {
+                    let blended = (s as f32 * src_alpha + *d as f32 * dst_alpha * (1.0 - src_alpha)) / out_alpha;
+                    *d = blended.round() as u8;
+                }
+                dst[3] = (out_alpha * 255.0).round() as u8;
+            }
+        },
+        // decode_image normalizes everything to RGB(A)
+        _ => (),
+    }
+    result
+}
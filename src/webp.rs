@@ -1,6 +1,12 @@
-use og_libwebp_sys::{WebPEncodeLosslessRGB, WebPEncodeLosslessRGBA, WebPEncodeRGB, WebPEncodeRGBA, WebPFree};
+use crate::WebpImageHint as ImageHint;
+use og_libwebp_sys::*;
 use snafu::{ResultExt, Snafu};
-use std::{convert::TryInto, ptr, slice};
+use std::{
+    convert::{TryFrom, TryInto},
+    mem,
+    os::raw::c_int,
+    ptr, slice,
+};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -10,8 +16,14 @@ pub enum Error {
     #[snafu(display("Could not fit size value into signed type: {}", source))]
     ConvertSigned { source: std::num::TryFromIntError },
 
+    #[snafu(display("Could not initialize the encoder, libwebp version mismatch?"))]
+    Init,
+
     #[snafu(display("Could not encode: {}", ret))]
     Encode { ret: usize },
+
+    #[snafu(display("Could not decode"))]
+    Decode,
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -35,44 +47,156 @@ impl Drop for WebPOinter {
     }
 }
 
+/// Decodes a WebP file into RGBA pixels.
+pub fn decode(data: &[u8]) -> Result<image::RgbaImage> {
+    let mut width: c_int = 0;
+    let mut height: c_int = 0;
+    let pixels = unsafe {
+        let ptr = WebPDecodeRGBA(data.as_ptr(), data.len(), &mut width, &mut height);
+        if ptr.is_null() {
+            return Err(Error::Decode);
+        }
+        WebPOinter {
+            ptr,
+            cnt: width as usize * height as usize * 4,
+        }
+    };
+    image::RgbaImage::from_raw(width as u32, height as u32, pixels.as_slice().to_vec()).ok_or(Error::Decode)
+}
+
+const ALPHA_FLAG: u8 = 0x10;
+const EXIF_FLAG: u8 = 0x08;
+const XMP_FLAG: u8 = 0x04;
+
+/// Rewrites an encoded WebP into the extended format with EXIF and/or XMP chunks, like libwebp's mux does
+/// (which would need libwebpmux built and linked too). `None` when it's not a WebP from the encoder.
+pub fn with_metadata(
+    webp: &[u8],
+    (width, height): (u32, u32),
+    exif: Option<&[u8]>,
+    xmp: Option<&[u8]>,
+) -> Option<Vec<u8>> {
+    if webp.get(0..4)? != b"RIFF" || webp.get(8..12)? != b"WEBP" || width == 0 || height == 0 {
+        return None;
+    }
+    let mut flags = 0;
+    let mut image_chunks = Vec::new();
+    let mut i = 12;
+    while i + 8 <= webp.len() {
+        let fourcc = &webp[i..i + 4];
+        let size = u32::from_le_bytes([webp[i + 4], webp[i + 5], webp[i + 6], webp[i + 7]]) as usize;
+        let payload = webp.get(i + 8..(i + 8).checked_add(size)?)?;
+        match fourcc {
+            b"VP8X" => flags = *payload.first()? & !(EXIF_FLAG | XMP_FLAG),
+            // The alpha_is_used bit of the lossless header, simple files have no flags for it
+            b"VP8L" if payload.get(4).map_or(false, |b| b & 0x10 != 0) => {
+                flags |= ALPHA_FLAG;
+                image_chunks.push((fourcc, payload));
+            },
+            _ => image_chunks.push((fourcc, payload)),
+        }
+        // Chunks are padded to an even size
+        i += 8 + size + (size & 1);
+    }
+    let mut vp8x = [0; 10];
+    vp8x[0] = flags | exif.map_or(0, |_| EXIF_FLAG) | xmp.map_or(0, |_| XMP_FLAG);
+    vp8x[4..7].copy_from_slice(&(width - 1).to_le_bytes()[..3]);
+    vp8x[7..10].copy_from_slice(&(height - 1).to_le_bytes()[..3]);
+
+    let mut result = Vec::with_capacity(webp.len() + 32 + exif.map_or(0, <[u8]>::len) + xmp.map_or(0, <[u8]>::len));
+    result.extend_from_slice(b"RIFF\0\0\0\0WEBP");
+    let chunks = std::iter::once((&b"VP8X"[..], &vp8x[..]))
+        .chain(image_chunks)
+        .chain(exif.map(|exif| (&b"EXIF"[..], exif)))
+        .chain(xmp.map(|xmp| (&b"XMP "[..], xmp)));
+    for (fourcc, payload) in chunks {
+        result.extend_from_slice(fourcc);
+        result.extend_from_slice(&u32::try_from(payload.len()).ok()?.to_le_bytes());
+        result.extend_from_slice(payload);
+        if payload.len() % 2 == 1 {
+            result.push(0);
+        }
+    }
+    let riff_size = u32::try_from(result.len() - 8).ok()?;
+    result[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(result)
+}
+
 pub enum Quality {
     Lossless,
     Lossy(f32),
 }
 
-pub fn encode(imag: image::DynamicImage, quality: Quality) -> Result<WebPOinter> {
+pub fn encode(imag: &image::DynamicImage, quality: Quality, hint: ImageHint) -> Result<WebPOinter> {
     use image::GenericImageView;
     use Quality::*;
-    let samp = match imag.color() {
-        image::ColorType::Rgb8 => imag.to_rgb8().into_flat_samples(),
-        image::ColorType::Rgba8 => imag.to_rgba8().into_flat_samples(),
+    let channels = match imag.color() {
+        image::ColorType::Rgb8 => 3,
+        image::ColorType::Rgba8 => 4,
         f => return Err(Error::UnsupportedColor { format: f }),
     };
     let (width, height) = imag.dimensions();
-    let (_, _, rowstride) = samp.strides_cwh();
-    let mut result = WebPOinter {
-        ptr: ptr::null_mut(),
-        cnt: 0,
-    };
+    // A DynamicImage always owns an ImageBuffer with packed rows (crops and rotations make new ones,
+    // views can't get here), so the pixels can be passed along without a copy
+    let rowstride = width as usize * channels;
+    let pixels = imag.as_bytes();
     let w = width.try_into().context(ConvertSigned {})?;
     let h = height.try_into().context(ConvertSigned {})?;
     let s = rowstride.try_into().context(ConvertSigned {})?;
-    let ret = unsafe {
-        match (imag.color(), quality) {
-            (image::ColorType::Rgb8, Lossy(q)) => WebPEncodeRGB(&samp.as_slice()[0], w, h, s, q, &mut result.ptr),
-            (image::ColorType::Rgba8, Lossy(q)) => WebPEncodeRGBA(&samp.as_slice()[0], w, h, s, q, &mut result.ptr),
-            (image::ColorType::Rgb8, Lossless) => {
-                WebPEncodeLosslessRGB(&samp.as_slice()[0], w, h, s, &mut result.ptr)
-            },
-            (image::ColorType::Rgba8, Lossless) => {
-                WebPEncodeLosslessRGBA(&samp.as_slice()[0], w, h, s, &mut result.ptr)
+    // Same setup as the one-shot WebPEncode* functions, which don't let us set the hint
+    let (quality_factor, lossless) = match quality {
+        Lossy(q) => (q, false),
+        Lossless => (70.0, true),
+    };
+    unsafe {
+        let mut config: WebPConfig = mem::zeroed();
+        if WebPConfigInitInternal(
+            &mut config,
+            WebPPreset_WEBP_PRESET_DEFAULT,
+            quality_factor,
+            WEBP_ENCODER_ABI_VERSION as c_int,
+        ) == 0
+        {
+            return Err(Error::Init);
+        }
+        config.lossless = lossless as c_int;
+        config.image_hint = match hint {
+            ImageHint::Default => WebPImageHint_WEBP_HINT_DEFAULT,
+            ImageHint::Picture => WebPImageHint_WEBP_HINT_PICTURE,
+            ImageHint::Photo => WebPImageHint_WEBP_HINT_PHOTO,
+            ImageHint::Graph => WebPImageHint_WEBP_HINT_GRAPH,
+        };
+        let mut pic: WebPPicture = mem::zeroed();
+        if WebPPictureInitInternal(&mut pic, WEBP_ENCODER_ABI_VERSION as c_int) == 0 {
+            return Err(Error::Init);
+        }
+        pic.use_argb = lossless as c_int;
+        pic.width = w;
+        pic.height = h;
+        let mut writer: WebPMemoryWriter = mem::zeroed();
+        WebPMemoryWriterInit(&mut writer);
+        pic.writer = Some(WebPMemoryWrite);
+        pic.custom_ptr = &mut writer as *mut WebPMemoryWriter as *mut _;
+        let imported = match imag.color() {
+            image::ColorType::Rgb8 => WebPPictureImportRGB(&mut pic, pixels.as_ptr(), s),
+            image::ColorType::Rgba8 => WebPPictureImportRGBA(&mut pic, pixels.as_ptr(), s),
+            f => {
+                WebPPictureFree(&mut pic);
+                return Err(Error::UnsupportedColor { format: f });
             },
-            (f, _) => return Err(Error::UnsupportedColor { format: f }),
+        };
+        let ok = imported != 0 && WebPEncode(&config, &mut pic) != 0;
+        let error_code = pic.error_code;
+        WebPPictureFree(&mut pic);
+        if !ok || writer.mem == ptr::null_mut() {
+            WebPMemoryWriterClear(&mut writer);
+            return Err(Error::Encode {
+                ret: error_code as usize,
+            });
         }
-    };
-    if ret < 1 || result.ptr == ptr::null_mut() {
-        return Err(Error::Encode { ret });
+        Ok(WebPOinter {
+            ptr: writer.mem,
+            cnt: writer.size,
+        })
     }
-    result.cnt = ret;
-    Ok(result)
 }
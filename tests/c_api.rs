@@ -0,0 +1,101 @@
+#![cfg(all(feature = "ffi", feature = "jpeg"))]
+
+use imgroll::ffi::*;
+use std::{
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_void},
+    ptr,
+};
+
+const LANDSCAPE: &[u8] = include_bytes!("fixtures/landscape.jpg");
+
+unsafe extern "C" fn collect(
+    ctx: *mut c_void,
+    name: *const c_char,
+    _mimetype: *const c_char,
+    data: *const u8,
+    len: usize,
+) -> i32 {
+    let files = &mut *(ctx as *mut Vec<(String, usize)>);
+    assert!(!data.is_null());
+    files.push((CStr::from_ptr(name).to_str().unwrap().to_owned(), len));
+    0
+}
+
+unsafe extern "C" fn refuse(_: *mut c_void, _: *const c_char, _: *const c_char, _: *const u8, _: usize) -> i32 {
+    1
+}
+
+#[test]
+fn process_through_c_api() {
+    let name = CString::new("landscape.jpg").unwrap();
+    let mut files: Vec<(String, usize)> = vec![];
+    let mut json = ptr::null_mut();
+    let ret = unsafe {
+        imgroll_process(
+            LANDSCAPE.as_ptr(),
+            LANDSCAPE.len(),
+            name.as_ptr(),
+            ptr::null(),
+            Some(collect),
+            &mut files as *mut _ as *mut c_void,
+            &mut json,
+            ptr::null_mut(),
+        )
+    };
+    assert_eq!(ret, IMGROLL_OK);
+    let photo: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(json) }.to_str().unwrap()).unwrap();
+    unsafe { imgroll_free(json) };
+    assert_eq!(photo["width"], 320);
+    assert_eq!(files.len(), 2);
+    assert!(files.iter().all(|(_, len)| *len > 0));
+}
+
+#[test]
+fn errors_through_c_api() {
+    let name = CString::new("landscape.jpg").unwrap();
+    let options = CString::new(r#"{"parallelism": "sequential"}"#).unwrap();
+    let mut error = ptr::null_mut();
+    let ret = unsafe {
+        imgroll_process(
+            LANDSCAPE.as_ptr(),
+            LANDSCAPE.len(),
+            name.as_ptr(),
+            options.as_ptr(),
+            Some(refuse),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut error,
+        )
+    };
+    assert_eq!(ret, IMGROLL_ERROR);
+    let message = unsafe { CStr::from_ptr(error) }.to_str().unwrap().to_owned();
+    unsafe { imgroll_free(error) };
+    assert!(message.contains("write callback returned 1"), "{}", message);
+}
+
+#[test]
+fn process_all_through_c_api() {
+    let name = CString::new("landscape.jpg").unwrap();
+    unsafe {
+        let result = imgroll_process_all(LANDSCAPE.as_ptr(), LANDSCAPE.len(), name.as_ptr(), ptr::null());
+        assert_eq!((*result).code, IMGROLL_OK);
+        assert!((*result).error.is_null());
+        let photo: serde_json::Value = serde_json::from_str(CStr::from_ptr((*result).json).to_str().unwrap()).unwrap();
+        assert_eq!(photo["width"], 320);
+        let files = std::slice::from_raw_parts((*result).files, (*result).files_len);
+        assert_eq!(files.len(), 2);
+        for file in files {
+            let mimetype = CStr::from_ptr(file.mimetype).to_str().unwrap();
+            assert!(mimetype == "image/jpeg" || mimetype == "image/webp");
+            assert!(file.len > 0);
+        }
+        imgroll_result_free(result);
+
+        let result = imgroll_process_all(b"nope".as_ptr(), 4, name.as_ptr(), ptr::null());
+        assert_eq!((*result).code, IMGROLL_ERROR);
+        assert!((*result).json.is_null() && (*result).files.is_null());
+        assert!(!CStr::from_ptr((*result).error).to_bytes().is_empty());
+        imgroll_result_free(result);
+    }
+}
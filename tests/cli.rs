@@ -0,0 +1,395 @@
+//! Runs the imgroll-local binary.
+#![cfg(all(
+    feature = "local",
+    feature = "exiv2",
+    feature = "jpeg",
+    feature = "webp",
+    feature = "png"
+))]
+
+use assert_cmd::Command;
+use std::{fs, path::PathBuf};
+
+fn fixture(name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", name].iter().collect()
+}
+
+/// A fresh directory for one test's outputs.
+fn out_dir(test: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(test);
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn help_lists_the_flags() {
+    let output = Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .arg("--help")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let help = String::from_utf8(output.stdout).unwrap();
+    for flag in &["--jpeg-quality", "--widths", "--formats", "--config", "--json-out"] {
+        assert!(help.contains(flag), "{} is missing from:\n{}", flag, help);
+    }
+}
+
+#[test]
+fn flags_become_options() {
+    let dir = out_dir("flags_become_options");
+    let output = Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .arg(fixture("landscape.jpg"))
+        .args(&["--widths", "100,200", "--formats", "webp", "--no-original", "--out-dir"])
+        .arg(&dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let photo: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let sources = photo["source"].as_array().unwrap();
+    assert_eq!(sources.len(), 1);
+    assert_eq!(sources[0]["type"], "image/webp");
+    let widths = sources[0]["srcset"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["width"].as_u64().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(widths, vec![320, 200, 100]);
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+}
+
+#[test]
+fn stdin_and_config_file() {
+    let dir = out_dir("stdin_and_config_file");
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("imgroll.toml");
+    fs::write(&config, "max_dimension = 160\nformats = [\"jpeg\"]\n").unwrap();
+    Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .arg("--out-dir")
+        .arg(&dir)
+        .args(&["--json-out", "-"])
+        .write_stdin(fs::read(fixture("landscape.jpg")).unwrap())
+        .assert()
+        .success();
+    let photo: serde_json::Value = serde_json::from_slice(&fs::read(dir.join("stdin.json")).unwrap()).unwrap();
+    assert_eq!(photo["served_width"], 160);
+    assert!(fs::read_dir(&dir)
+        .unwrap()
+        .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+        .any(|name| name.ends_with(".160.jpg")));
+
+    Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .arg(fixture("landscape.jpg"))
+        .args(&["--formats", "gif"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn recursive_and_glob_inputs() {
+    let dir = out_dir("recursive_and_glob_inputs");
+    let photos = dir.join("photos");
+    fs::create_dir_all(photos.join("trip")).unwrap();
+    fs::copy(fixture("landscape.jpg"), photos.join("a.JPG")).unwrap();
+    fs::copy(fixture("four-colors.png"), photos.join("trip").join("b.png")).unwrap();
+    fs::write(photos.join("trip").join("broken.jpg"), b"not a jpeg").unwrap();
+    fs::write(photos.join("notes.txt"), b"skipped").unwrap();
+
+    let out = dir.join("out");
+    let output = Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .args(&["--recursive", "--formats", "jpeg,png", "--out-dir"])
+        .arg(&out)
+        .arg(&photos)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("broken.jpg") && stderr.contains("1 of 3 inputs failed"),
+        "{}",
+        stderr
+    );
+    let names = |dir: PathBuf| {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+            .collect::<Vec<_>>()
+    };
+    assert!(names(out.clone()).iter().any(|name| name.ends_with(".320.jpg")));
+    assert!(names(out.join("trip")).iter().any(|name| name.ends_with(".png")));
+
+    Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .arg("--out-dir")
+        .arg(dir.join("globbed"))
+        .arg(photos.join("**").join("*.png"))
+        .assert()
+        .success();
+    assert!(names(dir.join("globbed").join("trip"))
+        .iter()
+        .any(|name| name.ends_with(".png")));
+
+    // Only a run where everything failed is an error, unless --fail-fast
+    Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .arg(photos.join("trip").join("broken.jpg"))
+        .assert()
+        .failure();
+    Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .args(&["--fail-fast", "--out-dir"])
+        .arg(&out)
+        .arg(photos.join("trip").join("broken.jpg"))
+        .arg(photos.join("a.JPG"))
+        .assert()
+        .failure();
+}
+
+#[test]
+fn parallel_jobs() {
+    let dir = out_dir("parallel_jobs");
+    let photos = dir.join("photos");
+    fs::create_dir_all(&photos).unwrap();
+    for i in 0..4 {
+        fs::copy(fixture("landscape.jpg"), photos.join(format!("{}.jpg", i))).unwrap();
+    }
+    let out = dir.join("out");
+    Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .args(&["--recursive", "--jobs", "2", "--json-out", "--out-dir"])
+        .arg(&out)
+        .arg(&photos)
+        .assert()
+        .success();
+    for i in 0..4 {
+        let json = fs::read(out.join(format!("{}.json", i))).unwrap();
+        let photo: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(photo["width"], 320);
+    }
+}
+
+#[test]
+fn watch_mode() {
+    let dir = out_dir("watch_mode");
+    let photos = dir.join("photos");
+    fs::create_dir_all(&photos).unwrap();
+    fs::copy(fixture("landscape.jpg"), photos.join("before.jpg")).unwrap();
+    let out = dir.join("out");
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("imgroll-local"))
+        .arg("--watch")
+        .arg(&photos)
+        .arg("--out-dir")
+        .arg(&out)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let wait_for = |path: PathBuf| {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+        while !path.exists() && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        path.exists()
+    };
+    // The startup scan, then a file added while watching
+    let scanned = wait_for(out.join("before.json"));
+    fs::create_dir_all(photos.join("new")).unwrap();
+    fs::copy(fixture("landscape.jpg"), photos.join("new").join("after.jpg")).unwrap();
+    let watched = wait_for(out.join("new").join("after.json"));
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert!(scanned && watched);
+}
+
+#[test]
+fn force_and_dry_run() {
+    let dir = out_dir("force_and_dry_run");
+    let run = |extra: &[&str]| {
+        Command::cargo_bin("imgroll-local")
+            .unwrap()
+            .arg(fixture("landscape.jpg"))
+            .arg("--out-dir")
+            .arg(&dir)
+            .args(extra)
+            .output()
+            .unwrap()
+    };
+    assert!(run(&[]).status.success());
+    let again = run(&[]);
+    assert!(!again.status.success());
+    let stderr = String::from_utf8_lossy(&again.stderr);
+    assert!(stderr.contains("exists, use --force"), "{}", stderr);
+    assert!(run(&["--force"]).status.success());
+
+    let planned = dir.join("planned");
+    let output = Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .arg(fixture("landscape.jpg"))
+        .args(&["--dry-run", "--json-out", "--out-dir"])
+        .arg(&planned)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(!planned.exists());
+    let photo: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(photo["width"], 320);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr
+            .lines()
+            .any(|line| line.contains(&*planned.to_string_lossy()) && line.ends_with(".320.jpg")),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn stdin_name_and_base_url() {
+    let dir = out_dir("stdin_name_and_base_url");
+    let output = Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .args(&[
+            "-",
+            "--name",
+            "Holiday.jpg",
+            "--base-url",
+            "https://cdn.example.com/photos/",
+            "--out-dir",
+        ])
+        .arg(&dir)
+        .write_stdin(fs::read(fixture("landscape.jpg")).unwrap())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let photo: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let srcs = photo["source"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .flat_map(|source| source["srcset"].as_array().unwrap())
+        .map(|entry| entry["src"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert!(
+        srcs.contains(&"https://cdn.example.com/photos/Holiday.jpg"),
+        "{:?}",
+        srcs
+    );
+    assert!(srcs
+        .iter()
+        .all(|src| src.starts_with("https://cdn.example.com/photos/") && src.contains("holiday")));
+
+    Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .arg(fixture("landscape.jpg"))
+        .args(&["--name", "other.jpg"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn html_preview() {
+    let dir = out_dir("html_preview");
+    Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .arg(fixture("landscape.jpg"))
+        .args(&["--html", "--out-dir"])
+        .arg(&dir)
+        .assert()
+        .success();
+    let page = fs::read_to_string(dir.join("preview.html")).unwrap();
+    assert_eq!(page.matches("<picture>").count(), 1);
+    assert!(page.contains(r#"value="image/webp""#) && page.contains(r#"value="image/jpeg""#));
+    assert!(page.contains("KiB"));
+    // The page is next to the files it shows
+    let src = page.split(r#"<img data-type="image/webp" src=""#).nth(1).unwrap();
+    assert!(dir.join(src.split('"').next().unwrap()).is_file());
+}
+
+#[test]
+fn verify_outputs() {
+    let dir = out_dir("verify_outputs");
+    Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .arg(fixture("landscape.jpg"))
+        .args(&["--json-out", "--out-dir"])
+        .arg(&dir)
+        .assert()
+        .success();
+    Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .arg("--verify")
+        .arg(&dir)
+        .assert()
+        .success();
+
+    let webp = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().map_or(false, |ext| ext == "webp"))
+        .unwrap();
+    let contents = fs::read(&webp).unwrap();
+    fs::write(&webp, &contents[..contents.len() / 2]).unwrap();
+    let output = Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .arg("--verify")
+        .arg(dir.join("landscape.json"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&*webp.file_name().unwrap().to_string_lossy()) && stderr.contains("1 of "),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn files_from_list() {
+    let dir = out_dir("files_from_list");
+    let photos = dir.join("photos");
+    fs::create_dir_all(&photos).unwrap();
+    let mut list = Vec::new();
+    for name in &["a.jpg", "b.jpg"] {
+        fs::copy(fixture("landscape.jpg"), photos.join(name)).unwrap();
+        list.extend_from_slice(photos.join(name).to_string_lossy().as_bytes());
+        list.push(0);
+    }
+    let out = dir.join("out");
+    Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .args(&["--files-from", "-", "-0", "--jobs", "2", "--json-out", "--out-dir"])
+        .arg(&out)
+        .write_stdin(list)
+        .assert()
+        .success();
+    assert!(out.join("a.json").is_file() && out.join("b.json").is_file());
+
+    let newline_list = dir.join("list.txt");
+    fs::write(
+        &newline_list,
+        format!("{}\n\nmissing.jpg\n", photos.join("a.jpg").display()),
+    )
+    .unwrap();
+    let output = Command::cargo_bin("imgroll-local")
+        .unwrap()
+        .arg("--files-from")
+        .arg(&newline_list)
+        .arg("--dry-run")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("missing.jpg: No such file") && stderr.contains("1 of 2 inputs failed"),
+        "{}",
+        stderr
+    );
+}
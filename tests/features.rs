@@ -0,0 +1,38 @@
+//! Builds with only some of the encoders, run with `cargo test --no-default-features --features webp`.
+#![cfg(all(feature = "webp", not(any(feature = "jpeg", feature = "png", feature = "wasm"))))]
+
+use imgroll::ProcessOptions;
+
+const LANDSCAPE: &[u8] = include_bytes!("fixtures/landscape.jpg");
+const FOUR_COLORS: &[u8] = include_bytes!("fixtures/four-colors.png");
+
+#[test]
+fn jpeg_with_only_webp() {
+    let opts = ProcessOptions {
+        social_preview: Some(Default::default()),
+        ..Default::default()
+    };
+    let (photo, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+    assert_eq!((photo.width, photo.height), (320, 240));
+    assert!(!files.is_empty());
+    assert!(files.iter().all(|f| f.mimetype == "image/webp"));
+    assert!(photo.social_preview.unwrap().src.ends_with(".webp"));
+    assert!(photo.tiny_preview.is_some());
+}
+
+#[test]
+fn supported_formats_with_only_webp() {
+    assert_eq!(imgroll::supported_input_formats(), ["image/jpeg"]);
+    assert_eq!(imgroll::supported_output_formats(), ["image/webp"]);
+}
+
+#[test]
+fn png_without_png_encoder() {
+    match imgroll::process_photo(FOUR_COLORS, "four-colors.png", &Default::default()) {
+        Err(imgroll::Error::NoEncoder {
+            format: image::ImageFormat::Png,
+        }) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("processed without an encoder"),
+    }
+}
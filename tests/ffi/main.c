@@ -0,0 +1,91 @@
+/*
+ * Links against the C API and processes an image, writing nothing but checking what comes out.
+ *
+ *   cargo rustc --release --lib --crate-type cdylib --features ffi
+ *   cc -Iinclude tests/ffi/main.c -Ltarget/release -limgroll -o target/ffi-test
+ *   LD_LIBRARY_PATH=target/release target/ffi-test tests/fixtures/landscape.jpg
+ */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "imgroll.h"
+
+struct outputs {
+  int count;
+  size_t bytes;
+};
+
+static int32_t count_output(void *ctx, const char *name, const char *mimetype, const uint8_t *data, size_t len) {
+  struct outputs *outputs = ctx;
+  if (len == 0 || data == NULL) {
+    return 1;
+  }
+  fprintf(stderr, "%s (%s): %zu bytes\n", name, mimetype, len);
+  outputs->count++;
+  outputs->bytes += len;
+  return 0;
+}
+
+static unsigned char *read_file(const char *path, size_t *len) {
+  FILE *file = fopen(path, "rb");
+  if (file == NULL) {
+    return NULL;
+  }
+  fseek(file, 0, SEEK_END);
+  *len = (size_t)ftell(file);
+  fseek(file, 0, SEEK_SET);
+  unsigned char *data = malloc(*len);
+  if (data != NULL && fread(data, 1, *len, file) != *len) {
+    free(data);
+    data = NULL;
+  }
+  fclose(file);
+  return data;
+}
+
+int main(int argc, char **argv) {
+  if (argc != 2) {
+    fprintf(stderr, "usage: %s <image>\n", argv[0]);
+    return 2;
+  }
+  size_t len;
+  unsigned char *data = read_file(argv[1], &len);
+  if (data == NULL) {
+    perror(argv[1]);
+    return 2;
+  }
+
+  struct outputs outputs = {0, 0};
+  char *json = NULL;
+  char *error = NULL;
+  int32_t ret = imgroll_process(data, len, argv[1], "{\"parallelism\": \"sequential\"}", count_output, &outputs,
+                                &json, &error);
+  if (ret != IMGROLL_OK) {
+    fprintf(stderr, "imgroll_process failed (%d): %s\n", ret, error);
+    imgroll_free(error);
+    free(data);
+    return 1;
+  }
+
+  printf("%s\n", json);
+  int ok = outputs.count > 0 && strstr(json, "\"source\"") != NULL;
+  imgroll_free(json);
+
+  /* The same files, all at once */
+  ImgrollResult *result = imgroll_process_all(data, len, argv[1], NULL);
+  free(data);
+  ok = ok && result->code == IMGROLL_OK && result->files_len == (size_t)outputs.count;
+  for (size_t i = 0; ok && i < result->files_len; i++) {
+    ok = result->files[i].len > 0 && result->files[i].data != NULL;
+  }
+  imgroll_result_free(result);
+
+  /* Errors come back as messages */
+  ret = imgroll_process((const uint8_t *)"nope", 4, "nope.jpg", NULL, NULL, NULL, NULL, &error);
+  ok = ok && ret == IMGROLL_ERROR && error != NULL;
+  imgroll_free(error);
+
+  fprintf(stderr, "%d files, %zu bytes\n", outputs.count, outputs.bytes);
+  return ok ? 0 : 1;
+}
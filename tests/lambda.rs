@@ -0,0 +1,227 @@
+//! Runs imgroll-lambda against a fake Lambda runtime API.
+#![cfg(feature = "lambda")]
+
+use std::{
+    io,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Reads an HTTP request, giving the request line and the body.
+fn read_request(reader: &mut BufReader<TcpStream>) -> Option<(String, Vec<u8>)> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).ok()? == 0 {
+        return None;
+    }
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().ok()?;
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    Some((request_line.trim_end().to_owned(), body))
+}
+
+fn respond(stream: &mut TcpStream, headers: &[(&str, String)], body: &str) {
+    let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n", body.len());
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    stream.write_all(response.as_bytes()).unwrap();
+}
+
+/// Runs the function with each event in turn, giving the response (or error) requests and the logs.
+fn invoke(events: &[&str]) -> (Vec<(String, String)>, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    // Polled, so that the test fails instead of hanging when the function exits
+    listener.set_nonblocking(true).unwrap();
+    let mut child = Command::new(assert_cmd::cargo::cargo_bin("imgroll-lambda"))
+        .env("AWS_LAMBDA_RUNTIME_API", listener.local_addr().unwrap().to_string())
+        .env("AWS_LAMBDA_FUNCTION_NAME", "imgroll")
+        .env("AWS_LAMBDA_FUNCTION_MEMORY_SIZE", "1024")
+        .env("AWS_LAMBDA_FUNCTION_VERSION", "$LATEST")
+        .env("AWS_LAMBDA_LOG_STREAM_NAME", "test")
+        .env("AWS_LAMBDA_LOG_GROUP_NAME", "test")
+        .env("LOG_FORMAT", "json")
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let deadline = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() + 60_000;
+
+    // All in the same process, like on a warm container
+    let mut invocations = 0;
+    let mut results = Vec::new();
+    'serve: loop {
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                assert_eq!(child.try_wait().unwrap(), None, "imgroll-lambda exited");
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            },
+            Err(e) => panic!("{}", e),
+        };
+        stream.set_nonblocking(false).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        while let Some((request_line, body)) = read_request(&mut reader) {
+            if request_line.contains("/invocation/next") {
+                let event = match events.get(invocations) {
+                    Some(event) => event,
+                    None => break 'serve,
+                };
+                invocations += 1;
+                let headers = [
+                    ("Lambda-Runtime-Aws-Request-Id", format!("request-{}", invocations)),
+                    ("Lambda-Runtime-Deadline-Ms", deadline.to_string()),
+                    (
+                        "Lambda-Runtime-Invoked-Function-Arn",
+                        "arn:aws:lambda:us-east-1:0:function:imgroll".to_owned(),
+                    ),
+                    ("Lambda-Runtime-Trace-Id", "Root=1-0-0".to_owned()),
+                ];
+                respond(&mut stream, &headers, event);
+            } else {
+                results.push((request_line, String::from_utf8_lossy(&body).into_owned()));
+                respond(&mut stream, &[], "");
+            }
+        }
+    }
+    child.kill().unwrap();
+    let output = child.wait_with_output().unwrap();
+    (results, String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn warm_invocations() {
+    let (results, logs) = invoke(&[r#"{"Records":[]}"#, r#"{"Records":[]}"#]);
+    assert_eq!(results.len(), 2, "{:?}", results);
+    for (i, (request_line, body)) in results.iter().enumerate() {
+        assert!(
+            request_line.contains(&format!("/invocation/request-{}/response", i + 1)),
+            "{}: {}",
+            request_line,
+            body
+        );
+    }
+    let request_ids = logs
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter_map(|line| line["request_id"].as_str().map(str::to_owned))
+        .collect::<Vec<_>>();
+    assert!(request_ids.contains(&"request-2".to_owned()), "{}", logs);
+}
+
+#[test]
+fn warmup_event() {
+    let (results, logs) = invoke(&[r#"{"warmup":true}"#]);
+    assert_eq!(results.len(), 1, "{}", logs);
+    let (request_line, body) = &results[0];
+    assert!(
+        request_line.contains("/invocation/request-1/response"),
+        "{}: {}",
+        request_line,
+        body
+    );
+    let response = serde_json::from_str::<serde_json::Value>(body).unwrap();
+    assert_eq!(response["warmup"], true);
+    assert!(response["millis"].is_u64());
+}
+
+#[test]
+fn s3_and_sns_events() {
+    // The region doesn't exist, so the record fails without going anywhere
+    let (results, logs) = invoke(&[
+        include_str!("fixtures/s3-event.json"),
+        include_str!("fixtures/sns-event.json"),
+    ]);
+    assert_eq!(results.len(), 2, "{}", logs);
+    for (i, (request_line, body)) in results.iter().enumerate() {
+        assert!(
+            request_line.contains(&format!("/invocation/request-{}/error", i + 1)),
+            "{}: {}",
+            request_line,
+            body
+        );
+        assert!(body.contains("landscape.jpg"), "{}", body);
+    }
+}
+
+#[test]
+fn encoded_keys() {
+    let (results, logs) = invoke(&[include_str!("fixtures/s3-event-encoded-key.json")]);
+    assert_eq!(results.len(), 1, "{}", logs);
+    let (request_line, body) = &results[0];
+    assert!(
+        request_line.contains("/invocation/request-1/error"),
+        "{}: {}",
+        request_line,
+        body
+    );
+    assert!(body.contains("summer photos/landscape (1).jpg"), "{}", body);
+}
+
+#[test]
+fn sqs_batch_item_failures() {
+    let (results, logs) = invoke(&[include_str!("fixtures/sqs-event.json")]);
+    assert_eq!(results.len(), 1, "{}", logs);
+    let (request_line, body) = &results[0];
+    assert!(
+        request_line.contains("/invocation/request-1/response"),
+        "{}: {}",
+        request_line,
+        body
+    );
+    // Only the messages with the failed records, not the S3 test event
+    let response = serde_json::from_str::<serde_json::Value>(body).unwrap();
+    // In the order they finished
+    let mut failures = response["batchItemFailures"].as_array().unwrap().clone();
+    failures.sort_by_key(|failure| failure["itemIdentifier"].as_str().map(str::to_owned));
+    assert_eq!(
+        failures,
+        vec![
+            serde_json::json!({ "itemIdentifier": "059f36b4-87a3-44ab-83d2-661975830a7d" }),
+            serde_json::json!({ "itemIdentifier": "d9a1c4a2-3b6f-4e0a-9c55-6f1e2b7a8c90" }),
+        ]
+    );
+    // The record in the SNS notification got as far as processing
+    assert!(logs.contains("Unable to process 'fanned-out.jpg'"), "{}", logs);
+}
+
+#[test]
+fn eventbridge_events() {
+    let created = include_str!("fixtures/eventbridge-event.json");
+    let deleted = created.replace("Object Created", "Object Deleted");
+    let (results, logs) = invoke(&[created, &deleted]);
+    assert_eq!(results.len(), 2, "{}", logs);
+    let (request_line, body) = &results[0];
+    assert!(
+        request_line.contains("/invocation/request-1/error"),
+        "{}: {}",
+        request_line,
+        body
+    );
+    assert!(body.contains("landscape.jpg"), "{}", body);
+    let (request_line, body) = &results[1];
+    assert!(
+        request_line.contains("/invocation/request-2/response"),
+        "{}: {}",
+        request_line,
+        body
+    );
+}
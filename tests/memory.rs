@@ -0,0 +1,44 @@
+#![cfg(all(target_os = "linux", any(feature = "jpeg", feature = "webp", feature = "wasm")))]
+
+use imgroll::{Parallelism, ProcessOptions};
+
+fn peak_rss_bytes() -> u64 {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    let line = status.lines().find(|l| l.starts_with("VmHWM:")).unwrap();
+    line.split_whitespace().nth(1).unwrap().parse::<u64>().unwrap() * 1024
+}
+
+/// Slow (encodes a 45 megapixel JPEG just to have an input), run with `cargo test -- --ignored`.
+#[test]
+#[ignore]
+fn peak_memory_45mp() {
+    let (width, height) = (8192, 5504);
+    let raw_size = u64::from(width) * u64::from(height) * 3;
+    let jpeg = {
+        let imag = image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x % 251) as u8, (y % 241) as u8, ((x ^ y) % 256) as u8])
+        });
+        let mut jpeg = vec![];
+        image::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 80)
+            .encode_image(&imag)
+            .unwrap();
+        jpeg
+    };
+    // Reset the high water mark, so that making the input doesn't count
+    std::fs::write("/proc/self/clear_refs", "5").unwrap();
+
+    let opts = ProcessOptions {
+        parallelism: Parallelism::Sequential,
+        ..Default::default()
+    };
+    let (photo, _) = imgroll::process_photo_owned(jpeg, "big.jpg", &opts).unwrap();
+    assert_eq!(photo.width, width);
+
+    let peak = peak_rss_bytes();
+    assert!(
+        peak < raw_size * 3,
+        "peak RSS {} MiB over 3x the raw pixel size {} MiB",
+        peak >> 20,
+        raw_size >> 20
+    );
+}
@@ -0,0 +1,1124 @@
+#![cfg(all(feature = "exiv2", feature = "jpeg", feature = "webp", feature = "png"))]
+
+use imgroll::{DescriptorMode, Parallelism, Photo, ProcessOptions, Progress, ProgressCallback};
+
+const LANDSCAPE: &[u8] = include_bytes!("fixtures/landscape.jpg");
+const FOUR_COLORS: &[u8] = include_bytes!("fixtures/four-colors.png");
+
+fn widths(photo: &Photo, mime: &str) -> Vec<u32> {
+    photo
+        .source
+        .iter()
+        .find(|s| !s.original && s.r#type == mime)
+        .map(|s| s.srcset.iter().map(|e| e.width).collect())
+        .unwrap_or_default()
+}
+
+#[test]
+fn jpeg_pipeline() {
+    let (photo, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &Default::default()).unwrap();
+    assert_eq!((photo.width, photo.height), (320, 240));
+    assert_eq!((photo.served_width, photo.served_height), (320, 240));
+    assert_eq!(photo.schema_version, imgroll::PHOTO_SCHEMA_VERSION);
+    let json = serde_json::to_value(&photo).unwrap();
+    assert_eq!(json["schema_version"], imgroll::PHOTO_SCHEMA_VERSION);
+    assert_eq!(files.len(), 2);
+    assert_eq!(photo.source.len(), 3);
+    assert_eq!(widths(&photo, "image/jpeg"), vec![320]);
+    assert_eq!(widths(&photo, "image/webp"), vec![320]);
+    let original = photo.source.iter().find(|s| s.original).unwrap();
+    assert_eq!(original.srcset[0].src, "landscape.jpg");
+    assert_eq!(original.r#type, "image/jpeg");
+    assert!(photo.file_prefix.ends_with("_landscape"));
+    for file in &files {
+        assert!(file.name.ends_with(".320.jpg") || file.name.ends_with(".320.webp"));
+        assert!(file.name.starts_with(&photo.file_prefix));
+        assert!(!file.bytes.is_empty());
+    }
+    assert!(photo.tiny_preview.unwrap().starts_with("data:image/webp;base64,"));
+    let palette = photo.palette.unwrap();
+    assert!(!palette.is_empty() && palette.len() <= 10);
+    assert!(!photo.monochrome);
+}
+
+#[test]
+fn exif_fields() {
+    let (photo, _) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &Default::default()).unwrap();
+    let geo = photo.geo.unwrap();
+    assert!((geo.latitude - 52.52).abs() < 1e-6);
+    assert!((geo.longitude - 13.41).abs() < 1e-6);
+    assert!((geo.altitude - 34.0).abs() < 1e-6);
+    assert_eq!(photo.aperture, Some(2.8));
+    assert_eq!(photo.focal_length, Some(27.0));
+    assert_eq!(photo.iso, Some(100));
+    assert_eq!(photo.shutter_speed.map(|r| (*r.numer(), *r.denom())), Some((1, 320)));
+    assert_eq!(photo.exposure_program.as_deref(), Some("Aperture priority"));
+    assert_eq!(photo.metering_mode.as_deref(), Some("Pattern"));
+    assert_eq!(photo.flash.as_deref(), Some("Did not fire, suppressed"));
+    assert_eq!(photo.subject_distance, Some(3.5));
+    assert_eq!(photo.white_balance.as_deref(), Some("Manual"));
+}
+
+#[test]
+fn png_pipeline() {
+    let (photo, files) = imgroll::process_photo(FOUR_COLORS, "four-colors.png", &Default::default()).unwrap();
+    assert_eq!((photo.width, photo.height), (64, 64));
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].mimetype, "image/png");
+    assert_eq!(widths(&photo, "image/png"), vec![64]);
+    assert!(photo.geo.is_none());
+}
+
+#[test]
+fn few_color_png_is_not_quantized() {
+    use image::GenericImageView;
+    use std::collections::HashSet;
+    let colors = |imag: &image::DynamicImage| {
+        imag.pixels()
+            .map(|(_, _, p)| (p[0], p[1], p[2], p[3]))
+            .collect::<HashSet<_>>()
+    };
+    let (_, files) = imgroll::process_photo(FOUR_COLORS, "four-colors.png", &Default::default()).unwrap();
+    let input = colors(&image::load_from_memory(FOUR_COLORS).unwrap());
+    let output = colors(&image::load_from_memory(&files[0].bytes).unwrap());
+    assert_eq!(input.len(), 4);
+    assert_eq!(output, input);
+}
+
+#[test]
+fn density_descriptors() {
+    let opts = ProcessOptions {
+        descriptor_mode: DescriptorMode::retina(100),
+        ..Default::default()
+    };
+    let (photo, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+    assert_eq!(widths(&photo, "image/jpeg"), vec![100, 200]);
+    assert_eq!(files.len(), 4);
+    let jpeg = photo
+        .source
+        .iter()
+        .find(|s| s.r#type == "image/jpeg" && !s.original)
+        .unwrap();
+    assert!(jpeg.srcset_string().ends_with(".200.jpg 2x"));
+}
+
+#[test]
+fn phash_similarity() {
+    let (a, _) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &Default::default()).unwrap();
+    assert_eq!(a.phash.len(), 16);
+
+    // Same picture, smaller and losslessly re-encoded
+    let mut smaller = vec![];
+    image::load_from_memory(LANDSCAPE)
+        .unwrap()
+        .resize(160, 160, image::imageops::FilterType::Triangle)
+        .write_to(&mut smaller, image::ImageOutputFormat::Png)
+        .unwrap();
+    let (b, _) = imgroll::process_photo(&smaller, "smaller.png", &Default::default()).unwrap();
+    assert!(imgroll::phash_distance(&a.phash, &b.phash).unwrap() <= 10);
+
+    let (c, _) = imgroll::process_photo(FOUR_COLORS, "four-colors.png", &Default::default()).unwrap();
+    assert!(imgroll::phash_distance(&a.phash, &c.phash).unwrap() > 10);
+
+    assert_eq!(
+        imgroll::phash_distance("ffffffffffffffff", "0000000000000000").unwrap(),
+        64
+    );
+    assert!(imgroll::phash_distance("not hex", "0000000000000000").is_err());
+}
+
+#[test]
+fn parallelism_does_not_change_output() {
+    let run = |parallelism| {
+        let opts = ProcessOptions {
+            parallelism,
+            ..Default::default()
+        };
+        let (photo, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+        let files = files.into_iter().map(|f| (f.name, f.bytes)).collect::<Vec<_>>();
+        (serde_json::to_string(&photo).unwrap(), files)
+    };
+    let sequential = run(Parallelism::Sequential);
+    assert_eq!(sequential, run(Parallelism::Global));
+    assert_eq!(sequential, run(Parallelism::Threads(2)));
+}
+
+#[test]
+fn inspect_only_lists_the_original() {
+    let photo = imgroll::inspect_photo(LANDSCAPE, "landscape.jpg").unwrap();
+    assert_eq!(photo.source.len(), 1);
+    assert!(photo.source[0].original);
+    assert_eq!((photo.width, photo.height), (320, 240));
+    assert!(photo.tiny_preview.is_some());
+    assert!(photo.geo.is_some());
+}
+
+#[test]
+fn art_direction_crops() {
+    use image::GenericImageView;
+    let opts = imgroll::ProcessOptions {
+        crops: vec![imgroll::CropSpec {
+            aspect: (1, 1),
+            gravity: imgroll::Gravity::Center,
+            widths: vec![500, 100],
+            media: Some("(max-width: 600px)".to_owned()),
+        }],
+        ..Default::default()
+    };
+    let (photo, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+    let square = photo
+        .source
+        .iter()
+        .find(|s| s.r#type == "image/jpeg" && s.media.is_some())
+        .unwrap();
+    assert_eq!(square.media.as_deref(), Some("(max-width: 600px)"));
+    // Never wider than the crop itself
+    assert_eq!(
+        square.srcset.iter().map(|e| e.width).collect::<Vec<_>>(),
+        vec![240, 100]
+    );
+    for entry in &square.srcset {
+        assert!(entry.src.contains(".1x1."), "{}", entry.src);
+        let file = files.iter().find(|f| f.name == entry.src).unwrap();
+        let imag = image::load_from_memory(&file.bytes).unwrap();
+        assert_eq!(imag.dimensions(), (entry.width, entry.width));
+    }
+}
+
+#[test]
+fn cmyk_jpeg() {
+    use image::GenericImageView;
+    // Four components without an Adobe marker, which the decoder doesn't take as is
+    let cmyk = include_bytes!("fixtures/cmyk.jpg");
+    let (photo, files) = imgroll::process_photo(cmyk, "cmyk.jpg", &Default::default()).unwrap();
+    assert_eq!((photo.width, photo.height), (32, 32));
+    let jpeg = files.iter().find(|f| f.mimetype == "image/jpeg").unwrap();
+    let imag = image::load_from_memory(&jpeg.bytes).unwrap();
+    // Cyan on the left, magenta on the right
+    let cyan = imag.get_pixel(4, 16);
+    assert!(cyan[0] < 64 && cyan[1] > 192 && cyan[2] > 192, "{:?}", cyan);
+    let magenta = imag.get_pixel(28, 16);
+    assert!(magenta[0] > 192 && magenta[1] < 64 && magenta[2] > 192, "{:?}", magenta);
+}
+
+/// Encodes generated pixels as a PNG input.
+fn png(imag: image::RgbImage) -> Vec<u8> {
+    let mut bytes = vec![];
+    image::DynamicImage::ImageRgb8(imag)
+        .write_to(&mut bytes, image::ImageOutputFormat::Png)
+        .unwrap();
+    bytes
+}
+
+#[test]
+fn focal_point_crops() {
+    use image::GenericImageView;
+    // Red on the left, blue on the right
+    let input = png(image::RgbImage::from_fn(200, 100, |x, _| {
+        if x < 100 {
+            image::Rgb([255, 0, 0])
+        } else {
+            image::Rgb([0, 0, 255])
+        }
+    }));
+    let square = |gravity, focal_point| {
+        let opts = imgroll::ProcessOptions {
+            crops: vec![imgroll::CropSpec {
+                aspect: (1, 1),
+                gravity,
+                widths: vec![100],
+                media: None,
+            }],
+            focal_point,
+            ..Default::default()
+        };
+        let (photo, files) = imgroll::process_photo(&input, "halves.png", &opts).unwrap();
+        let file = files
+            .iter()
+            .find(|f| f.name.contains(".1x1.") && f.mimetype == "image/png")
+            .unwrap();
+        let imag = image::load_from_memory(&file.bytes).unwrap();
+        assert_eq!(imag.dimensions(), (100, 100));
+        (photo.focal_point, imag.get_pixel(50, 50))
+    };
+
+    let (_, left) = square(imgroll::Gravity::FocalPoint { x: 0.0, y: 0.5 }, None);
+    assert!(left[0] > 200 && left[2] < 50, "{:?}", left);
+
+    // The crop stays inside the image, and the point used is reported
+    let point = imgroll::FocalPoint { x: 1.0, y: 0.5 };
+    let (focal_point, right) = square(imgroll::Gravity::Subject, Some(point));
+    assert_eq!(focal_point, Some(point));
+    assert!(right[0] < 50 && right[2] > 200, "{:?}", right);
+}
+
+#[test]
+fn sidecar_xmp() {
+    let sidecar = br#"<?xml version="1.0" encoding="UTF-8"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:exif="http://ns.adobe.com/exif/1.0/" exif:FNumber="4/1" exif:FocalLength="50/1"/>
+ </rdf:RDF>
+</x:xmpmeta>
+"#;
+    let opts = imgroll::ProcessOptions {
+        xmp_sidecar: Some(sidecar.to_vec()),
+        ..Default::default()
+    };
+    let (photo, _) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+    // Instead of the embedded f/2.8 and 27mm
+    assert_eq!(photo.aperture, Some(4.0));
+    assert_eq!(photo.focal_length, Some(50.0));
+    // The rest of the embedded metadata stays
+    assert_eq!(photo.iso, Some(100));
+}
+
+#[test]
+fn watermark_corner() {
+    use image::GenericImageView;
+    let background = [20, 40, 60];
+    let input = png(image::RgbImage::from_pixel(200, 100, image::Rgb(background)));
+    let output = |watermark| {
+        let opts = imgroll::ProcessOptions {
+            watermark,
+            ..Default::default()
+        };
+        let (_, files) = imgroll::process_photo(&input, "plain.png", &opts).unwrap();
+        let file = files.iter().find(|f| f.mimetype == "image/png").unwrap();
+        image::load_from_memory(&file.bytes).unwrap()
+    };
+    let is_background = |p: image::Rgba<u8>| {
+        p.0[..3]
+            .iter()
+            .zip(&background)
+            .all(|(&a, &b)| a.max(b) - a.min(b) <= 4)
+    };
+    let is_white = |p: image::Rgba<u8>| p.0[..3].iter().all(|&c| c > 200);
+
+    let plain = output(None);
+    let marked = output(Some(imgroll::Watermark {
+        overlay: image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(40, 40, image::Rgba([255; 4]))),
+        corner: imgroll::Corner::TopLeft,
+        margin: imgroll::Margin::Pixels(10),
+        opacity: 1.0,
+        relative_width: 0.1,
+    }));
+    // Scaled to a tenth of the width (20px), 10px away from the top left corner
+    for &(x, y) in &[(10, 10), (29, 29)] {
+        assert!(is_background(plain.get_pixel(x, y)));
+        assert!(is_white(marked.get_pixel(x, y)), "{:?}", marked.get_pixel(x, y));
+    }
+    for &(x, y) in &[(9, 9), (30, 30), (190, 90)] {
+        assert!(is_background(marked.get_pixel(x, y)), "{:?}", marked.get_pixel(x, y));
+    }
+}
+
+#[test]
+fn content_addressed_names() {
+    // The same crop twice gives identical renditions
+    let crop = imgroll::CropSpec {
+        aspect: (1, 1),
+        gravity: imgroll::Gravity::Center,
+        widths: vec![100],
+        media: None,
+    };
+    let names = |file_name: &str| {
+        let opts = imgroll::ProcessOptions {
+            crops: vec![crop.clone(), crop.clone()],
+            content_addressed: true,
+            ..Default::default()
+        };
+        let (photo, files) = imgroll::process_photo(LANDSCAPE, file_name, &opts).unwrap();
+        let names = files.iter().map(|f| f.name.clone()).collect::<Vec<_>>();
+        for entry in photo.source.iter().filter(|s| !s.original).flat_map(|s| &s.srcset) {
+            assert!(names.contains(&entry.src), "{}", entry.src);
+        }
+        names
+    };
+    let names_a = names("a.jpg");
+    // The main image and one crop, in JPEG and WebP
+    assert_eq!(names_a.len(), 4, "{:?}", names_a);
+    assert!(names_a.iter().all(|name| !name.contains('_')), "{:?}", names_a);
+    // Named after the bytes, not the source file
+    assert_eq!(names_a, names("b.jpg"));
+}
+
+#[test]
+fn social_preview_size() {
+    use image::GenericImageView;
+    let opts = imgroll::ProcessOptions {
+        social_preview: Some(imgroll::SocialPreviewSpec::default()),
+        ..Default::default()
+    };
+    let (photo, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+    let social = photo.social_preview.unwrap();
+    assert_eq!(social.width, 1200);
+    let file = files.iter().find(|f| f.name == social.src).unwrap();
+    assert_eq!(file.mimetype, "image/jpeg");
+    // Cropped from 4:3, not letterboxed
+    assert_eq!(image::load_from_memory(&file.bytes).unwrap().dimensions(), (1200, 630));
+}
+
+#[test]
+fn orientation_override() {
+    let dimensions = |orientation_override, apply_orientation| {
+        let opts = imgroll::ProcessOptions {
+            orientation_override,
+            apply_orientation,
+            ..Default::default()
+        };
+        let (photo, _) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+        (photo.width, photo.height)
+    };
+    assert_eq!(dimensions(Some(imgroll::Orientation::Rotate90), true), (240, 320));
+    assert_eq!(dimensions(Some(imgroll::Orientation::Rotate90), false), (320, 240));
+}
+
+#[test]
+fn monochrome_png() {
+    let input = png(image::RgbImage::from_fn(64, 64, |x, _| image::Rgb([x as u8 * 4; 3])));
+    let (photo, files) = imgroll::process_photo(&input, "grey.png", &Default::default()).unwrap();
+    assert!(photo.monochrome);
+    let file = files.iter().find(|f| f.mimetype == "image/png").unwrap();
+    assert_eq!(
+        image::load_from_memory(&file.bytes).unwrap().color(),
+        image::ColorType::L8
+    );
+}
+
+#[test]
+fn cancelled_before_start() {
+    let token = imgroll::CancelToken::new();
+    token.cancel();
+    let opts = ProcessOptions {
+        cancel: Some(token),
+        ..Default::default()
+    };
+    match imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts) {
+        Err(imgroll::Error::Cancelled) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("processing was not cancelled"),
+    }
+}
+
+#[test]
+fn tiny_preview_as_file() {
+    let opts = ProcessOptions {
+        tiny_preview_file: true,
+        ..Default::default()
+    };
+    let (photo, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+    assert!(photo.tiny_preview.is_none());
+    let name = photo.tiny_preview_url.unwrap();
+    assert!(name.ends_with(".tiny.webp"));
+    let file = files.iter().find(|f| f.name == name).unwrap();
+    assert_eq!(file.mimetype, "image/webp");
+    assert_eq!(&file.bytes[8..12], b"WEBP");
+    let info = photo.tiny_preview_info.unwrap();
+    assert_eq!((info.width, info.height, info.bytes), (48, 36, file.bytes.len()));
+}
+
+#[test]
+fn progress_events() {
+    use std::sync::{Arc, Mutex};
+    let events = Arc::new(Mutex::new(vec![]));
+    let sink = events.clone();
+    let opts = ProcessOptions {
+        parallelism: Parallelism::Sequential,
+        progress: Some(ProgressCallback::new(move |p| sink.lock().unwrap().push(p))),
+        ..Default::default()
+    };
+    imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+    let encoded = |format, completed| Progress::Encoded {
+        format,
+        width: 320,
+        completed,
+        total: 2,
+    };
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            Progress::Decoding,
+            Progress::ExtractingPalette,
+            encoded("jpg", 1),
+            encoded("webp", 2),
+            Progress::Done,
+        ]
+    );
+}
+
+#[test]
+fn size_budget() {
+    let (_, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &Default::default()).unwrap();
+    let budget = files.iter().map(|f| f.bytes.len()).min().unwrap() / 2;
+    let opts = ProcessOptions {
+        size_budget: Some(imgroll::SizeBudget::new(budget)),
+        ..Default::default()
+    };
+    let (_, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+    assert_eq!(files.len(), 2);
+    for file in files {
+        assert!(
+            file.bytes.len() <= budget,
+            "{} is {} bytes",
+            file.name,
+            file.bytes.len()
+        );
+    }
+}
+
+const ROTATED: &[u8] = include_bytes!("fixtures/rotated.jpg");
+
+#[test]
+fn outputs_are_not_rotated_twice() {
+    let (photo, files) = imgroll::process_photo(ROTATED, "rotated.jpg", &Default::default()).unwrap();
+    // Stored as 64x48 with orientation 6 (rotate 90° clockwise)
+    assert_eq!((photo.width, photo.height), (48, 64));
+    assert_eq!(photo.source_orientation, Some(6));
+    for file in files {
+        let decoded = image::load_from_memory(&file.bytes);
+        if let Ok(imag) = decoded {
+            use image::GenericImageView;
+            assert_eq!(imag.dimensions(), (48, 64));
+        }
+        if let Ok(meta) = rexiv2::Metadata::new_from_buffer(&file.bytes) {
+            assert!(matches!(
+                meta.get_orientation(),
+                rexiv2::Orientation::Normal | rexiv2::Orientation::Unspecified
+            ));
+        }
+    }
+}
+
+#[test]
+fn normalize_orientation() {
+    let (imag, meta) = imgroll::decode_and_orient(ROTATED, &Default::default()).unwrap();
+    let meta = meta.unwrap();
+    assert_eq!(meta.orientation(), imgroll::Orientation::Rotate90);
+    assert_eq!(meta.orientation().exif_value(), Some(6));
+    imgroll::normalize_orientation(meta.as_ref());
+    assert_eq!(meta.orientation(), imgroll::Orientation::Normal);
+    use image::GenericImageView;
+    assert_eq!(imag.dimensions(), (48, 64));
+}
+
+#[test]
+fn streaming_matches_collected() {
+    use std::io::Read;
+    let (photo, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &Default::default()).unwrap();
+    let mut streamed = vec![];
+    let streamed_photo = imgroll::process_photo_streaming(
+        LANDSCAPE,
+        "landscape.jpg",
+        &Default::default(),
+        &mut |meta, contents| {
+            let mut bytes = vec![];
+            contents.read_to_end(&mut bytes)?;
+            assert_eq!(meta.size, bytes.len());
+            streamed.push((meta.name, bytes));
+            Ok(())
+        },
+    )
+    .unwrap();
+    streamed.sort();
+    assert_eq!(
+        streamed,
+        files.into_iter().map(|f| (f.name, f.bytes)).collect::<Vec<_>>()
+    );
+    assert_eq!(streamed_photo.source.len(), photo.source.len());
+}
+
+#[test]
+fn streaming_sink_error() {
+    let result = imgroll::process_photo_streaming(LANDSCAPE, "landscape.jpg", &Default::default(), &mut |_, _| {
+        Err(std::io::Error::new(std::io::ErrorKind::Other, "disk full"))
+    });
+    match result {
+        Err(imgroll::Error::Sink { .. }) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("sink error was ignored"),
+    }
+}
+
+#[test]
+fn mpo_images_are_processed_separately() {
+    let stereo = include_bytes!("fixtures/stereo.mpo");
+    let results = imgroll::process_multi_photo(stereo, "stereo.mpo", &Default::default()).unwrap();
+    assert_eq!(results.len(), 2);
+    for (photo, files) in &results {
+        assert_eq!((photo.width, photo.height), (80, 60));
+        assert_eq!(files.len(), 2);
+    }
+    assert_ne!(results[0].1[0].name, results[1].1[0].name);
+
+    let single = imgroll::process_multi_photo(LANDSCAPE, "landscape.jpg", &Default::default()).unwrap();
+    assert_eq!(single.len(), 1);
+}
+
+#[test]
+fn example_config_round_trips() {
+    let example = include_str!("../imgroll.example.toml");
+    let opts = ProcessOptions::from_toml_str(example).unwrap();
+    assert_eq!(opts.webp_image_hint, imgroll::WebpImageHint::Photo);
+    assert_eq!(opts.crops.len(), 1);
+    assert_eq!(opts.size_budget.unwrap().max_attempts, 6);
+    let json = serde_json::to_string(&opts).unwrap();
+    let again = ProcessOptions::from_json_str(&json).unwrap();
+    assert_eq!(format!("{:?}", again), format!("{:?}", opts));
+
+    let partial = ProcessOptions::from_toml_str("palette_hex = true").unwrap();
+    assert!(partial.palette_hex && partial.include_original);
+
+    match ProcessOptions::from_toml_str("[stages]\npalette = 3") {
+        Err(imgroll::Error::Config { field, .. }) => assert_eq!(field, "stages.palette"),
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+
+    let from_file = ProcessOptions::from_path(concat!(env!("CARGO_MANIFEST_DIR"), "/imgroll.example.toml")).unwrap();
+    assert_eq!(format!("{:?}", from_file), format!("{:?}", opts));
+    match ProcessOptions::from_path("does-not-exist.json") {
+        Err(imgroll::Error::ConfigRead { path, .. }) => assert_eq!(path.to_str(), Some("does-not-exist.json")),
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn supported_formats() {
+    assert_eq!(imgroll::supported_input_formats(), ["image/jpeg", "image/png"]);
+    assert_eq!(
+        imgroll::supported_output_formats(),
+        ["image/jpeg", "image/webp", "image/png"]
+    );
+}
+
+#[test]
+fn map_urls() {
+    let opts = ProcessOptions {
+        tiny_preview_file: true,
+        ..Default::default()
+    };
+    let (mut photo, _) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+    photo.map_urls(|name| format!("https://example.com/{}", name));
+    let tiny = photo.tiny_preview_url.unwrap();
+    assert!(tiny.starts_with("https://example.com/landscape") && tiny.ends_with(".webp"));
+    for source in &photo.source {
+        for entry in &source.srcset {
+            assert!(entry.src.starts_with("https://example.com/landscape"), "{}", entry.src);
+        }
+    }
+}
+
+#[test]
+fn thumbnail_filter() {
+    let opts = ProcessOptions {
+        descriptor_mode: DescriptorMode::retina(100),
+        thumbnail_filter: imgroll::ResizeFilter::Triangle,
+        ..Default::default()
+    };
+    let (photo, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+    assert_eq!(widths(&photo, "image/jpeg"), vec![100, 200]);
+    let (_, lanczos) = imgroll::process_photo(
+        LANDSCAPE,
+        "landscape.jpg",
+        &ProcessOptions {
+            descriptor_mode: DescriptorMode::retina(100),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let bytes =
+        |files: &[imgroll::OutFile], suffix| files.iter().find(|f| f.name.ends_with(suffix)).unwrap().bytes.clone();
+    // The 1x rendition is the main image, only the 2x one is a thumbnail
+    assert_eq!(bytes(&files, ".100.jpg"), bytes(&lanczos, ".100.jpg"));
+    assert_ne!(bytes(&files, ".200.jpg"), bytes(&lanczos, ".200.jpg"));
+}
+
+#[test]
+fn reencode_lossless_png() {
+    let opts = ProcessOptions {
+        reencode_lossless: true,
+        ..Default::default()
+    };
+    let (photo, files) = imgroll::process_photo(FOUR_COLORS, "four-colors.png", &opts).unwrap();
+    let mut types = files.iter().map(|f| f.mimetype.as_str()).collect::<Vec<_>>();
+    types.sort_unstable();
+    assert_eq!(types, vec!["image/png", "image/webp"]);
+    assert_eq!(widths(&photo, "image/webp"), vec![64]);
+}
+
+#[test]
+fn animated_inputs_are_rejected() {
+    let inputs: [(&[u8], _, _); 2] = [
+        (
+            include_bytes!("fixtures/animated.png"),
+            "animated.png",
+            image::ImageFormat::Png,
+        ),
+        (
+            include_bytes!("fixtures/animated.gif"),
+            "animated.gif",
+            image::ImageFormat::Gif,
+        ),
+    ];
+    for (contents, name, expected) in inputs.iter() {
+        match imgroll::process_photo(contents, name, &Default::default()) {
+            Err(imgroll::Error::AnimatedUnsupported { format, frames }) => {
+                assert_eq!((format, frames), (*expected, 2));
+            },
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("{} was processed as a still image", name),
+        }
+    }
+}
+
+#[test]
+fn jpeg_subsampling() {
+    // Sampling factors byte of the luma component in the SOF segment
+    fn luma_sampling(jpeg: &[u8]) -> u8 {
+        let sof = jpeg
+            .windows(2)
+            .position(|m| m == [0xFF, 0xC0] || m == [0xFF, 0xC2])
+            .unwrap();
+        jpeg[sof + 11]
+    }
+    let main_jpeg = |subsampling| {
+        let opts = ProcessOptions {
+            jpeg_subsampling: subsampling,
+            ..Default::default()
+        };
+        let (_, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+        files.into_iter().find(|f| f.mimetype == "image/jpeg").unwrap().bytes
+    };
+    assert_eq!(luma_sampling(&main_jpeg(imgroll::ChromaSubsampling::Yuv420)), 0x22);
+    assert_eq!(luma_sampling(&main_jpeg(imgroll::ChromaSubsampling::Yuv422)), 0x21);
+    assert_eq!(luma_sampling(&main_jpeg(imgroll::ChromaSubsampling::Yuv444)), 0x11);
+}
+
+#[test]
+fn custom_widths_and_max_dimension() {
+    let opts = ProcessOptions {
+        widths: Some(vec![100, 400, 200, 200]),
+        max_dimension: 300,
+        ..Default::default()
+    };
+    let (photo, _) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+    assert_eq!((photo.served_width, photo.served_height), (300, 225));
+    assert_eq!(widths(&photo, "image/jpeg"), vec![300, 200, 100]);
+    assert_eq!(widths(&photo, "image/webp"), vec![300, 200, 100]);
+}
+
+#[test]
+fn output_formats() {
+    let opts = ProcessOptions {
+        formats: Some(vec![imgroll::OutputFormat::Webp]),
+        social_preview: Some(Default::default()),
+        ..Default::default()
+    };
+    let (photo, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+    assert!(files.iter().all(|f| f.mimetype == "image/webp"));
+    assert!(widths(&photo, "image/jpeg").is_empty());
+    assert!(photo.social_preview.unwrap().src.ends_with(".webp"));
+
+    let opts = ProcessOptions {
+        formats: Some(vec![imgroll::OutputFormat::Jpeg]),
+        ..Default::default()
+    };
+    assert!(matches!(
+        imgroll::process_photo(FOUR_COLORS, "four-colors.png", &opts),
+        Err(imgroll::Error::NoAllowedFormat {
+            format: image::ImageFormat::Png
+        })
+    ));
+}
+
+#[test]
+fn fixed_quality() {
+    let size = |quality| {
+        let opts = ProcessOptions {
+            jpeg_quality: Some(quality),
+            formats: Some(vec![imgroll::OutputFormat::Jpeg]),
+            ..Default::default()
+        };
+        let (_, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+        files[0].bytes.len()
+    };
+    assert!(size(20.0) < size(95.0));
+}
+
+#[test]
+fn quality_by_width() {
+    let jpegs = |opts: ProcessOptions| {
+        let opts = ProcessOptions {
+            widths: Some(vec![100]),
+            formats: Some(vec![imgroll::OutputFormat::Jpeg]),
+            ..opts
+        };
+        let (_, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+        files.into_iter().map(|f| (f.name, f.bytes)).collect::<Vec<_>>()
+    };
+    let fixed = |quality| {
+        jpegs(ProcessOptions {
+            jpeg_quality: Some(quality),
+            ..Default::default()
+        })
+    };
+    let by_width = jpegs(ProcessOptions {
+        jpeg_quality: Some(50.0),
+        quality_by_width: Some(vec![(200, 90.0), (150, 95.0), (250, 20.0)]),
+        ..Default::default()
+    });
+    let (high, low) = (fixed(95.0), fixed(20.0));
+    let find = |files: &[(String, Vec<u8>)], suffix: &str| {
+        files.iter().find(|(name, _)| name.ends_with(suffix)).unwrap().1.clone()
+    };
+    // 100px fits into 150 first, 320px is wider than everything and gets the largest width's quality
+    assert_eq!(find(&by_width, ".100.jpg"), find(&high, ".100.jpg"));
+    assert_eq!(find(&by_width, ".320.jpg"), find(&low, ".320.jpg"));
+}
+
+#[test]
+fn process_from_path() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/landscape.jpg");
+    let (photo, files) = imgroll::process_photo_path(path, &Default::default()).unwrap();
+    let (_, expected) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &Default::default()).unwrap();
+    assert_eq!(photo.width, 320);
+    assert_eq!(
+        files.iter().map(|f| &f.name).collect::<Vec<_>>(),
+        expected.iter().map(|f| &f.name).collect::<Vec<_>>()
+    );
+    let original = photo.source.iter().find(|s| s.original).unwrap();
+    assert_eq!(original.srcset[0].src, "landscape.jpg");
+
+    match imgroll::process_photo_path("does-not-exist.jpg", &Default::default()) {
+        Err(imgroll::Error::ReadInput { path, .. }) => assert_eq!(path.to_str(), Some("does-not-exist.jpg")),
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn lossless_webp_for_few_colors() {
+    let is_lossless = |max_colors| {
+        let opts = ProcessOptions {
+            reencode_lossless: true,
+            webp_lossless_max_colors: max_colors,
+            ..Default::default()
+        };
+        let (_, files) = imgroll::process_photo(FOUR_COLORS, "four-colors.png", &opts).unwrap();
+        let webp = files.iter().find(|f| f.mimetype == "image/webp").unwrap();
+        webp.bytes.windows(4).any(|chunk| chunk == b"VP8L")
+    };
+    assert!(!is_lossless(None));
+    assert!(is_lossless(Some(16)));
+    assert!(!is_lossless(Some(2)));
+}
+
+#[test]
+fn original_as_output_file() {
+    let opts = ProcessOptions {
+        include_original_file: true,
+        ..Default::default()
+    };
+    let (_, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+    let original = files.iter().find(|f| f.name == "landscape.jpg").unwrap();
+    assert_eq!(original.bytes, LANDSCAPE);
+    assert_eq!(original.mimetype, "image/jpeg");
+
+    let (_, files) = imgroll::process_photo_owned(LANDSCAPE.to_vec(), "landscape.jpg", &opts).unwrap();
+    assert!(files.iter().any(|f| f.name == "landscape.jpg"));
+    let (_, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &Default::default()).unwrap();
+    assert!(!files.iter().any(|f| f.name == "landscape.jpg"));
+}
+
+#[test]
+fn decode_outputs() {
+    use image::GenericImageView;
+    let (_, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &Default::default()).unwrap();
+    for file in &files {
+        let (mimetype, imag) = imgroll::decode_output_file(&file.bytes).unwrap();
+        assert_eq!(mimetype, file.mimetype);
+        assert_eq!(imag.width(), 320);
+    }
+    assert!(imgroll::decode_output_file(b"RIFF\0\0\0\0WEBPVP8 garbage").is_err());
+}
+
+#[test]
+fn tiny_preview_svg() {
+    let opts = ProcessOptions {
+        tiny_preview_svg: true,
+        ..Default::default()
+    };
+    let (photo, _) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+    let svg = photo.tiny_preview.unwrap();
+    assert!(svg.starts_with("data:image/svg+xml,%3Csvg "));
+    assert!(svg.contains("feGaussianBlur") && svg.contains("href='data:image/webp;base64,"));
+    assert!(!svg.contains(&['<', '>', '#', '"'][..]));
+}
+
+#[test]
+fn tiny_preview_size() {
+    let info = |tiny_preview_size| {
+        let opts = ProcessOptions {
+            tiny_preview_size,
+            ..Default::default()
+        };
+        let (photo, _) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+        let info = photo.tiny_preview_info.unwrap();
+        (info.width, info.height)
+    };
+    assert_eq!(info(48), (48, 36));
+    assert_eq!(info(64), (64, 48));
+    assert_eq!(info(32), (32, 24));
+}
+
+#[test]
+fn palette_from_downscaled_image() {
+    // The colors of either palette are close to ones in the other
+    let distance = |a: rgb::RGB8, b: rgb::RGB8| {
+        let d = |x: u8, y: u8| (f64::from(x) - f64::from(y)).powi(2);
+        (d(a.r, b.r) + d(a.g, b.g) + d(a.b, b.b)).sqrt()
+    };
+    for (contents, name, size) in [(LANDSCAPE, "landscape.jpg", 100), (FOUR_COLORS, "four-colors.png", 32)] {
+        let palette = |palette_sample_size| {
+            let opts = ProcessOptions {
+                palette_sample_size,
+                ..Default::default()
+            };
+            imgroll::process_photo(contents, name, &opts)
+                .unwrap()
+                .0
+                .palette
+                .unwrap()
+        };
+        let (full, sampled) = (palette(0), palette(size));
+        let mean_nearest = |from: &[rgb::RGB8], to: &[rgb::RGB8]| {
+            let nearest = |color| to.iter().map(|&c| distance(color, c)).fold(f64::MAX, f64::min);
+            from.iter().map(|&c| nearest(c)).sum::<f64>() / from.len() as f64
+        };
+        assert!(
+            mean_nearest(&sampled, &full) < 32.0,
+            "{}: {:?} vs {:?}",
+            name,
+            full,
+            sampled
+        );
+        assert!(
+            mean_nearest(&full, &sampled) < 32.0,
+            "{}: {:?} vs {:?}",
+            name,
+            full,
+            sampled
+        );
+    }
+}
+
+#[test]
+fn min_rating() {
+    let sidecar = |rating: i32| {
+        format!(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+<rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:Rating="{}"/>
+</rdf:RDF></x:xmpmeta>"#,
+            rating
+        )
+        .into_bytes()
+    };
+    let opts = ProcessOptions {
+        min_rating: Some(3),
+        ..Default::default()
+    };
+    match imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts) {
+        Err(imgroll::Error::RatingTooLow {
+            rating: None,
+            min_rating: 3,
+        }) => (),
+        other => panic!("{:?}", other.map(|(photo, _)| photo)),
+    }
+    let rated = |rating| ProcessOptions {
+        xmp_sidecar: Some(sidecar(rating)),
+        ..opts.clone()
+    };
+    match imgroll::process_photo(LANDSCAPE, "landscape.jpg", &rated(2)) {
+        Err(imgroll::Error::RatingTooLow { rating: Some(2), .. }) => (),
+        other => panic!("{:?}", other.map(|(photo, _)| photo)),
+    }
+    let (photo, _) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &rated(4)).unwrap();
+    assert_eq!(photo.rating, Some(4));
+    let (photo, _) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &Default::default()).unwrap();
+    assert_eq!(photo.rating, None);
+}
+
+#[test]
+fn max_pixels() {
+    let max_pixels = |max_pixels| ProcessOptions {
+        max_pixels: Some(max_pixels),
+        ..Default::default()
+    };
+    match imgroll::process_photo(LANDSCAPE, "landscape.jpg", &max_pixels(320 * 240 - 1)) {
+        Err(imgroll::Error::TooManyPixels {
+            width: 320,
+            height: 240,
+            ..
+        }) => (),
+        other => panic!("{:?}", other.map(|(photo, _)| photo)),
+    }
+    imgroll::process_photo(LANDSCAPE, "landscape.jpg", &max_pixels(320 * 240)).unwrap();
+    imgroll::process_photo(FOUR_COLORS, "four-colors.png", &max_pixels(1)).unwrap_err();
+}
+
+#[test]
+fn mislabeled_extension() {
+    let (photo, files) = imgroll::process_photo(FOUR_COLORS, "four-colors.jpg", &Default::default()).unwrap();
+    assert!(files
+        .iter()
+        .all(|f| f.mimetype == "image/png" && f.name.ends_with(".png")));
+    let original = photo.source.iter().find(|s| s.original).unwrap();
+    assert_eq!(original.r#type, "image/png");
+}
+
+#[test]
+fn error_classification() {
+    // Cut off in the middle of the scan
+    let err = imgroll::process_photo(&LANDSCAPE[..LANDSCAPE.len() / 2], "landscape.jpg", &Default::default())
+        .map(|(photo, _)| photo)
+        .unwrap_err();
+    assert_eq!(err.kind(), "image_proc");
+    assert!(err.is_permanent());
+    let opts = ProcessOptions {
+        max_pixels: Some(1),
+        ..Default::default()
+    };
+    let err = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts)
+        .map(|(photo, _)| photo)
+        .unwrap_err();
+    assert_eq!(err.kind(), "too_many_pixels");
+    assert!(err.is_permanent());
+    let err = ProcessOptions::from_json_str(r#"{"jpeg_quality": "high"}"#).unwrap_err();
+    assert_eq!(err.kind(), "config");
+    assert!(!err.is_permanent());
+}
+
+#[test]
+fn lossless_webp_of_odd_width_crop() {
+    // 27px wide RGB rows are 81 bytes, not a multiple of 4, cut from the right edge of the image
+    let opts = ProcessOptions {
+        reencode_lossless: true,
+        webp_lossless_max_colors: Some(16),
+        thumbnail_filter: imgroll::ResizeFilter::Nearest,
+        crops: vec![imgroll::CropSpec {
+            aspect: (3, 7),
+            gravity: imgroll::Gravity::FocalPoint { x: 1.0, y: 0.5 },
+            widths: vec![27],
+            media: None,
+        }],
+        ..Default::default()
+    };
+    let (_, files) = imgroll::process_photo(FOUR_COLORS, "four-colors.png", &opts).unwrap();
+    let file = files.iter().find(|f| f.name.ends_with(".3x7.27.webp")).unwrap();
+    let (_, decoded) = imgroll::decode_output_file(&file.bytes).unwrap();
+    let (original, _) = imgroll::decode_and_orient(FOUR_COLORS, &Default::default()).unwrap();
+    let expected = original
+        .crop_imm(37, 0, 27, 64)
+        .resize(27, u32::MAX, image::imageops::FilterType::Nearest);
+    assert_eq!(decoded.to_rgb8(), expected.to_rgb8());
+}
+
+#[test]
+fn process_decoded_image() {
+    let gradient = image::RgbImage::from_fn(640, 480, |x, y| image::Rgb([(x / 3) as u8, (y / 2) as u8, 96]));
+    let opts = ProcessOptions {
+        widths: Some(vec![320]),
+        ..Default::default()
+    };
+    let (photo, files) = imgroll::process_image(image::DynamicImage::ImageRgb8(gradient), "gradient", &opts).unwrap();
+    assert_eq!((photo.width, photo.height), (640, 480));
+    assert_eq!(widths(&photo, "image/jpeg"), vec![640, 320]);
+    assert_eq!(widths(&photo, "image/webp"), vec![640, 320]);
+    assert!(photo.source.iter().all(|s| !s.original));
+    assert_eq!(files.len(), 4);
+    assert!(photo.tiny_preview.is_some() && photo.palette.is_some());
+    assert_eq!(
+        (photo.aperture, photo.iso, photo.source_orientation),
+        (None, None, None)
+    );
+
+    // Transparency rules out JPEG, but it's resized all the same
+    let (photo, files) = imgroll::process_image(
+        image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(640, 480, image::Rgba([200, 40, 40, 160]))),
+        "transparent",
+        &opts,
+    )
+    .unwrap();
+    assert_eq!(widths(&photo, "image/png"), vec![640, 320]);
+    assert_eq!(widths(&photo, "image/webp"), vec![640, 320]);
+    assert!(files.iter().all(|f| f.mimetype != "image/jpeg"));
+}
+
+#[test]
+fn webp_metadata() {
+    use image::GenericImageView;
+    let webp = |opts: &ProcessOptions| {
+        let (_, files) = imgroll::process_photo(ROTATED, "rotated.jpg", opts).unwrap();
+        files.into_iter().find(|f| f.mimetype == "image/webp").unwrap().bytes
+    };
+    assert!(!webp(&Default::default()).windows(4).any(|chunk| chunk == b"EXIF"));
+
+    let bytes = webp(&ProcessOptions {
+        webp_metadata: true,
+        ..Default::default()
+    });
+    assert_eq!(&bytes[12..16], b"VP8X");
+    let (_, decoded) = imgroll::decode_output_file(&bytes).unwrap();
+    assert_eq!(decoded.dimensions(), (48, 64));
+    let meta = rexiv2::Metadata::new_from_buffer(&bytes).unwrap();
+    assert!(meta.has_exif());
+    // Already rotated, so viewers must not rotate it again
+    assert_eq!(meta.get_orientation(), rexiv2::Orientation::Normal);
+}
+
+#[test]
+fn slugs() {
+    use imgroll::SlugStyle;
+    // Everything after the 12 hex digits of the hash
+    let names = |file_name: &str, opts: &ProcessOptions| {
+        let (_, files) = imgroll::process_photo(LANDSCAPE, file_name, opts).unwrap();
+        files.into_iter().map(|f| f.name[12..].to_owned()).collect::<Vec<_>>()
+    };
+    // Transliterated, or left out, but never ending the prefix in an underscore
+    for name in names("写真.jpg", &Default::default()) {
+        assert!(name.is_ascii() && !name.starts_with("_."), "{}", name);
+    }
+
+    let unicode = ProcessOptions {
+        slug_style: SlugStyle::Unicode,
+        ..Default::default()
+    };
+    assert_eq!(
+        names("写真 2024.jpg", &unicode),
+        vec!["_写真-2024.320.jpg", "_写真-2024.320.webp"]
+    );
+    // Nothing is left of these names
+    assert_eq!(names("🎉🎉.jpg", &unicode), vec![".320.jpg", ".320.webp"]);
+    assert_eq!(names("(!).jpg", &Default::default()), vec![".320.jpg", ".320.webp"]);
+
+    let placeholder = ProcessOptions {
+        slug_placeholder: Some("photo".to_owned()),
+        ..unicode
+    };
+    assert_eq!(names("🎉.jpg", &placeholder), vec!["_photo.320.jpg", "_photo.320.webp"]);
+}
+
+#[test]
+fn embedded_thumbnail() {
+    use image::GenericImageView;
+    let with_thumbnail = include_bytes!("fixtures/exif-thumbnail.jpg");
+    let thumb = imgroll::embedded_thumbnail(with_thumbnail).unwrap();
+    assert_eq!(image::guess_format(&thumb).unwrap(), image::ImageFormat::Jpeg);
+    assert_eq!(image::load_from_memory(&thumb).unwrap().dimensions(), (160, 120));
+    assert!(imgroll::embedded_thumbnail(LANDSCAPE).is_none());
+
+    // Both with the thumbnail and falling back to the full image
+    for input in &[&with_thumbnail[..], LANDSCAPE] {
+        let preview = imgroll::quick_tiny_preview(input).unwrap();
+        assert!(preview.starts_with("data:image/webp;base64,"), "{}", preview);
+    }
+}
@@ -0,0 +1,36 @@
+# Run with `maturin develop && pytest tests/python`
+from pathlib import Path
+
+import pytest
+
+import imgroll
+
+FIXTURES = Path(__file__).parent.parent / "fixtures"
+
+
+def test_process():
+    data = (FIXTURES / "landscape.jpg").read_bytes()
+    photo, files = imgroll.process(data, "landscape.jpg")
+    assert photo["width"] == 320
+    assert photo["schema_version"] >= 1
+    assert photo["height"] == 240
+    assert len(photo["palette"]) > 0
+    assert {mimetype for _, _, mimetype in files} == {"image/jpeg", "image/webp"}
+    for name, contents, _ in files:
+        assert name.startswith("landscape")
+        assert isinstance(contents, bytes)
+        assert len(contents) > 0
+
+
+def test_options():
+    data = (FIXTURES / "landscape.jpg").read_bytes()
+    photo, files = imgroll.process(data, "landscape.jpg", include_original=False, stages={"palette": False})
+    assert "palette" not in photo
+    assert not any(source["original"] for source in photo["source"])
+
+
+def test_errors():
+    with pytest.raises(imgroll.Error, match="stages"):
+        imgroll.process(b"", "x.jpg", stages={"nope": True})
+    with pytest.raises(imgroll.Error):
+        imgroll.process(b"not an image", "x.jpg")
@@ -0,0 +1,130 @@
+//! The upload helpers shared by the binaries, run with the `s3` feature (on by default through `lambda`).
+#![cfg(feature = "s3")]
+
+use imgroll::s3::{is_transient, object_url, put_request, skip_reason, Location, Skip};
+use rusoto_core::{request::HttpDispatchError, Region, RusotoError};
+use rusoto_s3::PutObjectError;
+use std::collections::HashMap;
+
+fn meta(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect()
+}
+
+#[test]
+fn locations() {
+    let location = Location::parse("s3://photos/blog/2021/").unwrap();
+    assert_eq!(location.bucket, "photos");
+    assert_eq!(location.prefix, "blog/2021");
+    assert_eq!(location.key("a.jpg"), "blog/2021/a.jpg");
+    let root = Location::parse("s3://photos").unwrap();
+    assert_eq!(root.prefix, "");
+    assert_eq!(root.key("a.jpg"), "a.jpg");
+    assert_eq!(Location::parse("s3:///key"), None);
+    assert_eq!(Location::parse("https://photos/key"), None);
+}
+
+#[test]
+fn object_urls() {
+    assert_eq!(
+        object_url(&Region::EuWest1, "photos", "a.jpg", false),
+        "https://photos.s3.dualstack.eu-west-1.amazonaws.com/a.jpg"
+    );
+    let minio = Region::Custom {
+        name: "us-east-1".to_owned(),
+        endpoint: "http://minio.local:9000/".to_owned(),
+    };
+    assert_eq!(
+        object_url(&minio, "photos", "a.jpg", true),
+        "http://minio.local:9000/photos/a.jpg"
+    );
+    assert_eq!(
+        object_url(&minio, "photos", "a.jpg", false),
+        "http://photos.minio.local:9000/a.jpg"
+    );
+    let no_scheme = Region::Custom {
+        name: "us-east-1".to_owned(),
+        endpoint: "minio.local".to_owned(),
+    };
+    assert_eq!(
+        object_url(&no_scheme, "photos", "a.jpg", true),
+        "https://minio.local/photos/a.jpg"
+    );
+}
+
+#[test]
+fn output_uploads() {
+    let request = put_request("photos", "blog/a.webp".to_owned(), vec![0; 10], "image/webp".to_owned());
+    assert_eq!(
+        (request.bucket.as_str(), request.key.as_str()),
+        ("photos", "blog/a.webp")
+    );
+    assert_eq!(request.content_length, Some(10));
+    assert_eq!(request.content_type.as_deref(), Some("image/webp"));
+    assert_eq!(
+        request.cache_control.as_deref(),
+        Some("public, max-age=31536000, immutable")
+    );
+    assert_eq!(request.acl, None);
+}
+
+#[test]
+fn skipping() {
+    let next_to_originals = Location::parse("s3://photos").unwrap();
+    let upload = meta(&[("imgroll-cb", "https://example.com/cb")]);
+    assert_eq!(skip_reason("photos", "a.jpg", &upload, &next_to_originals, false), None);
+    assert_eq!(
+        skip_reason(
+            "photos",
+            "a.1280.webp",
+            &meta(&[("imgroll-original", "a.jpg")]),
+            &next_to_originals,
+            false
+        ),
+        Some(Skip::Output {
+            original: "a.jpg".to_owned()
+        })
+    );
+    assert_eq!(
+        skip_reason("photos", "a.jpg", &upload, &next_to_originals, true),
+        Some(Skip::Marker)
+    );
+    // Markers written before they got the imgroll-original metadata
+    assert_eq!(
+        skip_reason("photos", "a.jpg.imgroll-done", &meta(&[]), &next_to_originals, false),
+        Some(Skip::IsMarker)
+    );
+    let flagged = meta(&[("imgroll-cb", "https://example.com/cb"), ("imgroll-processed", "true")]);
+    assert_eq!(
+        skip_reason("photos", "a.jpg", &flagged, &next_to_originals, false),
+        Some(Skip::Flagged)
+    );
+    let not_flagged = meta(&[("imgroll-processed", "false")]);
+    assert_eq!(
+        skip_reason("photos", "a.jpg", &not_flagged, &next_to_originals, false),
+        None
+    );
+
+    let under_prefix = Location::parse("s3://photos/derived").unwrap();
+    assert_eq!(
+        skip_reason("photos", "derived/a.jpg", &upload, &under_prefix, false),
+        Some(Skip::OutputPrefix)
+    );
+    assert_eq!(
+        skip_reason("photos", "derived-not/a.jpg", &upload, &under_prefix, false),
+        None
+    );
+    // The same prefix in another bucket is fine
+    let other_bucket = Location::parse("s3://derived/derived").unwrap();
+    assert_eq!(
+        skip_reason("photos", "derived/a.jpg", &upload, &other_bucket, false),
+        None
+    );
+}
+
+#[test]
+fn transient_errors() {
+    let dispatch = RusotoError::<PutObjectError>::HttpDispatch(HttpDispatchError::new("connection reset".to_owned()));
+    assert!(is_transient(&dispatch));
+    let validation = RusotoError::<PutObjectError>::Validation("invalid key".to_owned());
+    assert!(!is_transient(&validation));
+}
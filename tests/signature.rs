@@ -0,0 +1,32 @@
+//! Callback signatures, run with the `signature` feature (on by default through `lambda`).
+#![cfg(feature = "signature")]
+
+use imgroll::signature::{sign, verify};
+
+const SECRET: &[u8] = b"It's a secret to everybody";
+const BODY: &[u8] = br#"{"width":320}"#;
+
+#[test]
+fn known_signature() {
+    // From Python: hmac.new(SECRET, b'1700000000.' + BODY, hashlib.sha256).hexdigest()
+    assert_eq!(
+        sign(SECRET, 1_700_000_000, BODY),
+        "sha256=826aa6f4a50a0d02e33da1b42fd03b7ea6f17e359426da0c45eefe2a9c321dff"
+    );
+}
+
+#[test]
+fn verification() {
+    let signature = sign(SECRET, 1_700_000_000, BODY);
+    assert!(verify(SECRET, 1_700_000_000, BODY, &signature));
+    assert!(!verify(SECRET, 1_700_000_001, BODY, &signature));
+    assert!(!verify(SECRET, 1_700_000_000, br#"{"width":321}"#, &signature));
+    assert!(!verify(b"guess", 1_700_000_000, BODY, &signature));
+    assert!(!verify(
+        SECRET,
+        1_700_000_000,
+        BODY,
+        signature.trim_start_matches("sha256=")
+    ));
+    assert!(!verify(SECRET, 1_700_000_000, BODY, "sha256=not hex"));
+}
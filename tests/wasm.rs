@@ -0,0 +1,26 @@
+//! Run with `wasm-pack test --node -- --no-default-features --features wasm`.
+#![cfg(target_arch = "wasm32")]
+
+use imgroll::{Parallelism, ProcessOptions};
+use wasm_bindgen_test::wasm_bindgen_test;
+
+const LANDSCAPE: &[u8] = include_bytes!("fixtures/landscape.jpg");
+
+#[wasm_bindgen_test]
+fn processes_a_jpeg() {
+    // No threads on wasm32-unknown-unknown
+    let opts = ProcessOptions {
+        parallelism: Parallelism::Sequential,
+        ..Default::default()
+    };
+    let (photo, files) = imgroll::process_photo(LANDSCAPE, "landscape.jpg", &opts).unwrap();
+    assert_eq!((photo.width, photo.height), (320, 240));
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].mimetype, "image/jpeg");
+    assert_eq!(
+        image::load_from_memory(&files[0].bytes).unwrap().to_rgb8().dimensions(),
+        (320, 240)
+    );
+    assert!(photo.tiny_preview.is_none());
+    assert!(photo.palette.is_some());
+}